@@ -0,0 +1,429 @@
+// libretro core wrapping the pabnes Emulator. Frontends call into the
+// exported retro_* functions from a single thread, one core instance per
+// process, so all state lives in two globals.
+//
+// The retro_* entry points are dictated by libretro.h; their safety
+// requirements are the ones documented there.
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::c_void;
+use std::os::raw::{c_char, c_uint};
+use std::ptr;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use libretro_sys::*;
+use pabnes::emulator::AUDIO_SAMPLE_RATE;
+use pabnes::joypad::JoypadButton;
+use pabnes::render::frame::Frame;
+use pabnes::{Emulator, Region};
+
+// libretro-sys predates retro_get_region
+const REGION_NTSC: c_uint = 0;
+const REGION_PAL: c_uint = 1;
+
+// NES pixels are 8:7, so a 256x240 picture is wider than it is tall
+const ASPECT_RATIO: f32 = (Frame::WIDTH as f32 * 8.0 / 7.0) / Frame::HEIGHT as f32;
+
+const BUTTON_MAP: [(c_uint, JoypadButton); 8] = [
+    (DEVICE_ID_JOYPAD_A, JoypadButton::BUTTON_A),
+    (DEVICE_ID_JOYPAD_B, JoypadButton::BUTTON_B),
+    (DEVICE_ID_JOYPAD_SELECT, JoypadButton::SELECT),
+    (DEVICE_ID_JOYPAD_START, JoypadButton::START),
+    (DEVICE_ID_JOYPAD_UP, JoypadButton::UP),
+    (DEVICE_ID_JOYPAD_DOWN, JoypadButton::DOWN),
+    (DEVICE_ID_JOYPAD_LEFT, JoypadButton::LEFT),
+    (DEVICE_ID_JOYPAD_RIGHT, JoypadButton::RIGHT),
+];
+
+#[derive(Default)]
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+}
+
+struct Core {
+    // Boxed so the RAM pointers handed to the frontend never move
+    emulator: Box<Emulator>,
+    video: Vec<u32>,
+    audio: Vec<i16>,
+}
+
+// Raw function pointers are fine to share; the frontend owns what they point at
+unsafe impl Send for Callbacks {}
+
+lazy_static! {
+    static ref CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks::default());
+    static ref CORE: Mutex<Option<Core>> = Mutex::new(None);
+}
+
+fn callbacks() -> std::sync::MutexGuard<'static, Callbacks> {
+    CALLBACKS.lock().unwrap()
+}
+
+fn with_core<T>(default: T, f: impl FnOnce(&mut Core) -> T) -> T {
+    match CORE.lock().unwrap().as_mut() {
+        Some(core) => f(core),
+        None => default,
+    }
+}
+
+unsafe fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    match callbacks().environment {
+        Some(cb) => cb(cmd, data),
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(cb: EnvironmentFn) {
+    callbacks().environment = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(cb: VideoRefreshFn) {
+    callbacks().video_refresh = Some(cb);
+}
+
+// Only the batch callback is used
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_cb: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(cb: AudioSampleBatchFn) {
+    callbacks().audio_sample_batch = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(cb: InputPollFn) {
+    callbacks().input_poll = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(cb: InputStateFn) {
+    callbacks().input_state = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    *CORE.lock().unwrap() = None;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    *info = SystemInfo {
+        library_name: b"pabnes\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: b"nes|nsf|nsfe\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    *info = SystemAvInfo {
+        geometry: GameGeometry {
+            base_width: Frame::WIDTH as c_uint,
+            base_height: Frame::HEIGHT as c_uint,
+            max_width: Frame::WIDTH as c_uint,
+            max_height: Frame::HEIGHT as c_uint,
+            aspect_ratio: ASPECT_RATIO,
+        },
+        timing: SystemTiming {
+            fps: with_core(Region::Ntsc, |core| core.emulator.region()).frame_rate(),
+            sample_rate: AUDIO_SAMPLE_RATE as f64,
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    with_core((), |core| core.emulator.reset());
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const GameInfo) -> bool {
+    if game.is_null() || (*game).data.is_null() {
+        return false;
+    }
+    let raw = std::slice::from_raw_parts((*game).data as *const u8, (*game).size);
+
+    let mut emulator = Box::new(Emulator::new());
+    if let Err(err) = emulator.load_rom(raw) {
+        eprintln!("pabnes: {}", err);
+        return false;
+    }
+
+    let mut format = PixelFormat::ARGB8888;
+    if !environment(ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut _ as *mut c_void) {
+        eprintln!("pabnes: frontend does not support XRGB8888");
+        return false;
+    }
+
+    let mut core = Core {
+        emulator,
+        video: vec![0; Frame::WIDTH * Frame::HEIGHT],
+        audio: vec![],
+    };
+    set_memory_maps(&mut core);
+    *CORE.lock().unwrap() = Some(core);
+    true
+}
+
+// Describes the CPU address space so cheats and achievements can find RAM
+unsafe fn set_memory_maps(core: &mut Core) {
+    let mut descriptors = vec![MemoryDescriptor {
+        flags: 0,
+        ptr: core.emulator.work_ram_mut().as_mut_ptr() as *mut c_void,
+        offset: 0,
+        start: 0x0000,
+        // mirrored every 2K up to $1FFF
+        select: 0xE000,
+        disconnect: 0,
+        len: 0x0800,
+        addrspace: ptr::null(),
+    }];
+    if let Some(save_ram) = core.emulator.save_ram_mut() {
+        descriptors.push(MemoryDescriptor {
+            flags: 0,
+            ptr: save_ram.as_mut_ptr() as *mut c_void,
+            offset: 0,
+            start: 0x6000,
+            select: 0xE000,
+            disconnect: 0,
+            len: save_ram.len(),
+            addrspace: ptr::null(),
+        });
+    }
+    let mut map = MemoryMap {
+        descriptors: descriptors.as_ptr(),
+        num_descriptors: descriptors.len() as c_uint,
+    };
+    // the frontend copies the descriptors before returning
+    environment(ENVIRONMENT_SET_MEMORY_MAPS, &mut map as *mut _ as *mut c_void);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_type: c_uint, _info: *const GameInfo, _num: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    *CORE.lock().unwrap() = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    match with_core(Region::Ntsc, |core| core.emulator.region()) {
+        Region::Ntsc => REGION_NTSC,
+        Region::Pal | Region::Dendy => REGION_PAL,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_run() {
+    let cb = callbacks();
+    let mut core_guard = CORE.lock().unwrap();
+    let core = match core_guard.as_mut() {
+        Some(core) => core,
+        None => return,
+    };
+
+    if let Some(poll) = cb.input_poll {
+        poll();
+    }
+    if let Some(state) = cb.input_state {
+        for player in 1..=2 {
+            let mut buttons = JoypadButton::empty();
+            for (id, button) in BUTTON_MAP.iter() {
+                if state(player as c_uint - 1, DEVICE_JOYPAD, 0, *id) != 0 {
+                    buttons.insert(*button);
+                }
+            }
+            core.emulator.set_input(player, buttons);
+        }
+    }
+
+    if let Err(err) = core.emulator.step_frame() {
+        eprintln!("pabnes: {}", err);
+    }
+
+    for (pixel, rgb) in core.video.iter_mut().zip(core.emulator.frame_buffer().chunks_exact(3)) {
+        *pixel = u32::from_be_bytes([0, rgb[0], rgb[1], rgb[2]]);
+    }
+    if let Some(video_refresh) = cb.video_refresh {
+        video_refresh(
+            core.video.as_ptr() as *const c_void,
+            Frame::WIDTH as c_uint,
+            Frame::HEIGHT as c_uint,
+            Frame::WIDTH * 4,
+        );
+    }
+
+    core.audio.clear();
+    for sample in core.emulator.take_audio_samples() {
+        let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        core.audio.extend_from_slice(&[sample, sample]);
+    }
+    if let Some(audio_batch) = cb.audio_sample_batch {
+        // the frontend may take fewer frames than offered, so keep feeding it
+        let mut sent = 0;
+        let frames = core.audio.len() / 2;
+        while sent < frames {
+            let taken = audio_batch(core.audio[sent * 2..].as_ptr(), frames - sent);
+            if taken == 0 {
+                break;
+            }
+            sent += taken;
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    with_core(0, |core| core.emulator.save_state().len())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    with_core(false, |core| {
+        let state = core.emulator.save_state();
+        if state.len() > size {
+            return false;
+        }
+        ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+        true
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let state = std::slice::from_raw_parts(data as *const u8, size);
+    with_core(false, |core| match core.emulator.load_state(state) {
+        Ok(()) => true,
+        Err(err) => {
+            eprintln!("pabnes: {}", err);
+            false
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    with_core(ptr::null_mut(), |core| match id {
+        MEMORY_SYSTEM_RAM => core.emulator.work_ram_mut().as_mut_ptr() as *mut c_void,
+        MEMORY_SAVE_RAM => match core.emulator.save_ram_mut() {
+            Some(ram) => ram.as_mut_ptr() as *mut c_void,
+            None => ptr::null_mut(),
+        },
+        _ => ptr::null_mut(),
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    with_core(0, |core| match id {
+        MEMORY_SYSTEM_RAM => core.emulator.work_ram_mut().len(),
+        MEMORY_SAVE_RAM => core.emulator.save_ram_mut().map_or(0, |ram| ram.len()),
+        _ => 0,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pabnes::cartridge::nrom;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static FRAMES: AtomicUsize = AtomicUsize::new(0);
+    static MEMORY_MAPS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn environment_cb(cmd: c_uint, data: *mut c_void) -> bool {
+        match cmd {
+            ENVIRONMENT_SET_PIXEL_FORMAT => *(data as *const PixelFormat) == PixelFormat::ARGB8888,
+            ENVIRONMENT_SET_MEMORY_MAPS => {
+                MEMORY_MAPS.store((*(data as *const MemoryMap)).num_descriptors as usize, Ordering::SeqCst);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    unsafe extern "C" fn video_cb(_data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+        assert_eq!((width, height, pitch), (256, 240, 256 * 4));
+        FRAMES.fetch_add(1, Ordering::SeqCst);
+    }
+
+    unsafe extern "C" fn input_state_cb(_port: c_uint, _device: c_uint, _index: c_uint, _id: c_uint) -> i16 {
+        0
+    }
+
+    // Battery-backed NROM: INC $6000; JMP $8000
+    fn test_rom() -> Vec<u8> {
+        let mut raw = nrom(&[0xEE, 0x00, 0x60, 0x4C, 0x00, 0x80], &[]);
+        raw[6] = 0x02;
+        raw
+    }
+
+    #[test]
+    fn test_core_lifecycle() {
+        retro_set_environment(environment_cb);
+        retro_set_video_refresh(video_cb);
+        retro_set_input_state(input_state_cb);
+        retro_init();
+
+        let rom = test_rom();
+        let game = GameInfo {
+            path: ptr::null(),
+            data: rom.as_ptr() as *const c_void,
+            size: rom.len(),
+            meta: ptr::null(),
+        };
+        unsafe {
+            assert!(retro_load_game(&game));
+            assert_eq!(MEMORY_MAPS.load(Ordering::SeqCst), 2);
+
+            retro_run();
+            assert_eq!(FRAMES.load(Ordering::SeqCst), 1);
+
+            assert_eq!(retro_get_memory_size(MEMORY_SYSTEM_RAM), 0x0800);
+            assert_eq!(retro_get_memory_size(MEMORY_SAVE_RAM), 0x2000);
+            let save_ram = retro_get_memory_data(MEMORY_SAVE_RAM) as *const u8;
+            let counter = *save_ram;
+            assert_ne!(counter, 0);
+
+            let mut state = vec![0u8; retro_serialize_size()];
+            assert!(retro_serialize(state.as_mut_ptr() as *mut c_void, state.len()));
+            retro_run();
+            assert_ne!(*save_ram, counter);
+            assert!(retro_unserialize(state.as_ptr() as *const c_void, state.len()));
+            assert_eq!(*save_ram, counter);
+        }
+
+        retro_unload_game();
+        assert!(retro_get_memory_data(MEMORY_SYSTEM_RAM).is_null());
+        retro_deinit();
+    }
+}
@@ -0,0 +1,758 @@
+use crate::emulator::AUDIO_SAMPLE_RATE;
+use crate::region::Region;
+use crate::savestate::{StateReader, StateWriter};
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+// The console's output is AC coupled; this is roughly its 90 Hz high-pass
+const HIGH_PASS: f32 = 0.987;
+
+#[derive(Default)]
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    period: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, data: u8) {
+        self.looping = data & 0x20 != 0;
+        self.constant = data & 0x10 != 0;
+        self.period = data & 0x0F;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.period;
+        } else if self.divider == 0 {
+            self.divider = self.period;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant {
+            self.period
+        } else {
+            self.decay
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.start);
+        w.write_u8(self.divider);
+        w.write_u8(self.decay);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.start = r.read_bool()?;
+        self.divider = r.read_u8()?;
+        self.decay = r.read_u8()?;
+        Ok(())
+    }
+}
+
+struct Pulse {
+    // pulse 1 negates its sweep in ones' complement, pulse 2 in two's
+    ones_complement: bool,
+    enabled: bool,
+    duty: u8,
+    step: u8,
+    period: u16,
+    timer: u16,
+    length: u8,
+    envelope: Envelope,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    fn new(ones_complement: bool) -> Pulse {
+        Pulse {
+            ones_complement,
+            enabled: false,
+            duty: 0,
+            step: 0,
+            period: 0,
+            timer: 0,
+            length: 0,
+            envelope: Envelope::default(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = data >> 6;
+                self.envelope.write(data);
+            }
+            1 => {
+                self.sweep_enabled = data & 0x80 != 0;
+                self.sweep_period = (data >> 4) & 0x07;
+                self.sweep_negate = data & 0x08 != 0;
+                self.sweep_shift = data & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => self.period = (self.period & 0x0700) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(data >> 3) as usize];
+                }
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.period >> self.sweep_shift;
+        if !self.sweep_negate {
+            self.period + change
+        } else if self.ones_complement {
+            self.period.saturating_sub(change + 1)
+        } else {
+            self.period.saturating_sub(change)
+        }
+    }
+
+    fn muted(&self) -> bool {
+        self.period < 8 || self.sweep_target() > 0x07FF
+    }
+
+    // every other CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) & 7;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_half_frame(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+        if !self.envelope.looping && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length == 0 || self.muted() || DUTY_TABLE[self.duty as usize][self.step as usize] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_u8(self.step);
+        w.write_u16(self.period);
+        w.write_u16(self.timer);
+        w.write_u8(self.length);
+        w.write_u8(self.sweep_divider);
+        w.write_bool(self.sweep_reload);
+        self.envelope.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.enabled = r.read_bool()?;
+        self.step = r.read_u8()? & 7;
+        self.period = r.read_u16()? & 0x07FF;
+        self.timer = r.read_u16()?;
+        self.length = r.read_u8()?;
+        self.sweep_divider = r.read_u8()?;
+        self.sweep_reload = r.read_bool()?;
+        self.envelope.load_state(r)
+    }
+}
+
+#[derive(Default)]
+struct Triangle {
+    enabled: bool,
+    // also halts the length counter
+    control: bool,
+    linear_period: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    period: u16,
+    timer: u16,
+    length: u8,
+    step: u8,
+}
+
+impl Triangle {
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.control = data & 0x80 != 0;
+                self.linear_period = data & 0x7F;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0x0700) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(data >> 3) as usize];
+                }
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length > 0 && self.linear_counter > 0 {
+                self.step = (self.step + 1) & 31;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_period;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    fn clock_half_frame(&mut self) {
+        if !self.control && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        // periods this short are ultrasonic, real hardware just pops
+        if self.period < 2 {
+            7
+        } else {
+            TRIANGLE_TABLE[self.step as usize]
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_u8(self.linear_counter);
+        w.write_bool(self.linear_reload);
+        w.write_u16(self.period);
+        w.write_u16(self.timer);
+        w.write_u8(self.length);
+        w.write_u8(self.step);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.enabled = r.read_bool()?;
+        self.linear_counter = r.read_u8()?;
+        self.linear_reload = r.read_bool()?;
+        self.period = r.read_u16()? & 0x07FF;
+        self.timer = r.read_u16()?;
+        self.length = r.read_u8()?;
+        self.step = r.read_u8()? & 31;
+        Ok(())
+    }
+}
+
+struct Noise {
+    enabled: bool,
+    short_mode: bool,
+    period: u8,
+    timer: u16,
+    shift: u16,
+    length: u8,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise {
+            enabled: false,
+            short_mode: false,
+            period: 0,
+            timer: 0,
+            shift: 1,
+            length: 0,
+            envelope: Envelope::default(),
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => self.envelope.write(data),
+            1 => {}
+            2 => {
+                self.short_mode = data & 0x80 != 0;
+                self.period = data & 0x0F;
+            }
+            _ => {
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(data >> 3) as usize];
+                }
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self, periods: &[u16; 16]) {
+        if self.timer == 0 {
+            self.timer = periods[self.period as usize] - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_half_frame(&mut self) {
+        if !self.envelope.looping && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length == 0 || self.shift & 1 != 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_u16(self.timer);
+        w.write_u16(self.shift);
+        w.write_u8(self.length);
+        self.envelope.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.enabled = r.read_bool()?;
+        self.timer = r.read_u16()?;
+        self.shift = r.read_u16()?;
+        self.length = r.read_u8()?;
+        self.envelope.load_state(r)
+    }
+}
+
+// Delta modulation channel. It reads its samples from CPU memory, which the
+// bus does on its behalf through `dmc_fetch_address` and `dmc_fill`.
+#[derive(Default)]
+struct Dmc {
+    looping: bool,
+    rate: u8,
+    timer: u16,
+    level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    address: u16,
+    remaining: u16,
+    buffer: Option<u8>,
+    shift: u8,
+    bits: u8,
+    silent: bool,
+}
+
+impl Dmc {
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.looping = data & 0x40 != 0;
+                self.rate = data & 0x0F;
+            }
+            1 => self.level = data & 0x7F,
+            2 => self.sample_address = 0xC000 | ((data as u16) << 6),
+            _ => self.sample_length = ((data as u16) << 4) | 1,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.address = self.sample_address;
+        self.remaining = self.sample_length;
+    }
+
+    fn fill(&mut self, data: u8) {
+        self.buffer = Some(data);
+        self.address = self.address.checked_add(1).unwrap_or(0x8000);
+        self.remaining -= 1;
+        if self.remaining == 0 && self.looping {
+            self.restart();
+        }
+    }
+
+    fn clock_timer(&mut self, periods: &[u16; 16]) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = periods[self.rate as usize] - 1;
+        if !self.silent {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        if self.bits > 0 {
+            self.bits -= 1;
+        }
+        if self.bits == 0 {
+            self.bits = 8;
+            match self.buffer.take() {
+                Some(data) => {
+                    self.silent = false;
+                    self.shift = data;
+                }
+                None => self.silent = true,
+            }
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.timer);
+        w.write_u8(self.level);
+        w.write_u16(self.address);
+        w.write_u16(self.remaining);
+        w.write_bool(self.buffer.is_some());
+        w.write_u8(self.buffer.unwrap_or(0));
+        w.write_u8(self.shift);
+        w.write_u8(self.bits);
+        w.write_bool(self.silent);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.timer = r.read_u16()?;
+        self.level = r.read_u8()? & 0x7F;
+        self.address = r.read_u16()?;
+        self.remaining = r.read_u16()?;
+        let buffered = r.read_bool()?;
+        let data = r.read_u8()?;
+        self.buffer = if buffered { Some(data) } else { None };
+        self.shift = r.read_u8()?;
+        self.bits = r.read_u8()?;
+        self.silent = r.read_bool()?;
+        Ok(())
+    }
+}
+
+// The 2A03's sound: two pulse channels, a triangle, noise and DMC, mixed
+// the way the console's resistor network does and averaged down to
+// AUDIO_SAMPLE_RATE. The frame counter IRQ is not raised, the CPU has no
+// IRQ line yet.
+pub struct Apu {
+    region: Region,
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    registers: [u8; 0x18],
+    five_step: bool,
+    frame_cycle: u32,
+    odd_cycle: bool,
+    // running average of the output since the last sample
+    sum: f32,
+    count: u32,
+    sample_time: f64,
+    filter_in: f32,
+    filter_out: f32,
+    samples: Vec<f32>,
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            region: Region::default(),
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::default(),
+            registers: [0; 0x18],
+            five_step: false,
+            frame_cycle: 0,
+            odd_cycle: false,
+            sum: 0.0,
+            count: 0,
+            sample_time: 0.0,
+            filter_in: 0.0,
+            filter_out: 0.0,
+            samples: vec![],
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    // $4000-$4013, $4015 and $4017
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        let register = addr & 0x03;
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(register, data),
+            0x4004..=0x4007 => self.pulse2.write(register, data),
+            0x4008..=0x400B => self.triangle.write(register, data),
+            0x400C..=0x400F => self.noise.write(register, data),
+            0x4010..=0x4013 => self.dmc.write(register, data),
+            0x4015 => self.write_status(data),
+            0x4017 => {
+                self.five_step = data & 0x80 != 0;
+                self.frame_cycle = 0;
+                if self.five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => return,
+        }
+        self.registers[(addr - 0x4000) as usize] = data;
+    }
+
+    fn write_status(&mut self, data: u8) {
+        self.pulse1.enabled = data & 0x01 != 0;
+        self.pulse2.enabled = data & 0x02 != 0;
+        self.triangle.enabled = data & 0x04 != 0;
+        self.noise.enabled = data & 0x08 != 0;
+        if !self.pulse1.enabled {
+            self.pulse1.length = 0;
+        }
+        if !self.pulse2.enabled {
+            self.pulse2.length = 0;
+        }
+        if !self.triangle.enabled {
+            self.triangle.length = 0;
+        }
+        if !self.noise.enabled {
+            self.noise.length = 0;
+        }
+        if data & 0x10 == 0 {
+            self.dmc.remaining = 0;
+        } else if self.dmc.remaining == 0 {
+            self.dmc.restart();
+        }
+    }
+
+    // $4015: which channels are still playing
+    pub fn read_status(&self) -> u8 {
+        (self.pulse1.length > 0) as u8
+            | ((self.pulse2.length > 0) as u8) << 1
+            | ((self.triangle.length > 0) as u8) << 2
+            | ((self.noise.length > 0) as u8) << 3
+            | ((self.dmc.remaining > 0) as u8) << 4
+    }
+
+    // Where the DMC wants its next sample byte from, if it wants one
+    pub fn dmc_fetch_address(&self) -> Option<u16> {
+        if self.dmc.buffer.is_none() && self.dmc.remaining > 0 {
+            Some(self.dmc.address)
+        } else {
+            None
+        }
+    }
+
+    pub fn dmc_fill(&mut self, data: u8) {
+        self.dmc.fill(data);
+    }
+
+    // One CPU cycle
+    pub fn tick(&mut self) {
+        self.triangle.clock_timer();
+        self.noise.clock_timer(self.region.noise_periods());
+        self.dmc.clock_timer(self.region.dmc_periods());
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+        self.clock_frame_counter();
+
+        self.sum += self.mix();
+        self.count += 1;
+        self.sample_time += AUDIO_SAMPLE_RATE as f64;
+        let clock_rate = self.region.cpu_clock_rate() as f64;
+        if self.sample_time >= clock_rate {
+            self.sample_time -= clock_rate;
+            let sample = self.sum / self.count as f32;
+            self.sum = 0.0;
+            self.count = 0;
+            self.filter_out = HIGH_PASS * (self.filter_out + sample - self.filter_in);
+            self.filter_in = sample;
+            self.samples.push(self.filter_out);
+        }
+    }
+
+    fn clock_frame_counter(&mut self) {
+        let steps = self.region.frame_counter_steps();
+        self.frame_cycle += 1;
+        let cycle = self.frame_cycle;
+        if cycle == steps[0] || cycle == steps[2] {
+            self.clock_quarter_frame();
+        } else if cycle == steps[1] || cycle == steps[if self.five_step { 4 } else { 3 }] {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+        }
+        if cycle > steps[if self.five_step { 4 } else { 3 }] {
+            self.frame_cycle = 0;
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_quarter_frame();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse1.clock_half_frame();
+        self.pulse2.clock_half_frame();
+        self.triangle.clock_half_frame();
+        self.noise.clock_half_frame();
+    }
+
+    // The nonlinear mix from the nesdev wiki, 0.0 to about 1.0
+    fn mix(&self) -> f32 {
+        let pulse = (self.pulse1.output() + self.pulse2.output()) as f32;
+        let pulse_out = if pulse == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulse + 100.0) };
+        let tnd = self.triangle.output() as f32 / 8227.0 + self.noise.output() as f32 / 12241.0 + self.dmc.level as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 { 0.0 } else { 159.79 / (1.0 / tnd + 100.0) };
+        pulse_out + tnd_out
+    }
+
+    // Samples at AUDIO_SAMPLE_RATE made since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.registers);
+        self.pulse1.save_state(w);
+        self.pulse2.save_state(w);
+        self.triangle.save_state(w);
+        self.noise.save_state(w);
+        self.dmc.save_state(w);
+        w.write_u16(self.frame_cycle as u16);
+        w.write_bool(self.odd_cycle);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        // the write-only settings come back by replaying the registers
+        let mut registers = [0; 0x18];
+        r.read_bytes_into(&mut registers)?;
+        for (addr, &data) in (0x4000..).zip(registers.iter()) {
+            match addr {
+                0x4003 | 0x4007 | 0x400B | 0x400F | 0x4015 | 0x4017 => self.registers[(addr - 0x4000) as usize] = data,
+                _ => self.write_register(addr, data),
+            }
+        }
+        self.five_step = registers[0x17] & 0x80 != 0;
+        self.pulse1.load_state(r)?;
+        self.pulse2.load_state(r)?;
+        self.triangle.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.dmc.load_state(r)?;
+        self.frame_cycle = r.read_u16()? as u32;
+        self.odd_cycle = r.read_bool()?;
+        self.samples.clear();
+        Ok(())
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Apu {
+        Apu::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pulse_plays_and_stops() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0x01);
+        // 50% duty, constant volume 15, length index 1 (254 half frames)
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 0xFD);
+        apu.write_register(0x4003, 0b0000_1000);
+        assert_eq!(apu.read_status(), 0x01);
+
+        // a tenth of a second is 4410 samples that swing both ways
+        for _ in 0..178_977 {
+            apu.tick();
+        }
+        let samples = apu.take_samples();
+        assert!((samples.len() as i64 - 4410).abs() <= 1);
+        assert!(samples.iter().any(|&s| s > 0.05) && samples.iter().any(|&s| s < -0.05));
+
+        apu.write_register(0x4015, 0x00);
+        assert_eq!(apu.read_status(), 0x00);
+    }
+
+    #[test]
+    fn test_length_counter_runs_out() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0x08);
+        // length index 3 is 2 half frames, two per 4-step sequence
+        apu.write_register(0x400C, 0x0F);
+        apu.write_register(0x400F, 0b0001_1000);
+        for _ in 0..14_913 {
+            apu.tick();
+        }
+        assert_eq!(apu.read_status(), 0x08);
+        for _ in 0..14_917 {
+            apu.tick();
+        }
+        assert_eq!(apu.read_status(), 0x00);
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample, Stream, StreamConfig};
+
+use super::AudioSink;
+
+// How much the sound card may have queued up, in seconds. Rate control aims
+// for half of it.
+const BUFFER_SECONDS: f64 = 0.1;
+
+// The default output device of the system through cpal. The emulator side
+// pushes into a queue that cpal's audio thread drains; running dry plays
+// silence and overflowing drops the newest samples.
+pub struct DeviceSink {
+    queue: Arc<Mutex<VecDeque<f32>>>,
+    capacity: usize,
+    sample_rate: u32,
+    _stream: Stream,
+}
+
+impl DeviceSink {
+    pub fn new() -> Result<DeviceSink, String> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or_else(|| "No audio output device".to_string())?;
+        let supported = device.default_output_config().map_err(|err| err.to_string())?;
+        let config: StreamConfig = supported.config();
+        let sample_rate = config.sample_rate.0;
+        let capacity = (sample_rate as f64 * BUFFER_SECONDS) as usize;
+        let queue = Arc::new(Mutex::new(VecDeque::with_capacity(capacity)));
+
+        let stream = match supported.sample_format() {
+            SampleFormat::F32 => build_stream::<f32>(&device, &config, queue.clone()),
+            SampleFormat::I16 => build_stream::<i16>(&device, &config, queue.clone()),
+            SampleFormat::U16 => build_stream::<u16>(&device, &config, queue.clone()),
+            format => Err(format!("Unsupported sample format {}", format)),
+        }?;
+        stream.play().map_err(|err| err.to_string())?;
+
+        Ok(DeviceSink {
+            queue,
+            capacity,
+            sample_rate,
+            _stream: stream,
+        })
+    }
+}
+
+fn build_stream<T>(device: &cpal::Device, config: &StreamConfig, queue: Arc<Mutex<VecDeque<f32>>>) -> Result<Stream, String>
+where T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    device
+        .build_output_stream(
+            config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                let mut queue = queue.lock().unwrap();
+                // mono, so every channel of a frame gets the same sample
+                for frame in data.chunks_mut(channels) {
+                    let sample = T::from_sample(queue.pop_front().unwrap_or(0.0));
+                    frame.fill(sample);
+                }
+            },
+            |err| eprintln!("Audio stream error: {}", err),
+            None,
+        )
+        .map_err(|err| err.to_string())
+}
+
+impl AudioSink for DeviceSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        let mut queue = self.queue.lock().unwrap();
+        let room = self.capacity.saturating_sub(queue.len());
+        queue.extend(samples.iter().take(room));
+        Ok(())
+    }
+
+    fn fill(&self) -> Option<(usize, usize)> {
+        Some((self.queue.lock().unwrap().len(), self.capacity))
+    }
+}
@@ -0,0 +1,182 @@
+#[cfg(feature = "cpal")]
+pub mod device;
+pub mod wav;
+
+// How far dynamic rate control may bend the resampling ratio. Half a percent
+// is well below what anyone hears as a pitch change.
+const MAX_RATE_DELTA: f64 = 0.005;
+
+// Somewhere mono samples go to be heard or kept
+pub trait AudioSink {
+    fn sample_rate(&self) -> u32;
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), String>;
+
+    // Samples queued but not played yet, and how many fit. Only sinks that
+    // play in real time have one; rate control is off for the rest.
+    fn fill(&self) -> Option<(usize, usize)> {
+        None
+    }
+}
+
+// Throws everything away
+pub struct NullSink {
+    sample_rate: u32,
+}
+
+impl NullSink {
+    pub fn new(sample_rate: u32) -> NullSink {
+        NullSink { sample_rate }
+    }
+}
+
+impl AudioSink for NullSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn write(&mut self, _samples: &[f32]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// Linear interpolation, keeping its position between calls so blocks join
+// up without clicks
+struct Resampler {
+    position: f64,
+    last: f32,
+}
+
+impl Resampler {
+    fn new() -> Resampler {
+        Resampler { position: 0.0, last: 0.0 }
+    }
+
+    // `step` is input samples per output sample
+    fn process(&mut self, input: &[f32], step: f64, out: &mut Vec<f32>) {
+        // position is relative to `last`, which sits just before input[0]
+        let last = self.last;
+        let sample = |index: usize| if index == 0 { last } else { input[index - 1] };
+        while self.position < input.len() as f64 {
+            let index = self.position as usize;
+            let fraction = (self.position - index as f64) as f32;
+            out.push(sample(index) * (1.0 - fraction) + sample(index + 1) * fraction);
+            self.position += step;
+        }
+        self.position -= input.len() as f64;
+        if let Some(&last) = input.last() {
+            self.last = last;
+        }
+    }
+}
+
+// Resamples the emulator's output to the sink's rate. For sinks that play in
+// real time the ratio is nudged to keep their buffer half full: video is
+// paced by vsync or the frame clock, which never quite matches the sound
+// card, and without this the buffer slowly runs dry (crackles) or fills up
+// (lag).
+pub struct AudioOutput {
+    sink: Box<dyn AudioSink>,
+    input_rate: u32,
+    resampler: Resampler,
+    rate_adjustment: f64,
+    buffer: Vec<f32>,
+}
+
+impl AudioOutput {
+    pub fn new(sink: Box<dyn AudioSink>, input_rate: u32) -> AudioOutput {
+        AudioOutput {
+            sink,
+            input_rate,
+            resampler: Resampler::new(),
+            rate_adjustment: 1.0,
+            buffer: vec![],
+        }
+    }
+
+    pub fn sink(&self) -> &dyn AudioSink {
+        self.sink.as_ref()
+    }
+
+    // The factor the output rate was last bent by, 1.0 without rate control
+    pub fn rate_adjustment(&self) -> f64 {
+        self.rate_adjustment
+    }
+
+    pub fn push(&mut self, samples: &[f32]) -> Result<(), String> {
+        self.rate_adjustment = match self.sink.fill() {
+            Some((queued, capacity)) if capacity > 0 => {
+                // +1 when empty, 0 at half full, -1 when full
+                let error = 1.0 - 2.0 * (queued.min(capacity) as f64 / capacity as f64);
+                1.0 + MAX_RATE_DELTA * error
+            }
+            _ => 1.0,
+        };
+        let output_rate = self.sink.sample_rate() as f64 * self.rate_adjustment;
+        let step = self.input_rate as f64 / output_rate;
+
+        self.buffer.clear();
+        self.resampler.process(samples, step, &mut self.buffer);
+        self.sink.write(&self.buffer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct TestSink {
+        written: Rc<RefCell<usize>>,
+        queued: usize,
+    }
+
+    impl AudioSink for TestSink {
+        fn sample_rate(&self) -> u32 {
+            48_000
+        }
+
+        fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+            *self.written.borrow_mut() += samples.len();
+            Ok(())
+        }
+
+        fn fill(&self) -> Option<(usize, usize)> {
+            Some((self.queued, 4800))
+        }
+    }
+
+    fn samples_out(queued: usize) -> usize {
+        let written = Rc::new(RefCell::new(0));
+        let sink = TestSink { written: written.clone(), queued };
+        let mut output = AudioOutput::new(Box::new(sink), 44_100);
+        for _ in 0..100 {
+            output.push(&[0.5; 441]).unwrap();
+        }
+        let count = *written.borrow();
+        count
+    }
+
+    #[test]
+    fn test_resampler_keeps_the_signal() {
+        let mut resampler = Resampler::new();
+        let mut out = vec![];
+        let input: Vec<f32> = (0..100).map(|i| i as f32).collect();
+        resampler.process(&input[..50], 0.5, &mut out);
+        resampler.process(&input[50..], 0.5, &mut out);
+        assert_eq!(out.len(), 200);
+        // a ramp stays a ramp across the block boundary, one sample late
+        assert!(out.windows(2).skip(2).all(|pair| (pair[1] - pair[0] - 0.5).abs() < 1e-4));
+    }
+
+    #[test]
+    fn test_rate_control_follows_fill() {
+        // one second of input is 48000 samples out at half fill, give or
+        // take the rounding of the step
+        let close = |count: usize, expected: usize| (count as i64 - expected as i64).abs() <= 2;
+        assert!(close(samples_out(2400), 48_000));
+        assert!(close(samples_out(0), 48_240));
+        assert!(close(samples_out(4800), 47_760));
+    }
+}
@@ -0,0 +1,157 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::AudioSink;
+
+const HEADER_SIZE: u32 = 44;
+// The RIFF size field counts everything after it and is 32 bits
+const MAX_DATA_SIZE: u32 = u32::MAX - (HEADER_SIZE - 8);
+
+// 16-bit PCM WAV. The sizes in the header are filled in by `finish`, or
+// when the writer is dropped.
+pub struct WavWriter<W: Write + Seek> {
+    out: Option<W>,
+    sample_rate: u32,
+    channels: u16,
+    data_size: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32, channels: u16) -> io::Result<WavWriter<W>> {
+        write_header(&mut out, sample_rate, channels, 0)?;
+        Ok(WavWriter {
+            out: Some(out),
+            sample_rate,
+            channels,
+            data_size: 0,
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Interleaved when there is more than one channel. Fails without
+    // writing anything once the file would outgrow what the header can
+    // describe, about 4 GiB.
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let data_size = u32::try_from(samples.len() * 2)
+            .ok()
+            .and_then(|size| self.data_size.checked_add(size))
+            .filter(|size| *size <= MAX_DATA_SIZE)
+            .ok_or_else(|| io::Error::other("WAV file is full, it cannot grow past 4 GiB"))?;
+        let out = self.out.as_mut().expect("writer is only taken by finish");
+        let mut bytes = Vec::with_capacity(samples.len() * 2);
+        for sample in samples {
+            bytes.extend_from_slice(&((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes());
+        }
+        out.write_all(&bytes)?;
+        self.data_size = data_size;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.patch_header()?;
+        Ok(self.out.take().expect("writer is only taken by finish"))
+    }
+
+    fn patch_header(&mut self) -> io::Result<()> {
+        if let Some(out) = &mut self.out {
+            let end = out.stream_position()?;
+            out.seek(SeekFrom::Start(0))?;
+            write_header(out, self.sample_rate, self.channels, self.data_size)?;
+            out.seek(SeekFrom::Start(end))?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32, channels: u16) -> io::Result<WavWriter<BufWriter<File>>> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate, channels)
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        if let Err(err) = self.patch_header() {
+            eprintln!("Failed to finish WAV file: {}", err);
+        }
+    }
+}
+
+fn write_header<W: Write>(out: &mut W, sample_rate: u32, channels: u16, data_size: u32) -> io::Result<()> {
+    let block_align = channels * 2;
+    let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(HEADER_SIZE - 8 + data_size).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    header.extend_from_slice(&channels.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_size.to_le_bytes());
+    out.write_all(&header)
+}
+
+// Plays into a mono WAV file instead of a sound card
+pub struct WavSink {
+    writer: WavWriter<BufWriter<File>>,
+}
+
+impl WavSink {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> Result<WavSink, String> {
+        let path = path.as_ref();
+        let writer = WavWriter::create(path, sample_rate, 1).map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(WavSink { writer })
+    }
+}
+
+impl AudioSink for WavSink {
+    fn sample_rate(&self) -> u32 {
+        self.writer.sample_rate()
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        self.writer.write_samples(samples).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_wav_header() {
+        let mut writer = WavWriter::new(Cursor::new(vec![]), 44_100, 1).unwrap();
+        writer.write_samples(&[0.0, 1.0, -1.0]).unwrap();
+        let data = writer.finish().unwrap().into_inner();
+
+        assert_eq!(data.len(), 44 + 6);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes([data[4], data[5], data[6], data[7]]), 36 + 6);
+        assert_eq!(u32::from_le_bytes([data[24], data[25], data[26], data[27]]), 44_100);
+        assert_eq!(u32::from_le_bytes([data[40], data[41], data[42], data[43]]), 6);
+        assert_eq!(&data[44..], &[0, 0, 0xFF, 0x7F, 0x01, 0x80]);
+    }
+
+    #[test]
+    fn test_stops_at_riff_size_limit() {
+        let mut writer = WavWriter::new(Cursor::new(vec![]), 44_100, 1).unwrap();
+        writer.data_size = MAX_DATA_SIZE - 4;
+        writer.write_samples(&[0.5, 0.5]).unwrap();
+        assert!(writer.write_samples(&[0.5]).is_err());
+        let data = writer.finish().unwrap().into_inner();
+
+        assert_eq!(data.len(), 44 + 4);
+        assert_eq!(u32::from_le_bytes([data[4], data[5], data[6], data[7]]), u32::MAX);
+    }
+}
@@ -0,0 +1,298 @@
+use std::io;
+
+use crate::apu::Apu;
+use crate::cartridge::Cartridge;
+use crate::cdl::{self, CodeDataLog};
+use crate::cpu::Mem;
+use crate::joypad::{Joypad, JoypadButton};
+use crate::ppu::NesPPU;
+use crate::region::Region;
+use crate::savestate::{StateReader, StateWriter};
+
+// The CPU is halted while OAM DMA copies a page into the PPU
+const OAM_DMA_CYCLES: usize = 513;
+
+pub struct Bus {
+    memory: [u8; 0x10000],
+    pub cartridge: Option<Cartridge>,
+    pub ppu: NesPPU,
+    pub apu: Apu,
+    pub joypad1: Joypad,
+    pub joypad2: Joypad,
+    pub cycles: usize,
+    // PPU dots owed for partial CPU cycles, in units of 1/denominator of a dot
+    dot_fraction: usize,
+    // the PRG-ROM half of the code/data log, the PPU keeps the CHR half
+    prg_log: Option<Vec<u8>>,
+}
+
+impl Bus {
+    pub fn new() -> Bus {
+        Bus {
+            memory: [0; 0x10000],
+            cartridge: None,
+            ppu: NesPPU::new_empty_rom(),
+            apu: Apu::new(),
+            joypad1: Joypad::new(),
+            joypad2: Joypad::new(),
+            cycles: 0,
+            dot_fraction: 0,
+            prg_log: None,
+        }
+    }
+
+    pub fn insert_cartridge(&mut self, cartridge: Cartridge) {
+        let region = self.region();
+        self.ppu = NesPPU::new(cartridge.rom.chr_rom.clone(), cartridge.rom.screen_mirroring);
+        self.ppu.set_region(region);
+        self.cartridge = Some(cartridge);
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.ppu.frame_count()
+    }
+
+    pub fn region(&self) -> Region {
+        self.ppu.region()
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
+        self.dot_fraction = 0;
+    }
+
+    // 3 PPU dots per CPU cycle, or 3.2 on PAL
+    pub fn tick(&mut self, cycles: usize) {
+        self.cycles += cycles;
+        let (dots, per_cycles) = self.region().ppu_dots_per_cpu_cycle();
+        self.dot_fraction += cycles * dots;
+        self.ppu.tick(self.dot_fraction / per_cycles);
+        self.dot_fraction %= per_cycles;
+        if self.cartridge.is_some() {
+            for _ in 0..cycles {
+                self.apu.tick();
+                if let Some(addr) = self.apu.dmc_fetch_address() {
+                    let data = self.mem_read(addr);
+                    self.apu.dmc_fill(data);
+                    self.log_prg(addr, cdl::PRG_PCM);
+                }
+            }
+        }
+    }
+
+    // Starts or stops code/data logging
+    pub fn set_code_data_log(&mut self, log: Option<CodeDataLog>) {
+        match log {
+            Some(log) => {
+                self.prg_log = Some(log.prg);
+                self.ppu.chr_log = Some(log.chr);
+            }
+            None => {
+                self.prg_log = None;
+                self.ppu.chr_log = None;
+            }
+        }
+    }
+
+    pub fn code_data_log(&self) -> Option<CodeDataLog> {
+        Some(CodeDataLog {
+            prg: self.prg_log.clone()?,
+            chr: self.ppu.chr_log.clone().unwrap_or_default(),
+        })
+    }
+
+    pub fn is_logging_code_data(&self) -> bool {
+        self.prg_log.is_some()
+    }
+
+    // Marks the PRG-ROM byte behind `addr`, if it is one and logging is on
+    pub fn log_prg(&mut self, addr: u16, flags: u8) {
+        if let (Some(log), Some(cartridge), 0x8000..=0xFFFF) = (&mut self.prg_log, &self.cartridge, addr) {
+            if let Some(offset) = cartridge.prg_offset(addr) {
+                cdl::log_prg(log, offset, addr, flags);
+            }
+        }
+    }
+
+    pub fn poll_nmi_status(&mut self) -> bool {
+        self.ppu.poll_nmi_interrupt()
+    }
+
+    fn oam_dma(&mut self, page: u8) {
+        let mut buffer = [0u8; 256];
+        let start = (page as u16) << 8;
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.mem_read(start + i as u16);
+        }
+        self.ppu.write_oam_dma(&buffer);
+        self.tick(OAM_DMA_CYCLES);
+    }
+
+    pub fn update_save(&mut self) -> io::Result<()> {
+        match &mut self.cartridge {
+            Some(cartridge) => cartridge.update_save(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        match &mut self.cartridge {
+            Some(cartridge) => cartridge.flush_save(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn load(&mut self, addr: u16, program: &[u8]) {
+        let start = addr as usize;
+        self.memory[start..(start + program.len())].copy_from_slice(program);
+    }
+
+    // Reads like the CPU would, minus the side effects of reading registers,
+    // for debuggers and scripts looking at memory
+    pub fn peek(&self, addr: u16) -> u8 {
+        match (addr, &self.cartridge) {
+            (0x0000..=0x1FFF, Some(_)) => self.memory[(addr & 0x07FF) as usize],
+            (0x2000..=0x3FFF, Some(_)) => match addr & 0x2007 {
+                0x2002 => self.ppu.peek_status(),
+                0x2004 => self.ppu.read_oam_data(),
+                0x2007 => self.ppu.peek_data(),
+                _ => 0,
+            },
+            (0x4015, Some(_)) => self.apu.read_status(),
+            (0x4016, Some(_)) => self.joypad1.peek(),
+            (0x4017, Some(_)) => self.joypad2.peek(),
+            (0x6000..=0x7FFF, Some(cartridge)) => cartridge.read_prg_ram(addr),
+            (0x8000..=0xFFFF, Some(cartridge)) => cartridge.read_prg_rom(addr),
+            _ => self.memory[addr as usize],
+        }
+    }
+
+    // The 2K of console RAM at $0000-$07FF
+    pub fn work_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.memory[..0x0800]
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.memory);
+        w.write_u64(self.cycles as u64);
+        w.write_u8(self.region().to_u8());
+        w.write_u8(self.dot_fraction as u8);
+        self.ppu.save_state(w);
+        self.apu.save_state(w);
+        for joypad in [&self.joypad1, &self.joypad2] {
+            w.write_bool(joypad.strobe);
+            w.write_u8(joypad.button_index);
+            w.write_u8(joypad.button_status.bits());
+        }
+        w.write_bool(self.cartridge.is_some());
+        if let Some(cartridge) = &self.cartridge {
+            cartridge.save_state(w);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.read_bytes_into(&mut self.memory)?;
+        self.cycles = r.read_u64()? as usize;
+        self.set_region(Region::from_u8(r.read_u8()?)?);
+        self.dot_fraction = r.read_u8()? as usize;
+        self.ppu.load_state(r)?;
+        self.apu.load_state(r)?;
+        for joypad in [&mut self.joypad1, &mut self.joypad2] {
+            joypad.strobe = r.read_bool()?;
+            joypad.button_index = r.read_u8()?;
+            joypad.button_status = JoypadButton::from_bits_truncate(r.read_u8()?);
+        }
+        match (r.read_bool()?, &mut self.cartridge) {
+            (true, Some(cartridge)) => cartridge.load_state(r),
+            (false, None) => Ok(()),
+            _ => Err("Save state does not match the inserted cartridge".to_string()),
+        }
+    }
+}
+
+impl Default for Bus {
+    fn default() -> Bus {
+        Bus::new()
+    }
+}
+
+impl Mem for Bus {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        match (addr, &self.cartridge) {
+            (0x0000..=0x1FFF, Some(_)) => self.memory[(addr & 0x07FF) as usize],
+            (0x2000..=0x3FFF, Some(_)) => match addr & 0x2007 {
+                0x2002 => self.ppu.read_status(),
+                0x2004 => self.ppu.read_oam_data(),
+                0x2007 => self.ppu.read_data(),
+                // write-only registers
+                _ => 0,
+            },
+            (0x4015, Some(_)) => self.apu.read_status(),
+            (0x4016, Some(_)) => self.joypad1.read(),
+            (0x4017, Some(_)) => self.joypad2.read(),
+            (0x6000..=0x7FFF, Some(cartridge)) => cartridge.read_prg_ram(addr),
+            (0x8000..=0xFFFF, Some(cartridge)) => cartridge.read_prg_rom(addr),
+            _ => self.memory[addr as usize],
+        }
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        match (addr, &mut self.cartridge) {
+            (0x0000..=0x1FFF, Some(_)) => self.memory[(addr & 0x07FF) as usize] = data,
+            (0x2000..=0x3FFF, Some(_)) => match addr & 0x2007 {
+                0x2000 => self.ppu.write_to_ctrl(data),
+                0x2001 => self.ppu.write_to_mask(data),
+                0x2002 => {}
+                0x2003 => self.ppu.write_to_oam_addr(data),
+                0x2004 => self.ppu.write_to_oam_data(data),
+                0x2005 => self.ppu.write_to_scroll(data),
+                0x2006 => self.ppu.write_to_ppu_addr(data),
+                _ => self.ppu.write_to_data(data),
+            },
+            (0x4000..=0x4013 | 0x4015 | 0x4017, Some(_)) => self.apu.write_register(addr, data),
+            (0x4014, Some(_)) => self.oam_dma(data),
+            (0x4016, Some(_)) => {
+                self.joypad1.write(data);
+                self.joypad2.write(data);
+            }
+            (0x5FF8..=0x5FFF, Some(cartridge)) => cartridge.write_bank(addr, data),
+            (0x6000..=0x7FFF, Some(cartridge)) => cartridge.write_prg_ram(addr, data),
+            (0x8000..=0xFFFF, Some(_)) => {
+                //writes to ROM are ignored until mappers with registers are supported
+            }
+            _ => self.memory[addr as usize] = data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::{nrom, Rom};
+    use crate::ppu::registers::StatusRegister;
+
+    #[test]
+    fn test_peek_leaves_registers_alone() {
+        let mut bus = Bus::new();
+        let rom = Rom::new(&nrom(&[], &[0x12, 0x34])).unwrap();
+        bus.insert_cartridge(Cartridge::new(rom).unwrap());
+        bus.ppu.status.insert(StatusRegister::VBLANK_STARTED);
+        bus.mem_write(0x2006, 0x00);
+        bus.mem_write(0x2006, 0x00);
+        bus.mem_read(0x2007);
+        bus.joypad1.button_status = JoypadButton::BUTTON_B;
+
+        for _ in 0..2 {
+            assert_eq!(bus.peek(0x2002), 0x80);
+            assert_eq!(bus.peek(0x2007), 0x12);
+            assert_eq!(bus.peek(0x4016), 0);
+        }
+        assert_eq!(bus.mem_read(0x2002), 0x80);
+        assert_eq!(bus.peek(0x2002), 0);
+        assert_eq!(bus.mem_read(0x2007), 0x12);
+        assert_eq!(bus.peek(0x2007), 0x34);
+        bus.mem_read(0x4016);
+        assert_eq!(bus.peek(0x4016), 1);
+    }
+}
@@ -0,0 +1,401 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::region::Region;
+use crate::savestate::{StateReader, StateWriter};
+
+const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const PRG_ROM_PAGE_SIZE: usize = 16384;
+const CHR_ROM_PAGE_SIZE: usize = 8192;
+pub const PRG_RAM_SIZE: usize = 0x2000;
+const NSF_BANK_SIZE: usize = 0x1000;
+
+// Flush once the game has stopped writing for a while...
+const SAVE_IDLE: Duration = Duration::from_secs(1);
+// ...but never keep unsaved data around longer than this
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Mirroring {
+    Vertical,
+    Horizontal,
+    FourScreen,
+}
+
+pub struct Rom {
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub mapper: u8,
+    pub screen_mirroring: Mirroring,
+    pub battery: bool,
+    // None when the header does not say
+    pub region: Option<Region>,
+}
+
+impl Rom {
+    pub fn new(raw: &[u8]) -> Result<Rom, String> {
+        if raw.len() < 16 || raw[0..4] != NES_TAG {
+            return Err("File is not in iNES file format".to_string());
+        }
+
+        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
+
+        let ines_ver = (raw[7] >> 2) & 0b11;
+        if ines_ver != 0 && ines_ver != 2 {
+            return Err("Unknown iNES version".to_string());
+        }
+
+        let four_screen = raw[6] & 0b1000 != 0;
+        let vertical_mirroring = raw[6] & 0b1 != 0;
+        let screen_mirroring = match (four_screen, vertical_mirroring) {
+            (true, _) => Mirroring::FourScreen,
+            (false, true) => Mirroring::Vertical,
+            (false, false) => Mirroring::Horizontal,
+        };
+
+        let battery = raw[6] & 0b10 != 0;
+
+        let region = if ines_ver == 2 {
+            match raw[12] & 0b11 {
+                // multi-region carts run fine on NTSC
+                0 | 2 => Some(Region::Ntsc),
+                1 => Some(Region::Pal),
+                _ => Some(Region::Dendy),
+            }
+        } else if raw[9] & 1 != 0 && raw[12..16].iter().all(|&byte| byte == 0) {
+            // old dumps often have junk in bytes 7-15, only trust a clean header
+            Some(Region::Pal)
+        } else {
+            None
+        };
+
+        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
+        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+
+        let skip_trainer = raw[6] & 0b100 != 0;
+
+        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
+        let chr_rom_start = prg_rom_start + prg_rom_size;
+
+        if raw.len() < chr_rom_start + chr_rom_size {
+            return Err("ROM file is truncated".to_string());
+        }
+
+        Ok(Rom {
+            prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
+            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
+            mapper,
+            screen_mirroring,
+            battery,
+            region,
+        })
+    }
+}
+
+pub struct Cartridge {
+    pub rom: Rom,
+    prg_ram: Vec<u8>,
+    // 4K banks mapped at $8000-$FFFF, only for NSF rips
+    prg_banks: Option<[u8; 8]>,
+    save_path: Option<PathBuf>,
+    dirty_since: Option<Instant>,
+    last_write: Option<Instant>,
+    pub save_idle: Duration,
+    pub save_interval: Duration,
+}
+
+impl Cartridge {
+    pub fn supports_mapper(mapper: u8) -> bool {
+        mapper == 0
+    }
+
+    pub fn new(rom: Rom) -> Result<Cartridge, String> {
+        if !Cartridge::supports_mapper(rom.mapper) {
+            return Err(format!("Mapper {} is not supported", rom.mapper));
+        }
+        if rom.prg_rom.is_empty() {
+            return Err("ROM has no PRG-ROM".to_string());
+        }
+
+        Ok(Cartridge {
+            rom,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            prg_banks: None,
+            save_path: None,
+            dirty_since: None,
+            last_write: None,
+            save_idle: SAVE_IDLE,
+            save_interval: SAVE_INTERVAL,
+        })
+    }
+
+    // The board an NSF player uses: `image` is cut into 4K banks that are
+    // switched in by writes to $5FF8-$5FFF, with 8K of RAM at $6000
+    pub fn nsf(image: Vec<u8>, banks: [u8; 8], region: Region) -> Cartridge {
+        let rom = Rom {
+            prg_rom: image,
+            chr_rom: vec![0; CHR_ROM_PAGE_SIZE],
+            mapper: 0,
+            screen_mirroring: Mirroring::Horizontal,
+            battery: false,
+            region: Some(region),
+        };
+        let mut cartridge = Cartridge::new(rom).expect("NSF images are never empty");
+        cartridge.prg_banks = Some(banks);
+        cartridge
+    }
+
+    // Only battery-backed cartridges get a .sav file, next to the ROM
+    pub fn with_save_file(mut self, rom_path: &str) -> io::Result<Cartridge> {
+        if !self.rom.battery {
+            return Ok(self);
+        }
+
+        let path = PathBuf::from(rom_path).with_extension("sav");
+        match fs::read(&path) {
+            Ok(data) => {
+                let len = data.len().min(PRG_RAM_SIZE);
+                self.prg_ram[..len].copy_from_slice(&data[..len]);
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        self.save_path = Some(path);
+        Ok(self)
+    }
+
+    pub fn read_prg_ram(&self, addr: u16) -> u8 {
+        self.prg_ram[(addr - 0x6000) as usize]
+    }
+
+    pub fn write_prg_ram(&mut self, addr: u16, data: u8) {
+        let index = (addr - 0x6000) as usize;
+        if self.prg_ram[index] == data {
+            return;
+        }
+        self.prg_ram[index] = data;
+        self.mark_dirty();
+    }
+
+    // Direct access for frontends that manage save RAM themselves; writes
+    // through this slice are not tracked for the .sav file
+    pub fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn mark_dirty(&mut self) {
+        if self.save_path.is_some() {
+            let now = Instant::now();
+            self.last_write = Some(now);
+            self.dirty_since.get_or_insert(now);
+        }
+    }
+
+    pub fn write_bank(&mut self, addr: u16, data: u8) {
+        if let Some(banks) = &mut self.prg_banks {
+            banks[(addr - 0x5FF8) as usize] = data;
+        }
+    }
+
+    pub fn read_prg_rom(&self, addr: u16) -> u8 {
+        // banks past the end of the image read as zeros
+        self.prg_offset(addr).map_or(0, |offset| self.rom.prg_rom[offset])
+    }
+
+    // Where $8000-$FFFF address `addr` is in PRG-ROM
+    pub fn prg_offset(&self, addr: u16) -> Option<usize> {
+        if let Some(banks) = &self.prg_banks {
+            let bank = banks[((addr - 0x8000) as usize) / NSF_BANK_SIZE] as usize;
+            let index = bank * NSF_BANK_SIZE + (addr as usize % NSF_BANK_SIZE);
+            return Some(index).filter(|&index| index < self.rom.prg_rom.len());
+        }
+        let mut addr = (addr - 0x8000) as usize;
+        if self.rom.prg_rom.len() == 0x4000 {
+            //mirror if needed
+            addr %= 0x4000;
+        }
+        Some(addr)
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_ram);
+        if let Some(banks) = &self.prg_banks {
+            w.write_bytes(banks);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.read_bytes_into(&mut self.prg_ram)?;
+        if let Some(banks) = &mut self.prg_banks {
+            r.read_bytes_into(banks)?;
+        }
+        self.mark_dirty();
+        Ok(())
+    }
+
+    // Meant to be polled regularly from the emulation loop
+    pub fn update_save(&mut self) -> io::Result<()> {
+        if let (Some(dirty_since), Some(last_write)) = (self.dirty_since, self.last_write) {
+            if last_write.elapsed() >= self.save_idle || dirty_since.elapsed() >= self.save_interval {
+                self.flush_save()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        let path = match (&self.save_path, self.dirty_since) {
+            (Some(path), Some(_)) => path,
+            _ => return Ok(()),
+        };
+
+        // write to a temporary file first so a crash never leaves a torn save behind
+        let tmp_path = path.with_extension("sav.tmp");
+        fs::write(&tmp_path, &self.prg_ram)?;
+        fs::rename(&tmp_path, path)?;
+        self.dirty_since = None;
+        Ok(())
+    }
+}
+
+impl Drop for Cartridge {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush_save() {
+            eprintln!("Failed to write save file: {}", err);
+        }
+    }
+}
+
+// A mapper 0 iNES image for tests that do not want ROM files: `prg` starts
+// at $8000 and `chr` at $0000 of the PPU, each padded to whole pages. The
+// reset vector points at $8000 unless `prg` is long enough to set its own.
+// Mirroring and battery flags are left for the caller to set in byte 6.
+#[cfg(any(test, feature = "test-util"))]
+#[doc(hidden)]
+pub fn nrom(prg: &[u8], chr: &[u8]) -> Vec<u8> {
+    let prg_pages = prg.len().div_ceil(PRG_ROM_PAGE_SIZE).max(1);
+    let chr_pages = chr.len().div_ceil(CHR_ROM_PAGE_SIZE).max(1);
+    let mut raw = NES_TAG.to_vec();
+    raw.extend([prg_pages as u8, chr_pages as u8]);
+    raw.resize(16, 0);
+
+    let mut prg_rom = prg.to_vec();
+    prg_rom.resize(prg_pages * PRG_ROM_PAGE_SIZE, 0);
+    let reset_vector = prg_rom.len() - 4;
+    if prg.len() <= reset_vector {
+        prg_rom[reset_vector..reset_vector + 2].copy_from_slice(&[0x00, 0x80]);
+    }
+    raw.extend(prg_rom);
+
+    let mut chr_rom = chr.to_vec();
+    chr_rom.resize(chr_pages * CHR_ROM_PAGE_SIZE, 0);
+    raw.extend(chr_rom);
+    raw
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_rom_raw(flags6: u8) -> Vec<u8> {
+        let mut raw = nrom(&[], &[]);
+        raw[6] = flags6;
+        raw
+    }
+
+    fn temp_rom_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("pabnes_{}_{}.nes", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let rom = Rom::new(&test_rom_raw(0b0000_0011)).unwrap();
+
+        assert_eq!(rom.prg_rom.len(), PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.chr_rom.len(), CHR_ROM_PAGE_SIZE);
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert!(rom.battery);
+        assert_eq!(rom.region, None);
+    }
+
+    #[test]
+    fn test_header_region() {
+        let mut raw = test_rom_raw(0);
+        raw[9] = 1;
+        assert_eq!(Rom::new(&raw).unwrap().region, Some(Region::Pal));
+        raw[13] = b'D'; // junk from an old dumper
+        assert_eq!(Rom::new(&raw).unwrap().region, None);
+
+        // NES 2.0
+        raw[7] = 0b1000;
+        raw[12] = 3;
+        assert_eq!(Rom::new(&raw).unwrap().region, Some(Region::Dendy));
+        raw[12] = 2;
+        assert_eq!(Rom::new(&raw).unwrap().region, Some(Region::Ntsc));
+    }
+
+    #[test]
+    fn test_reject_bad_files() {
+        assert!(Rom::new(&[0; 32]).is_err());
+        assert!(Rom::new(&test_rom_raw(0)[..100]).is_err());
+    }
+
+    #[test]
+    fn test_save_file_round_trip() {
+        let rom_path = temp_rom_path("round_trip");
+        let save_path = PathBuf::from(&rom_path).with_extension("sav");
+        let _ = fs::remove_file(&save_path);
+
+        {
+            let rom = Rom::new(&test_rom_raw(0b10)).unwrap();
+            let mut cart = Cartridge::new(rom).unwrap().with_save_file(&rom_path).unwrap();
+            cart.write_prg_ram(0x6000, 0x42);
+            cart.write_prg_ram(0x7FFF, 0x24);
+        }
+
+        let rom = Rom::new(&test_rom_raw(0b10)).unwrap();
+        let cart = Cartridge::new(rom).unwrap().with_save_file(&rom_path).unwrap();
+        assert_eq!(cart.read_prg_ram(0x6000), 0x42);
+        assert_eq!(cart.read_prg_ram(0x7FFF), 0x24);
+
+        drop(cart);
+        fs::remove_file(&save_path).unwrap();
+    }
+
+    #[test]
+    fn test_flush_after_writes_go_idle() {
+        let rom_path = temp_rom_path("idle");
+        let save_path = PathBuf::from(&rom_path).with_extension("sav");
+        let _ = fs::remove_file(&save_path);
+
+        let rom = Rom::new(&test_rom_raw(0b10)).unwrap();
+        let mut cart = Cartridge::new(rom).unwrap().with_save_file(&rom_path).unwrap();
+        cart.save_idle = Duration::from_secs(3600);
+        cart.write_prg_ram(0x6001, 7);
+        cart.update_save().unwrap();
+        assert!(!save_path.exists());
+
+        cart.save_idle = Duration::ZERO;
+        cart.update_save().unwrap();
+        assert_eq!(fs::read(&save_path).unwrap()[1], 7);
+
+        drop(cart);
+        fs::remove_file(&save_path).unwrap();
+    }
+
+    #[test]
+    fn test_no_save_file_without_battery() {
+        let rom_path = temp_rom_path("no_battery");
+        let rom = Rom::new(&test_rom_raw(0)).unwrap();
+        let mut cart = Cartridge::new(rom).unwrap().with_save_file(&rom_path).unwrap();
+
+        cart.write_prg_ram(0x6000, 1);
+        cart.flush_save().unwrap();
+
+        assert!(!PathBuf::from(&rom_path).with_extension("sav").exists());
+    }
+}
@@ -0,0 +1,106 @@
+// Code/data logging in FCEUX's .cdl format: a byte of flags for every byte
+// of PRG-ROM, followed by one for every byte of CHR-ROM. Disassemblers use
+// it to tell code from data. CHR-RAM is not logged, as in FCEUX.
+use std::fs;
+use std::path::Path;
+
+pub const PRG_CODE: u8 = 0x01;
+pub const PRG_DATA: u8 = 0x02;
+// the 8K window of $8000-$FFFF the byte was last accessed through
+const PRG_WINDOW_MASK: u8 = 0x0C;
+pub const PRG_INDIRECT_CODE: u8 = 0x10;
+pub const PRG_INDIRECT_DATA: u8 = 0x20;
+pub const PRG_PCM: u8 = 0x40;
+
+pub const CHR_RENDERED: u8 = 0x01;
+pub const CHR_READ: u8 = 0x02;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CodeDataLog {
+    pub prg: Vec<u8>,
+    pub chr: Vec<u8>,
+}
+
+impl CodeDataLog {
+    pub fn new(prg_size: usize, chr_size: usize) -> CodeDataLog {
+        CodeDataLog {
+            prg: vec![0; prg_size],
+            chr: vec![0; chr_size],
+        }
+    }
+
+    // Splits a .cdl file for a cartridge with these PRG-ROM and CHR-ROM sizes
+    pub fn from_bytes(data: &[u8], prg_size: usize, chr_size: usize) -> Result<CodeDataLog, String> {
+        if data.len() != prg_size + chr_size {
+            return Err(format!(
+                "CDL file is {} bytes, expected {} for this ROM",
+                data.len(),
+                prg_size + chr_size
+            ));
+        }
+        let (prg, chr) = data.split_at(prg_size);
+        Ok(CodeDataLog {
+            prg: prg.to_vec(),
+            chr: chr.to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&self.prg[..], &self.chr[..]].concat()
+    }
+
+    pub fn load(path: impl AsRef<Path>, prg_size: usize, chr_size: usize) -> Result<CodeDataLog, String> {
+        let path = path.as_ref();
+        let data = fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        CodeDataLog::from_bytes(&data, prg_size, chr_size)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        fs::write(path, self.to_bytes()).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    // How many PRG-ROM bytes have any of `flags` set
+    pub fn prg_count(&self, flags: u8) -> usize {
+        self.prg.iter().filter(|&&byte| byte & flags != 0).count()
+    }
+
+    pub fn chr_count(&self, flags: u8) -> usize {
+        self.chr.iter().filter(|&&byte| byte & flags != 0).count()
+    }
+}
+
+// Marks the PRG-ROM byte at `offset`, which the CPU accessed at `addr`
+pub fn log_prg(prg: &mut [u8], offset: usize, addr: u16, flags: u8) {
+    if let Some(byte) = prg.get_mut(offset) {
+        *byte |= ((addr >> 11) as u8 & PRG_WINDOW_MASK) | flags;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_flags_and_file_layout() {
+        let mut log = CodeDataLog::new(0x4000, 0x2000);
+        log_prg(&mut log.prg, 0x0010, 0xC010, PRG_CODE);
+        log_prg(&mut log.prg, 0x0010, 0xC010, PRG_DATA | PRG_INDIRECT_DATA);
+        log_prg(&mut log.prg, 0x0020, 0x8020, PRG_DATA);
+        log_prg(&mut log.prg, 0x4000, 0xFFFF, PRG_CODE);
+        log.chr[0x0100] = CHR_RENDERED | CHR_READ;
+
+        // $C000-$DFFF is window 2
+        assert_eq!(log.prg[0x10], PRG_CODE | PRG_DATA | PRG_INDIRECT_DATA | 0x08);
+        assert_eq!(log.prg[0x20], PRG_DATA);
+        assert_eq!(log.prg_count(PRG_CODE), 1);
+        assert_eq!(log.prg_count(PRG_DATA), 2);
+        assert_eq!(log.chr_count(CHR_READ), 1);
+
+        let bytes = log.to_bytes();
+        assert_eq!(bytes.len(), 0x6000);
+        assert_eq!(bytes[0x4100], CHR_RENDERED | CHR_READ);
+        assert_eq!(CodeDataLog::from_bytes(&bytes, 0x4000, 0x2000), Ok(log));
+        assert!(CodeDataLog::from_bytes(&bytes, 0x8000, 0x2000).is_err());
+    }
+}
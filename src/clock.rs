@@ -0,0 +1,227 @@
+use std::time::{Duration, Instant};
+
+pub const NTSC_FRAME_RATE: f64 = 60.0988;
+pub const PAL_FRAME_RATE: f64 = 50.007;
+
+// Fast-forward runs frames back to back for this long before giving the
+// frontend a chance to draw and handle input
+const FAST_FORWARD_BATCH: Duration = Duration::from_millis(15);
+// Falling further behind than this (a stall, a breakpoint) drops the missed
+// frames instead of running them all at once
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+// Decides when the next frame should be emulated. Frontends call
+// `should_run_frame` in a loop and run one frame every time it says so:
+//
+//     while clock.should_run_frame(Instant::now()) {
+//         emulator.step_frame();
+//     }
+pub struct Clock {
+    frame_rate: f64,
+    speed: f64,
+    fast_forward: bool,
+    paused: bool,
+    advance_frames: u32,
+    next_frame: Option<Instant>,
+    batch_start: Option<Instant>,
+}
+
+impl Clock {
+    pub fn new(frame_rate: f64) -> Clock {
+        Clock {
+            frame_rate,
+            speed: 1.0,
+            fast_forward: false,
+            paused: false,
+            advance_frames: 0,
+            next_frame: None,
+            batch_start: None,
+        }
+    }
+
+    pub fn ntsc() -> Clock {
+        Clock::new(NTSC_FRAME_RATE)
+    }
+
+    pub fn pal() -> Clock {
+        Clock::new(PAL_FRAME_RATE)
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        self.frame_rate
+    }
+
+    pub fn set_frame_rate(&mut self, frame_rate: f64) {
+        self.frame_rate = frame_rate;
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    // 1.0 is full speed, 0.5 half speed. Values above 1.0 run faster but
+    // still paced, unlike fast-forward.
+    pub fn set_speed(&mut self, speed: f64) -> Result<(), String> {
+        if !(speed.is_finite() && speed > 0.0) {
+            return Err(format!("Invalid speed {}", speed));
+        }
+        self.speed = speed;
+        Ok(())
+    }
+
+    pub fn fast_forward(&self) -> bool {
+        self.fast_forward
+    }
+
+    // Runs frames as fast as the host allows
+    pub fn set_fast_forward(&mut self, fast_forward: bool) {
+        if self.fast_forward && !fast_forward {
+            self.next_frame = None;
+            self.batch_start = None;
+        }
+        self.fast_forward = fast_forward;
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        if self.paused && !paused {
+            // pick up pacing from now instead of catching up the pause
+            self.next_frame = None;
+        }
+        self.paused = paused;
+        self.advance_frames = 0;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.set_paused(!self.paused);
+    }
+
+    // Pauses a running clock, or lets one more frame through a paused one
+    pub fn advance_frame(&mut self) {
+        if self.paused {
+            self.advance_frames += 1;
+        } else {
+            self.set_paused(true);
+        }
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / (self.frame_rate * self.speed))
+    }
+
+    pub fn should_run_frame(&mut self, now: Instant) -> bool {
+        if self.paused {
+            if self.advance_frames > 0 {
+                self.advance_frames -= 1;
+                return true;
+            }
+            return false;
+        }
+
+        if self.fast_forward {
+            let start = *self.batch_start.get_or_insert(now);
+            if now.duration_since(start) < FAST_FORWARD_BATCH {
+                return true;
+            }
+            self.batch_start = None;
+            return false;
+        }
+
+        let due = *self.next_frame.get_or_insert(now);
+        if now < due {
+            return false;
+        }
+        let next = due + self.frame_duration();
+        self.next_frame = Some(if now.duration_since(due) > MAX_LAG { now + self.frame_duration() } else { next });
+        true
+    }
+
+    // How long the frontend can sleep before the next frame is due
+    pub fn time_until_next_frame(&self, now: Instant) -> Option<Duration> {
+        if self.paused {
+            return if self.advance_frames > 0 { Some(Duration::ZERO) } else { None };
+        }
+        if self.fast_forward {
+            return Some(Duration::ZERO);
+        }
+        Some(self.next_frame.map_or(Duration::ZERO, |due| due.saturating_duration_since(now)))
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Clock {
+        Clock::ntsc()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frames_between(clock: &mut Clock, start: Instant, millis: u64) -> usize {
+        (0..=millis)
+            .map(|ms| start + Duration::from_millis(ms))
+            .map(|now| {
+                let mut frames = 0;
+                while clock.should_run_frame(now) {
+                    frames += 1;
+                }
+                frames
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_paces_at_frame_rate() {
+        let start = Instant::now();
+        // 1000ms covers frame 0 through frame 60 at 60.0988 Hz
+        assert_eq!(frames_between(&mut Clock::ntsc(), start, 1000), 61);
+        assert_eq!(frames_between(&mut Clock::pal(), start, 1000), 51);
+
+        let mut clock = Clock::ntsc();
+        clock.set_speed(0.25).unwrap();
+        assert_eq!(frames_between(&mut clock, start, 1000), 16);
+        assert!(clock.set_speed(0.0).is_err());
+    }
+
+    #[test]
+    fn test_drops_frames_after_a_stall() {
+        let start = Instant::now();
+        let mut clock = Clock::ntsc();
+        assert!(clock.should_run_frame(start));
+        assert!(clock.should_run_frame(start + Duration::from_secs(5)));
+        assert!(!clock.should_run_frame(start + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_pause_and_frame_advance() {
+        let start = Instant::now();
+        let mut clock = Clock::ntsc();
+        clock.advance_frame();
+        assert!(clock.paused());
+        assert!(!clock.should_run_frame(start));
+        assert_eq!(clock.time_until_next_frame(start), None);
+
+        clock.advance_frame();
+        clock.advance_frame();
+        assert_eq!(frames_between(&mut clock, start, 1000), 2);
+
+        clock.toggle_pause();
+        assert!(clock.should_run_frame(start + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_fast_forward_runs_in_batches() {
+        let start = Instant::now();
+        let mut clock = Clock::ntsc();
+        clock.set_fast_forward(true);
+        for _ in 0..1000 {
+            assert!(clock.should_run_frame(start));
+        }
+        assert!(!clock.should_run_frame(start + FAST_FORWARD_BATCH));
+        assert!(clock.should_run_frame(start + FAST_FORWARD_BATCH));
+    }
+}
@@ -1,5 +1,8 @@
-use std::{collections::HashMap, usize};
-use crate::opcodes;
+use std::collections::HashMap;
+use std::fmt;
+use crate::bus::Bus;
+use crate::cdl;
+use crate::opcodes::{self, OpCode};
 
 bitflags! {
     pub struct CpuFlags: u8 {
@@ -14,9 +17,76 @@ bitflags! {
     }
 }
 
-const STACK: u16 = 0x0010;
+const STACK: u16 = 0x0100;
 const STACK_RESET: u8 = 0xFD;
+// The NMOS opcodes that lock up the CPU until a reset, also known as KIL
+const JAM_OPCODES: [u8; 12] = [0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xB2, 0xD2, 0xF2];
+
+// Which member of the 6502 family the CPU behaves like
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum CpuVariant {
+    // The NES CPU: an NMOS 6502 with decimal mode cut out, so the D flag
+    // can be set but ADC and SBC ignore it
+    #[default]
+    Ricoh2A03,
+    // The original, with BCD arithmetic and its odd N, V and Z flags
+    Nmos6502,
+    // WDC 65C02: new instructions, valid flags in decimal mode and no JMP
+    // indirect page bug
+    Cmos65C02,
+}
+
+impl CpuVariant {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CpuVariant::Ricoh2A03 => "2a03",
+            CpuVariant::Nmos6502 => "6502",
+            CpuVariant::Cmos65C02 => "65c02",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<CpuVariant, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "2a03" | "nes" => Ok(CpuVariant::Ricoh2A03),
+            "6502" | "nmos" => Ok(CpuVariant::Nmos6502),
+            "65c02" | "cmos" => Ok(CpuVariant::Cmos65C02),
+            _ => Err(format!("Unknown CPU variant '{}'", name)),
+        }
+    }
+
+    pub fn has_decimal_mode(&self) -> bool {
+        *self != CpuVariant::Ricoh2A03
+    }
 
+    pub fn opcodes(&self) -> &'static HashMap<u8, &'static OpCode> {
+        match self {
+            CpuVariant::Cmos65C02 => &opcodes::CMOS_OPCODES_MAP,
+            _ => &opcodes::OPCODES_MAP,
+        }
+    }
+}
+
+// Why the CPU stopped, with the address of the instruction it stopped at
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CpuError {
+    // not an instruction of the variant, or one that is not emulated
+    UnknownOpcode { pc: u16, opcode: u8 },
+    // a JAM opcode, which hangs a real NMOS CPU
+    Jam { pc: u16, opcode: u8 },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::UnknownOpcode { pc, opcode } => write!(f, "Unknown opcode ${:02X} at ${:04X}", opcode, pc),
+            CpuError::Jam { pc, opcode } => write!(f, "CPU jammed by opcode ${:02X} at ${:04X}", opcode, pc),
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     pub reg_a: u8,
     pub reg_x: u8,
@@ -24,10 +94,45 @@ pub struct CPU {
     pub status: CpuFlags,
     pub pc: u16,
     pub sp: u8,
-    memory: [u8; 0xFFFF]
+    pub variant: CpuVariant,
+    pub bus: Bus,
+    // Run every bus access of an instruction on its own cycle, dummy reads
+    // and writes included, instead of ticking the bus once per instruction
+    pub cycle_accurate: bool,
+    // Records the bus accesses of instructions while set
+    pub access_log: Option<Vec<BusAccess>>,
+    // Stores and read-modify-writes do the indexed dummy read even
+    // without a page crossing
+    writes_operand: bool,
+    // the instruction being executed, for errors
+    opcode: u8,
+    operand_address: Option<u16>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BusAccess {
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
+// What `CPU::step` executed
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct StepInfo {
+    pub pc: u16,
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    pub mode: AddressingMode,
+    // the address the instruction read or wrote its operand at, if it has one
+    pub address: Option<u16>,
+    // bus cycles, including those of an NMI taken before the instruction
+    pub cycles: usize,
+    // the bus accesses, only recorded while access_log is set
+    pub accesses: Vec<BusAccess>,
+    // BRK, which stops run
+    pub halted: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[allow(non_camel_case_types)]
 pub enum AddressingMode {
     Immediate,
@@ -39,17 +144,19 @@ pub enum AddressingMode {
     AbsoluteY,
     IndirectX,
     IndirectY,
+    // 65C02 (zp)
+    ZeroPageIndirect,
     NoneAddressing,
 }
 
-trait Mem {
-    fn mem_read(&self, addr: u16) -> u8;
+pub trait Mem {
+    fn mem_read(&mut self, addr: u16) -> u8;
 
     fn mem_write(&mut self, addr: u16, data: u8);
 
-    fn mem_read_u16(&self, pos: u16) -> u16 {
+    fn mem_read_u16(&mut self, pos: u16) -> u16 {
         let low = self.mem_read(pos) as u16;
-        let high = self.mem_read(pos + 1) as u16;
+        let high = self.mem_read(pos.wrapping_add(1)) as u16;
         (high << 8) | low
     }
 
@@ -57,17 +164,17 @@ trait Mem {
         let low = (0x00ff & data) as u8;
         let high = (data >> 8) as u8;
         self.mem_write(pos, low);
-        self.mem_write(pos + 1, high);
+        self.mem_write(pos.wrapping_add(1), high);
     }
 }
 
 impl Mem for CPU {
-    fn mem_read(&self, addr: u16) -> u8 {
-        self.memory[addr as usize]
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.bus.mem_read(addr)
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
-        self.memory[addr as usize] = data;
+        self.bus.mem_write(addr, data)
     }
 }
 
@@ -80,19 +187,25 @@ impl CPU {
             status: CpuFlags::from_bits_truncate(0b100100),
             pc: 0,
             sp: STACK_RESET,
-            memory: [0; 0xFFFF]
+            variant: CpuVariant::default(),
+            bus: Bus::new(),
+            cycle_accurate: false,
+            access_log: None,
+            writes_operand: false,
+            opcode: 0,
+            operand_address: None,
         }
     }
 
-    pub fn load_and_run(&mut self, program: Vec<u8>) {
-        self.memory = [0; 0xFFFF];
+    pub fn load_and_run(&mut self, program: Vec<u8>) -> Result<(), CpuError> {
+        self.bus = Bus::new();
         self.load(program);
         self.reset();
-        self.run();
+        self.run()
     }
 
     pub fn load(&mut self, program: Vec<u8>) {
-        self.memory[0x8000..(0x8000 + program.len())].clone_from_slice(&program[..]);
+        self.bus.load(0x8000, &program[..]);
         self.mem_write_u16(0xFFFC, 0x8000);
     }
 
@@ -105,353 +218,598 @@ impl CPU {
         self.pc = self.mem_read_u16(0xFFFC);
     }
 
-    pub fn run(&mut self) {
-        self.run_with_callback(|_| {});
+    // Runs until BRK, or until an instruction fails
+    pub fn run(&mut self) -> Result<(), CpuError> {
+        self.run_with_callback(|_| {})
     }
 
-    pub fn run_with_callback<F>(&mut self, mut callback: F)
+    pub fn run_with_callback<F>(&mut self, mut callback: F) -> Result<(), CpuError>
     where F: FnMut(&mut CPU),
     {
-        let codes: &HashMap<u8, &'static opcodes::OpCode> = &*opcodes::OPCODES_MAP;
-        
         loop {
             callback(self);
-            let code = self.mem_read(self.pc);
-            self.pc += 1;
-            let pc_state = self.pc;
+            if self.step()?.halted {
+                return Ok(());
+            }
+        }
+    }
 
-            let operand = codes.get(&code).expect(&format!("Opcode {:x} is not recognized", code));
+    // Runs whole instructions until at least `cycles` bus cycles have passed
+    // or BRK is hit, returns the cycles run
+    pub fn run_for_cycles(&mut self, cycles: usize) -> Result<usize, CpuError> {
+        let start = self.bus.cycles;
+        while self.bus.cycles - start < cycles {
+            if self.step()?.halted {
+                break;
+            }
+        }
+        Ok(self.bus.cycles - start)
+    }
 
-            match code {
-                0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => {
-                    self.lda(&operand.mode);
-                }
+    // Runs until `done` returns true before an instruction, returns false if
+    // BRK was hit first
+    pub fn run_until<F>(&mut self, mut done: F) -> Result<bool, CpuError>
+    where F: FnMut(&CPU) -> bool,
+    {
+        loop {
+            if done(self) {
+                return Ok(true);
+            }
+            if self.step()?.halted {
+                return Ok(false);
+            }
+        }
+    }
 
-                0xAA => self.tax(),
-                0xE8 => self.inx(),
-                0x00 => return,
+    // Executes a single instruction, taking a pending NMI first. On an error
+    // the PC is left at the failing instruction.
+    pub fn step(&mut self) -> Result<StepInfo, CpuError> {
+        let start_cycles = self.bus.cycles;
+        let log_start = self.access_log.as_ref().map_or(0, Vec::len);
+        if self.bus.poll_nmi_status() {
+            self.interrupt_nmi();
+        }
 
-                /* CLD */ 0xD8 => self.status.remove(CpuFlags::DECIMAL_MODE),
+        let pc = self.pc;
+        self.operand_address = None;
+        let operand = self.execute()?;
+        if self.bus.is_logging_code_data() {
+            self.log_code_data(pc, operand);
+        }
+        Ok(StepInfo {
+            pc,
+            opcode: operand.code,
+            mnemonic: operand.mnemonic,
+            mode: operand.mode,
+            address: self.operand_address,
+            cycles: self.bus.cycles - start_cycles,
+            accesses: self.access_log.as_ref().map_or_else(Vec::new, |log| log[log_start..].to_vec()),
+            halted: operand.code == 0x00,
+        })
+    }
 
-                /* CLI */ 0x58 => self.status.remove(CpuFlags::INTERRUPT_DISABLE),
+    // Marks the instruction as code and what it read as data for the CDL
+    fn log_code_data(&mut self, pc: u16, operand: &OpCode) {
+        for i in 0..operand.len as u16 {
+            self.bus.log_prg(pc.wrapping_add(i), cdl::PRG_CODE);
+        }
+        let addr = match self.operand_address {
+            Some(addr) => addr,
+            None => return,
+        };
+        match operand.mode {
+            // JMP (indirect), which read its target from addr
+            AddressingMode::NoneAddressing => {
+                self.bus.log_prg(addr, cdl::PRG_DATA);
+                self.bus.log_prg(addr.wrapping_add(1), cdl::PRG_DATA);
+                self.bus.log_prg(self.pc, cdl::PRG_INDIRECT_CODE);
+            }
+            AddressingMode::Immediate => {}
+            _ if matches!(operand.mnemonic, "STA" | "STX" | "STY" | "STZ") => {}
+            AddressingMode::IndirectX | AddressingMode::IndirectY | AddressingMode::ZeroPageIndirect => {
+                self.bus.log_prg(addr, cdl::PRG_DATA | cdl::PRG_INDIRECT_DATA);
+            }
+            _ => self.bus.log_prg(addr, cdl::PRG_DATA),
+        }
+    }
 
-                /* CLV */ 0xB8 => self.status.remove(CpuFlags::OVERFLOW),
+    // Executes the instruction at PC, returns its opcode table entry
+    fn execute(&mut self) -> Result<&'static OpCode, CpuError> {
+        let codes = self.variant.opcodes();
+        let code = self.read(self.pc);
+        self.opcode = code;
+        self.pc = self.pc.wrapping_add(1);
+        let pc_state = self.pc;
+
+        let operand = match codes.get(&code) {
+            Some(operand) => operand,
+            None => return Err(self.fault()),
+        };
+        self.writes_operand = matches!(
+            operand.mnemonic,
+            "STA" | "STX" | "STY" | "STZ" | "ASL" | "LSR" | "ROL" | "ROR" | "INC" | "DEC" | "TSB" | "TRB"
+        );
+        // one byte instructions read the next byte anyway
+        if operand.len == 1 {
+            self.dummy_read(self.pc);
+        }
 
-                /* CLC */ 0x18 => self.clear_carry_flag(),
+        match code {
+            0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 | 0xB2 => {
+                self.lda(&operand.mode)?;
+            }
 
-                /* SEC */ 0x38 => self.set_carry_flag(),
+            0xAA => self.tax(),
+            0xE8 => self.inx(),
+            0x00 => self.brk(),
 
-                /* SEI */ 0x78 => self.status.insert(CpuFlags::INTERRUPT_DISABLE),
+            /* CLD */ 0xD8 => self.status.remove(CpuFlags::DECIMAL_MODE),
 
-                /* SED */ 0xF8 => self.status.insert(CpuFlags::DECIMAL_MODE),
+            /* CLI */ 0x58 => self.status.remove(CpuFlags::INTERRUPT_DISABLE),
 
-                /* PHA */ 0x48 => self.stack_push(self.reg_a),
+            /* CLV */ 0xB8 => self.status.remove(CpuFlags::OVERFLOW),
 
-                /* PLA */
-                0x68 => {
-                    self.pla();
-                }
+            /* CLC */ 0x18 => self.clear_carry_flag(),
 
-                /* PHP */
-                0x08 => {
-                    self.php();
-                }
+            /* SEC */ 0x38 => self.set_carry_flag(),
 
-                /* PLP */
-                0x28 => {
-                    self.plp();
-                }
+            /* SEI */ 0x78 => self.status.insert(CpuFlags::INTERRUPT_DISABLE),
 
-                /* ADC */
-                0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => {
-                    self.adc(&operand.mode);
-                }
+            /* SED */ 0xF8 => self.status.insert(CpuFlags::DECIMAL_MODE),
 
-                /* SBC */
-                0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => {
-                    self.sbc(&operand.mode);
-                }
+            /* PHA */ 0x48 => self.stack_push(self.reg_a),
 
-                /* AND */
-                0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => {
-                    self.and(&operand.mode);
-                }
+            /* PLA */
+            0x68 => {
+                self.pla();
+            }
 
-                /* EOR */
-                0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => {
-                    self.eor(&operand.mode);
-                }
+            /* PHP */
+            0x08 => {
+                self.php();
+            }
 
-                /* ORA */
-                0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => {
-                    self.ora(&operand.mode);
-                }
+            /* PLP */
+            0x28 => {
+                self.plp();
+            }
 
-                /* LSR */ 0x4A => self.lsr_accumulator(),
+            /* ADC */
+            0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 | 0x72 => {
+                self.adc(&operand.mode)?;
+            }
 
-                /* LSR */
-                0x46 | 0x56 | 0x4E | 0x5E => {
-                    self.lsr(&operand.mode);
-                }
+            /* SBC */
+            0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 | 0xF2 => {
+                self.sbc(&operand.mode)?;
+            }
 
-                /*ASL*/ 0x0A => self.asl_accumulator(),
+            /* AND */
+            0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 | 0x32 => {
+                self.and(&operand.mode)?;
+            }
 
-                /* ASL */
-                0x06 | 0x16 | 0x0E | 0x1E => {
-                    self.asl(&operand.mode);
-                }
+            /* EOR */
+            0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 | 0x52 => {
+                self.eor(&operand.mode)?;
+            }
 
-                /*ROL*/ 0x2A => self.rol_accumulator(),
+            /* ORA */
+            0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 | 0x12 => {
+                self.ora(&operand.mode)?;
+            }
 
-                /* ROL */
-                0x26 | 0x36 | 0x2E | 0x3E => {
-                    self.rol(&operand.mode);
-                }
+            /* LSR */ 0x4A => self.lsr_accumulator(),
 
-                /* ROR */ 0x6A => self.ror_accumulator(),
+            /* LSR */
+            0x46 | 0x56 | 0x4E | 0x5E => {
+                self.lsr(&operand.mode)?;
+            }
 
-                /* ROR */
-                0x66 | 0x76 | 0x6E | 0x7E => {
-                    self.ror(&operand.mode);
-                }
+            /*ASL*/ 0x0A => self.asl_accumulator(),
 
-                /* INC */
-                0xE6 | 0xF6 | 0xEE | 0xFE => {
-                    self.inc(&operand.mode);
-                }
+            /* ASL */
+            0x06 | 0x16 | 0x0E | 0x1E => {
+                self.asl(&operand.mode)?;
+            }
 
-                /* INY */
-                0xC8 => self.iny(),
+            /*ROL*/ 0x2A => self.rol_accumulator(),
 
-                /* DEC */
-                0xC6 | 0xD6 | 0xCE | 0xDE => {
-                    self.dec(&operand.mode);
-                }
+            /* ROL */
+            0x26 | 0x36 | 0x2E | 0x3E => {
+                self.rol(&operand.mode)?;
+            }
 
-                /* DEX */
-                0xCA => {
-                    self.dex();
-                }
+            /* ROR */ 0x6A => self.ror_accumulator(),
 
-                /* DEY */
-                0x88 => {
-                    self.dey();
-                }
+            /* ROR */
+            0x66 | 0x76 | 0x6E | 0x7E => {
+                self.ror(&operand.mode)?;
+            }
 
-                /* CMP */
-                0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => {
-                    self.compare(&operand.mode, self.reg_a);
-                }
+            /* INC */
+            0xE6 | 0xF6 | 0xEE | 0xFE => {
+                self.inc(&operand.mode)?;
+            }
 
-                /* CPY */
-                0xC0 | 0xC4 | 0xCC => {
-                    self.compare(&operand.mode, self.reg_y);
-                }
+            /* INY */
+            0xC8 => self.iny(),
 
-                /* CPX */
-                0xE0 | 0xE4 | 0xEC => self.compare(&operand.mode, self.reg_x),
+            /* DEC */
+            0xC6 | 0xD6 | 0xCE | 0xDE => {
+                self.dec(&operand.mode)?;
+            }
 
-                /* JMP Absolute */
-                0x4C => {
-                    let mem_address = self.mem_read_u16(self.pc);
-                    self.pc = mem_address;
-                }
+            /* DEX */
+            0xCA => {
+                self.dex();
+            }
 
-                /* JMP Indirect */
-                0x6C => {
-                    let mem_address = self.mem_read_u16(self.pc);
-                    
-                    let indirect_ref = if mem_address & 0x00FF == 0x00FF {
-                        let lo = self.mem_read(mem_address);
-                        let hi = self.mem_read(mem_address & 0xFF00);
-                        (hi as u16) << 8 | (lo as u16)
-                    } else {
-                        self.mem_read_u16(mem_address)
-                    };
-
-                    self.pc = indirect_ref;
-                }
+            /* DEY */
+            0x88 => {
+                self.dey();
+            }
 
-                /* JSR */
-                0x20 => {
-                    self.stack_push_u16(self.pc + 2 - 1);
-                    let target_address = self.mem_read_u16(self.pc);
-                    self.pc = target_address
-                }
+            /* CMP */
+            0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 | 0xD2 => {
+                self.compare(&operand.mode, self.reg_a)?;
+            }
 
-                /* RTS */
-                0x60 => {
-                    self.pc = self.stack_pop_u16() + 1;
-                }
+            /* CPY */
+            0xC0 | 0xC4 | 0xCC => {
+                self.compare(&operand.mode, self.reg_y)?;
+            }
 
-                /* RTI */
-                0x40 => {
-                    self.status.bits = self.stack_pop();
-                    self.status.remove(CpuFlags::BREAK);
-                    self.status.insert(CpuFlags::BREAK2);
+            /* CPX */
+            0xE0 | 0xE4 | 0xEC => self.compare(&operand.mode, self.reg_x)?,
 
-                    self.pc = self.stack_pop_u16();
-                }
+            /* JMP Absolute */
+            0x4C => {
+                let mem_address = self.read_u16(self.pc);
+                self.pc = mem_address;
+            }
 
-                /* BNE */
-                0xD0 => {
-                    self.branch(!self.status.contains(CpuFlags::ZERO));
-                }
+            /* JMP Indirect */
+            0x6C => {
+                let mem_address = self.read_u16(self.pc);
+                self.operand_address = Some(mem_address);
+
+                // the NMOS chips never carry into the high byte of the pointer
+                let indirect_ref = if mem_address & 0x00FF == 0x00FF && self.variant != CpuVariant::Cmos65C02 {
+                    let lo = self.read(mem_address);
+                    let hi = self.read(mem_address & 0xFF00);
+                    (hi as u16) << 8 | (lo as u16)
+                } else {
+                    self.read_u16(mem_address)
+                };
+
+                self.pc = indirect_ref;
+            }
 
-                /* BVS */
-                0x70 => {
-                    self.branch(self.status.contains(CpuFlags::OVERFLOW));
-                }
+            /* JSR */
+            0x20 => {
+                let lo = self.read(self.pc) as u16;
+                self.dummy_read(STACK + self.sp as u16);
+                self.stack_push_u16(self.pc.wrapping_add(1));
+                let hi = self.read(self.pc.wrapping_add(1)) as u16;
+                self.pc = hi << 8 | lo;
+            }
 
-                /* BVC */
-                0x50 => {
-                    self.branch(!self.status.contains(CpuFlags::OVERFLOW));
-                }
+            /* RTS */
+            0x60 => {
+                self.dummy_read(STACK + self.sp as u16);
+                let return_address = self.stack_pop_u16();
+                self.dummy_read(return_address);
+                self.pc = return_address.wrapping_add(1);
+            }
 
-                /* BPL */
-                0x10 => {
-                    self.branch(!self.status.contains(CpuFlags::NEGATIVE));
-                }
+            /* RTI */
+            0x40 => {
+                self.dummy_read(STACK + self.sp as u16);
+                self.status.bits = self.stack_pop();
+                self.status.remove(CpuFlags::BREAK);
+                self.status.insert(CpuFlags::BREAK2);
 
-                /* BMI */
-                0x30 => {
-                    self.branch(self.status.contains(CpuFlags::NEGATIVE));
-                }
+                self.pc = self.stack_pop_u16();
+            }
 
-                /* BEQ */
-                0xF0 => {
-                    self.branch(self.status.contains(CpuFlags::ZERO));
-                }
+            /* BNE */
+            0xD0 => {
+                self.branch(!self.status.contains(CpuFlags::ZERO));
+            }
 
-                /* BCS */
-                0xB0 => {
-                    self.branch(self.status.contains(CpuFlags::CARRY));
-                }
+            /* BVS */
+            0x70 => {
+                self.branch(self.status.contains(CpuFlags::OVERFLOW));
+            }
 
-                /* BCC */
-                0x90 => {
-                    self.branch(!self.status.contains(CpuFlags::CARRY));
-                }
+            /* BVC */
+            0x50 => {
+                self.branch(!self.status.contains(CpuFlags::OVERFLOW));
+            }
 
-                /* BIT */
-                0x24 | 0x2C => {
-                    self.bit(&operand.mode);
-                }
+            /* BPL */
+            0x10 => {
+                self.branch(!self.status.contains(CpuFlags::NEGATIVE));
+            }
 
-                /* STA */
-                0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => {
-                    self.sta(&operand.mode);
-                }
+            /* BMI */
+            0x30 => {
+                self.branch(self.status.contains(CpuFlags::NEGATIVE));
+            }
 
-                /* STX */
-                0x86 | 0x96 | 0x8E => {
-                    let addr = self.get_operand_address(&operand.mode);
-                    self.mem_write(addr, self.reg_x);
-                }
+            /* BEQ */
+            0xF0 => {
+                self.branch(self.status.contains(CpuFlags::ZERO));
+            }
 
-                /* STY */
-                0x84 | 0x94 | 0x8C => {
-                    let addr = self.get_operand_address(&operand.mode);
-                    self.mem_write(addr, self.reg_y);
-                }
+            /* BCS */
+            0xB0 => {
+                self.branch(self.status.contains(CpuFlags::CARRY));
+            }
 
-                /* LDX */
-                0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => {
-                    self.ldx(&operand.mode);
-                }
+            /* BCC */
+            0x90 => {
+                self.branch(!self.status.contains(CpuFlags::CARRY));
+            }
 
-                /* LDY */
-                0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => {
-                    self.ldy(&operand.mode);
-                }
+            /* BIT */
+            0x24 | 0x2C | 0x34 | 0x3C => {
+                self.bit(&operand.mode)?;
+            }
 
-                /* NOP */
-                0xEA => {
-                    //do nothing
-                }
+            /* STA */
+            0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 | 0x92 => {
+                self.sta(&operand.mode)?;
+            }
 
-                /* TAY */
-                0xA8 => {
-                    self.reg_y = self.reg_a;
-                    self.update_zero_and_negative_flags(self.reg_y);
-                }
+            /* STX */
+            0x86 | 0x96 | 0x8E => {
+                let addr = self.get_operand_address(&operand.mode)?;
+                self.write(addr, self.reg_x);
+            }
 
-                /* TSX */
-                0xBA => {
-                    self.reg_x = self.sp;
-                    self.update_zero_and_negative_flags(self.reg_x);
-                }
+            /* STY */
+            0x84 | 0x94 | 0x8C => {
+                let addr = self.get_operand_address(&operand.mode)?;
+                self.write(addr, self.reg_y);
+            }
 
-                /* TXA */
-                0x8A => {
-                    self.reg_a = self.reg_x;
-                    self.update_zero_and_negative_flags(self.reg_a);
-                }
+            /* LDX */
+            0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => {
+                self.ldx(&operand.mode)?;
+            }
 
-                /* TXS */
-                0x9A => {
-                    self.sp = self.reg_x;
-                }
+            /* LDY */
+            0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => {
+                self.ldy(&operand.mode)?;
+            }
 
-                /* TYA */
-                0x98 => {
-                    self.reg_a = self.reg_y;
-                    self.update_zero_and_negative_flags(self.reg_a);
-                }
+            /* NOP */
+            0xEA => {
+                //do nothing
+            }
+
+            /* TAY */
+            0xA8 => {
+                self.reg_y = self.reg_a;
+                self.update_zero_and_negative_flags(self.reg_y);
+            }
+
+            /* TSX */
+            0xBA => {
+                self.reg_x = self.sp;
+                self.update_zero_and_negative_flags(self.reg_x);
+            }
+
+            /* TXA */
+            0x8A => {
+                self.reg_a = self.reg_x;
+                self.update_zero_and_negative_flags(self.reg_a);
+            }
 
-                _ => todo!(),
+            /* TXS */
+            0x9A => {
+                self.sp = self.reg_x;
             }
 
-            if pc_state == self.pc {
-                self.pc += (operand.len - 1) as u16;
+            /* TYA */
+            0x98 => {
+                self.reg_a = self.reg_y;
+                self.update_zero_and_negative_flags(self.reg_a);
             }
+
+            /* 65C02 from here on, the other variants have no opcode table entries for these */
+
+            /* BRA */ 0x80 => self.branch(true),
+
+            /* JMP (Absolute,X) */
+            0x7C => {
+                let mem_address = self.read_u16(self.pc).wrapping_add(self.reg_x as u16);
+                self.operand_address = Some(mem_address);
+                self.pc = self.read_u16(mem_address);
+            }
+
+            /* PHX */ 0xDA => self.stack_push(self.reg_x),
+
+            /* PLX */
+            0xFA => {
+                self.dummy_read(STACK + self.sp as u16);
+                self.reg_x = self.stack_pop();
+                self.update_zero_and_negative_flags(self.reg_x);
+            }
+
+            /* PHY */ 0x5A => self.stack_push(self.reg_y),
+
+            /* PLY */
+            0x7A => {
+                self.dummy_read(STACK + self.sp as u16);
+                self.reg_y = self.stack_pop();
+                self.update_zero_and_negative_flags(self.reg_y);
+            }
+
+            /* INC A */ 0x1A => self.set_register_a(self.reg_a.wrapping_add(1)),
+
+            /* DEC A */ 0x3A => self.set_register_a(self.reg_a.wrapping_sub(1)),
+
+            /* STZ */
+            0x64 | 0x74 | 0x9C | 0x9E => {
+                let addr = self.get_operand_address(&operand.mode)?;
+                self.write(addr, 0);
+            }
+
+            /* TSB */
+            0x04 | 0x0C => {
+                let addr = self.get_operand_address(&operand.mode)?;
+                let data = self.read(addr);
+                self.dummy_write(addr, data);
+                self.status.set(CpuFlags::ZERO, data & self.reg_a == 0);
+                self.write(addr, data | self.reg_a);
+            }
+
+            /* TRB */
+            0x14 | 0x1C => {
+                let addr = self.get_operand_address(&operand.mode)?;
+                let data = self.read(addr);
+                self.dummy_write(addr, data);
+                self.status.set(CpuFlags::ZERO, data & self.reg_a == 0);
+                self.write(addr, data & !self.reg_a);
+            }
+
+            /* BIT Immediate, which only sets Z */
+            0x89 => {
+                let data = self.read(self.pc);
+                self.status.set(CpuFlags::ZERO, data & self.reg_a == 0);
+            }
+
+            /* RMB0-7, SMB0-7 */
+            _ if code & 0x0F == 0x07 => {
+                let addr = self.get_operand_address(&operand.mode)?;
+                let data = self.read(addr);
+                self.dummy_write(addr, data);
+                let bit = 1 << ((code >> 4) & 0x07);
+                let data = if code & 0x80 == 0 { data & !bit } else { data | bit };
+                self.write(addr, data);
+            }
+
+            /* BBR0-7, BBS0-7 */
+            _ if code & 0x0F == 0x0F => {
+                let addr = self.get_operand_address(&operand.mode)?;
+                let data = self.read(addr);
+                let bit_set = data & (1 << ((code >> 4) & 0x07)) != 0;
+                if bit_set == (code & 0x80 != 0) {
+                    let jump = self.read(self.pc.wrapping_add(1)) as i8;
+                    self.pc = self.pc.wrapping_add(2).wrapping_add(jump as u16);
+                }
+            }
+
+            _ => return Err(self.fault()),
+        }
+
+        if !self.cycle_accurate {
+            self.bus.tick(operand.cycles as usize);
+        }
+
+        if pc_state == self.pc {
+            self.pc = self.pc.wrapping_add((operand.len - 1) as u16);
+        }
+
+        Ok(operand)
+    }
+
+    // The error for the instruction being executed, rewinding the PC to it
+    fn fault(&mut self) -> CpuError {
+        self.pc = self.pc.wrapping_sub(1);
+        let (pc, opcode) = (self.pc, self.opcode);
+        if JAM_OPCODES.contains(&opcode) && self.variant != CpuVariant::Cmos65C02 {
+            CpuError::Jam { pc, opcode }
+        } else {
+            CpuError::UnknownOpcode { pc, opcode }
         }
     }
 
+    fn interrupt_nmi(&mut self) {
+        self.dummy_read(self.pc);
+        self.dummy_read(self.pc);
+        self.stack_push_u16(self.pc);
+        let mut flag = self.status;
+        flag.remove(CpuFlags::BREAK);
+        flag.insert(CpuFlags::BREAK2);
+        self.stack_push(flag.bits);
+
+        self.status.insert(CpuFlags::INTERRUPT_DISABLE);
+        if self.variant == CpuVariant::Cmos65C02 {
+            self.status.remove(CpuFlags::DECIMAL_MODE);
+        }
+        if !self.cycle_accurate {
+            self.bus.tick(7);
+        }
+        self.pc = self.read_u16(0xFFFA);
+    }
+
     // Instruction functions
 
-    fn ldy(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
+    // A software interrupt through the IRQ vector, returning past the
+    // padding byte. It still stops run.
+    fn brk(&mut self) {
+        self.stack_push_u16(self.pc.wrapping_add(1));
+        self.php();
+        self.status.insert(CpuFlags::INTERRUPT_DISABLE);
+        if self.variant == CpuVariant::Cmos65C02 {
+            self.status.remove(CpuFlags::DECIMAL_MODE);
+        }
+        self.pc = self.read_u16(0xFFFE);
+    }
+
+    fn ldy(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        let data = self.read(addr);
         self.reg_y = data;
         self.update_zero_and_negative_flags(self.reg_y);
+        Ok(())
     }
 
-    fn ldx(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
+    fn ldx(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        let data = self.read(addr);
         self.reg_x = data;
         self.update_zero_and_negative_flags(self.reg_x);
+        Ok(())
     }
 
-    fn lda(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
+    fn lda(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        let data = self.read(addr);
         self.reg_a = data;
         self.update_zero_and_negative_flags(self.reg_a);
+        Ok(())
     }
   
-    fn sta(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        self.mem_write(addr, self.reg_a);
+    fn sta(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        self.write(addr, self.reg_a);
+        Ok(())
     }
 
-    fn and(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
+    fn and(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        let data = self.read(addr);
         self.set_register_a(data & self.reg_a);
+        Ok(())
     }
 
-    fn eor(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
+    fn eor(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        let data = self.read(addr);
         self.set_register_a(data ^ self.reg_a);
+        Ok(())
     }
 
-    fn ora(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
+    fn ora(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        let data = self.read(addr);
         self.set_register_a(data | self.reg_a);
+        Ok(())
     }
   
     fn tax(&mut self) {
@@ -469,16 +827,92 @@ impl CPU {
         self.update_zero_and_negative_flags(self.reg_y);
     }
 
-    fn sbc(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(&mode);
-        let data = self.mem_read(addr);
-        self.add_to_register_a(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
+    fn sbc(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        let data = self.read(addr);
+        if self.decimal_mode() {
+            self.subtract_decimal(data);
+        } else {
+            self.add_to_register_a(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
+        }
+        Ok(())
+    }
+
+    fn adc(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        let value = self.read(addr);
+        if self.decimal_mode() {
+            self.add_decimal(value);
+        } else {
+            self.add_to_register_a(value);
+        }
+        Ok(())
+    }
+
+    fn decimal_mode(&self) -> bool {
+        self.variant.has_decimal_mode() && self.status.contains(CpuFlags::DECIMAL_MODE)
+    }
+
+    // BCD addition as in Bruce Clark's "Decimal Mode" tutorial. The NMOS
+    // chip takes N and V from the sum before the high digit is corrected
+    // and Z from the binary sum; the 65C02 fixes N and Z and spends a cycle
+    // on it.
+    fn add_decimal(&mut self, data: u8) {
+        let carry = self.status.contains(CpuFlags::CARRY) as i16;
+        let (a, b) = (self.reg_a as i16, data as i16);
+        let mut low = (a & 0x0F) + (b & 0x0F) + carry;
+        if low >= 0x0A {
+            low = ((low + 0x06) & 0x0F) + 0x10;
+        }
+        let sum = (a & 0xF0) + (b & 0xF0) + low;
+        let signed_sum = (a as u8 & 0xF0) as i8 as i16 + (b as u8 & 0xF0) as i8 as i16 + low;
+        self.status.set(CpuFlags::OVERFLOW, !(-128..=127).contains(&signed_sum));
+        let result = if sum >= 0xA0 { sum + 0x60 } else { sum };
+        self.status.set(CpuFlags::CARRY, result >= 0x100);
+
+        if self.variant == CpuVariant::Cmos65C02 {
+            self.set_register_a(result as u8);
+            self.bus.tick(1);
+        } else {
+            self.status.set(CpuFlags::ZERO, (a + b + carry) & 0xFF == 0);
+            self.status.set(CpuFlags::NEGATIVE, sum & 0x80 != 0);
+            self.reg_a = result as u8;
+        }
     }
 
-    fn adc(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
-        self.add_to_register_a(value);
+    // BCD subtraction. C and V come out as in binary mode on every variant,
+    // N and Z too on the NMOS chip.
+    fn subtract_decimal(&mut self, data: u8) {
+        let borrow = 1 - self.status.contains(CpuFlags::CARRY) as i16;
+        let (a, b) = (self.reg_a as i16, data as i16);
+        self.add_to_register_a(!data);
+
+        let low = (a & 0x0F) - (b & 0x0F) - borrow;
+        let result = if self.variant == CpuVariant::Cmos65C02 {
+            let mut result = a - b - borrow;
+            if result < 0 {
+                result -= 0x60;
+            }
+            if low < 0 {
+                result -= 0x06;
+            }
+            result
+        } else {
+            let low = if low < 0 { ((low - 0x06) & 0x0F) - 0x10 } else { low };
+            let result = (a & 0xF0) - (b & 0xF0) + low;
+            if result < 0 {
+                result - 0x60
+            } else {
+                result
+            }
+        };
+
+        if self.variant == CpuVariant::Cmos65C02 {
+            self.set_register_a(result as u8);
+            self.bus.tick(1);
+        } else {
+            self.reg_a = result as u8;
+        }
     }
 
     fn dey(&mut self) {
@@ -491,36 +925,39 @@ impl CPU {
         self.update_zero_and_negative_flags(self.reg_x);
     }
 
-    fn dec(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+    fn dec(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        let mut data = self.read(addr);
+        self.dummy_write(addr, data);
         data = data.wrapping_sub(1);
-        self.mem_write(addr, data);
+        self.write(addr, data);
         self.update_zero_and_negative_flags(data);
-        data
+        Ok(data)
     }
 
     fn pla(&mut self) {
+        self.dummy_read(STACK + self.sp as u16);
         let data = self.stack_pop();
         self.set_register_a(data);
     }
 
     fn plp(&mut self) {
+        self.dummy_read(STACK + self.sp as u16);
         self.status.bits = self.stack_pop();
         self.status.remove(CpuFlags::BREAK);
         self.status.insert(CpuFlags::BREAK2);
     }
 
     fn php(&mut self) {
-        let mut flags = self.status.clone();
+        let mut flags = self.status;
         flags.insert(CpuFlags::BREAK);
         flags.insert(CpuFlags::BREAK2);
         self.stack_push(flags.bits());
     }
 
-    fn bit(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
+    fn bit(&mut self, mode: &AddressingMode) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        let data = self.read(addr);
         let and = self.reg_a & data;
         if and == 0 {
             self.status.insert(CpuFlags::ZERO);
@@ -530,11 +967,12 @@ impl CPU {
 
         self.status.set(CpuFlags::NEGATIVE, data & 0b10000000 > 0);
         self.status.set(CpuFlags::OVERFLOW, data & 0b01000000 > 0);
+        Ok(())
     }
 
-    fn compare(&mut self, mode: &AddressingMode, compare_with: u8) {
-        let addr = self.get_operand_address(mode);
-        let data = self.mem_read(addr);
+    fn compare(&mut self, mode: &AddressingMode, compare_with: u8) -> Result<(), CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        let data = self.read(addr);
         if data <= compare_with {
             self.status.insert(CpuFlags::CARRY);
         } else {
@@ -542,27 +980,32 @@ impl CPU {
         }
 
         self.update_zero_and_negative_flags(compare_with.wrapping_sub(data));
+        Ok(())
     }
 
     fn branch(&mut self, condition: bool) {
+        let jump: i8 = self.read(self.pc) as i8;
         if condition {
-            let jump: i8 = self.mem_read(self.pc) as i8;
-            let jump_addr = self
-                .pc
-                .wrapping_add(1)
-                .wrapping_add(jump as u16);
+            let next = self.pc.wrapping_add(1);
+            let jump_addr = next.wrapping_add(jump as u16);
 
+            // the low byte is added first, and fixed up a cycle later
+            self.extra_cycle(next);
+            if next & 0xFF00 != jump_addr & 0xFF00 {
+                self.extra_cycle(next & 0xFF00 | jump_addr & 0x00FF);
+            }
             self.pc = jump_addr;
         }
     }
 
-    fn inc(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+    fn inc(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        let mut data = self.read(addr);
+        self.dummy_write(addr, data);
         data = data.wrapping_add(1);
-        self.mem_write(addr, data);
+        self.write(addr, data);
         self.update_zero_and_negative_flags(data);
-        data
+        Ok(data)
     }
 
     fn asl_accumulator(&mut self) {
@@ -572,22 +1015,23 @@ impl CPU {
         } else {
             self.clear_carry_flag();
         }
-        data = data << 1;
+        data <<= 1;
         self.set_register_a(data)
     }
 
-    fn asl(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+    fn asl(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        let mut data = self.read(addr);
+        self.dummy_write(addr, data);
         if data >> 7 == 1 {
             self.set_carry_flag();
         } else {
             self.clear_carry_flag();
         }
-        data = data << 1;
-        self.mem_write(addr, data);
+        data <<= 1;
+        self.write(addr, data);
         self.update_zero_and_negative_flags(data);
-        data
+        Ok(data)
     }
 
     fn lsr_accumulator(&mut self) {
@@ -597,27 +1041,29 @@ impl CPU {
         } else {
             self.clear_carry_flag();
         }
-        data = data >> 1;
+        data >>= 1;
         self.set_register_a(data)
     }
 
-    fn lsr(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+    fn lsr(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        let mut data = self.read(addr);
+        self.dummy_write(addr, data);
         if data & 1 == 1 {
             self.set_carry_flag();
         } else {
             self.clear_carry_flag();
         }
-        data = data >> 1;
-        self.mem_write(addr, data);
+        data >>= 1;
+        self.write(addr, data);
         self.update_zero_and_negative_flags(data);
-        data
+        Ok(data)
     }
 
-    fn rol(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+    fn rol(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        let mut data = self.read(addr);
+        self.dummy_write(addr, data);
         let old_carry = self.status.contains(CpuFlags::CARRY);
 
         if data >> 7 == 1 {
@@ -625,13 +1071,13 @@ impl CPU {
         } else {
             self.clear_carry_flag();
         }
-        data = data << 1;
+        data <<= 1;
         if old_carry {
-            data = data | 1;
+            data |= 1;
         }
-        self.mem_write(addr, data);
+        self.write(addr, data);
         self.update_zero_and_negative_flags(data);
-        data
+        Ok(data)
     }
 
     fn rol_accumulator(&mut self) {
@@ -643,16 +1089,17 @@ impl CPU {
         } else {
             self.clear_carry_flag();
         }
-        data = data << 1;
+        data <<= 1;
         if old_carry {
-            data = data | 1;
+            data |= 1;
         }
         self.set_register_a(data);
     }
 
-    fn ror(&mut self, mode: &AddressingMode) -> u8 {
-        let addr = self.get_operand_address(mode);
-        let mut data = self.mem_read(addr);
+    fn ror(&mut self, mode: &AddressingMode) -> Result<u8, CpuError> {
+        let addr = self.get_operand_address(mode)?;
+        let mut data = self.read(addr);
+        self.dummy_write(addr, data);
         let old_carry = self.status.contains(CpuFlags::CARRY);
 
         if data & 1 == 1 {
@@ -660,13 +1107,13 @@ impl CPU {
         } else {
             self.clear_carry_flag();
         }
-        data = data >> 1;
+        data >>= 1;
         if old_carry {
-            data = data | 0b10000000;
+            data |= 0b10000000;
         }
-        self.mem_write(addr, data);
+        self.write(addr, data);
         self.update_zero_and_negative_flags(data);
-        data
+        Ok(data)
     }
 
     fn ror_accumulator(&mut self) {
@@ -678,21 +1125,81 @@ impl CPU {
         } else {
             self.clear_carry_flag();
         }
-        data = data >> 1;
+        data >>= 1;
         if old_carry {
-            data = data | 0b10000000;
+            data |= 0b10000000;
         
 }
         self.set_register_a(data);
     }
 
+    // Bus accesses of instructions, one cycle each when cycle accurate
+    fn read(&mut self, addr: u16) -> u8 {
+        if self.cycle_accurate {
+            self.bus.tick(1);
+        }
+        let data = self.bus.mem_read(addr);
+        if let Some(log) = &mut self.access_log {
+            log.push(BusAccess::Read(addr, data));
+        }
+        data
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if self.cycle_accurate {
+            self.bus.tick(1);
+        }
+        self.bus.mem_write(addr, data);
+        if let Some(log) = &mut self.access_log {
+            log.push(BusAccess::Write(addr, data));
+        }
+    }
+
+    fn read_u16(&mut self, pos: u16) -> u16 {
+        let lo = self.read(pos) as u16;
+        let hi = self.read(pos.wrapping_add(1)) as u16;
+        hi << 8 | lo
+    }
+
+    // The accesses the CPU makes while it is busy with something else. They
+    // only happen when cycle accurate, as they can have side effects like
+    // clearing the vblank flag.
+    fn dummy_read(&mut self, addr: u16) {
+        if self.cycle_accurate {
+            self.read(addr);
+        }
+    }
+
+    // A cycle the opcode table does not count: a taken branch, or indexing
+    // across a page. Cycle accurate, it is the dummy read made meanwhile.
+    fn extra_cycle(&mut self, addr: u16) {
+        if self.cycle_accurate {
+            self.read(addr);
+        } else {
+            self.bus.tick(1);
+        }
+    }
+
+    // Read-modify-write instructions write the old value back while they
+    // modify it, the 65C02 reads it again instead
+    fn dummy_write(&mut self, addr: u16, data: u8) {
+        if !self.cycle_accurate {
+            return;
+        }
+        if self.variant == CpuVariant::Cmos65C02 {
+            self.read(addr);
+        } else {
+            self.write(addr, data);
+        }
+    }
+
     fn stack_pop(&mut self) -> u8 {
         self.sp = self.sp.wrapping_add(1);
-        self.mem_read((STACK as u16) + self.sp as u16)
+        self.read(STACK + self.sp as u16)
     }
 
     fn stack_push(&mut self, data: u8) {
-        self.mem_write((STACK as u16) + self.sp as u16, data);
+        self.write(STACK + self.sp as u16, data);
         self.sp = self.sp.wrapping_sub(1)
     }
 
@@ -765,58 +1272,84 @@ impl CPU {
         self.set_register_a(result);
     }
 
-    fn get_operand_address(&mut self, mode: &AddressingMode) -> u16 {
-        match mode {
+    // Indexing adds to the low byte first. The CPU reads from the address
+    // before the high byte is fixed up, and reads again if it has to be.
+    // Instructions that write always take that cycle, reads only when the
+    // page changes.
+    fn indexed(&mut self, base: u16, index: u8) -> u16 {
+        let addr = base.wrapping_add(index as u16);
+        let unfixed = base & 0xFF00 | addr & 0x00FF;
+        if self.writes_operand {
+            self.dummy_read(unfixed);
+        } else if base & 0xFF00 != addr & 0xFF00 {
+            self.extra_cycle(unfixed);
+        }
+        addr
+    }
+
+    fn get_operand_address(&mut self, mode: &AddressingMode) -> Result<u16, CpuError> {
+        let addr = match mode {
             AddressingMode::Immediate => self.pc,
 
-            AddressingMode::ZeroPage => self.mem_read(self.pc) as u16,
+            AddressingMode::ZeroPage => self.read(self.pc) as u16,
 
-            AddressingMode::Absolute => self.mem_read_u16(self.pc),
+            AddressingMode::Absolute => self.read_u16(self.pc),
             
             AddressingMode::ZeroPageX => {
-                let pos = self.mem_read(self.pc);
-                let addr = pos.wrapping_add(self.reg_x) as u16;
-                addr
+                let pos = self.read(self.pc);
+                self.dummy_read(pos as u16);
+                pos.wrapping_add(self.reg_x) as u16
             },
             AddressingMode::ZeroPageY => {
-                let pos = self.mem_read(self.pc);
-                let addr = pos.wrapping_add(self.reg_y) as u16;
-                addr
+                let pos = self.read(self.pc);
+                self.dummy_read(pos as u16);
+                pos.wrapping_add(self.reg_y) as u16
             },
             
             AddressingMode::AbsoluteX => {
-                let pos = self.mem_read_u16(self.pc);
-                let addr = pos.wrapping_add(self.reg_x as u16);
-                addr
+                let pos = self.read_u16(self.pc);
+                self.indexed(pos, self.reg_x)
             },
             AddressingMode::AbsoluteY => {
-                let pos = self.mem_read_u16(self.pc);
-                let addr = pos.wrapping_add(self.reg_y as u16);
-                addr
+                let pos = self.read_u16(self.pc);
+                self.indexed(pos, self.reg_y)
             },
             
             AddressingMode::IndirectX => {
-                let base = self.mem_read(self.pc);
+                let base = self.read(self.pc);
+                self.dummy_read(base as u16);
 
-                let ptr: u8 = (base as u8).wrapping_add(self.reg_x);
-                let hi = self.mem_read(ptr as u16);
-                let lo = self.mem_read(ptr.wrapping_add(1) as u16);
+                let ptr: u8 = base.wrapping_add(self.reg_x);
+                let lo = self.read(ptr as u16);
+                let hi = self.read(ptr.wrapping_add(1) as u16);
                 (hi as u16) << 8 | (lo as u16)
             },
             AddressingMode::IndirectY => {
-                let base = self.mem_read(self.pc);
+                let base = self.read(self.pc);
 
-                let lo = self.mem_read(base as u16);
-                let hi = self.mem_read((base as u8).wrapping_add(1) as u16);
+                let lo = self.read(base as u16);
+                let hi = self.read(base.wrapping_add(1) as u16);
                 let deref_base = (hi as u16) << 8 | (lo as u16);
-                let deref = deref_base.wrapping_add(self.reg_y as u16);
-                deref
+                self.indexed(deref_base, self.reg_y)
+            },
+            AddressingMode::ZeroPageIndirect => {
+                let base = self.read(self.pc);
+
+                let lo = self.read(base as u16);
+                let hi = self.read(base.wrapping_add(1) as u16);
+                (hi as u16) << 8 | (lo as u16)
             },
             
-            AddressingMode::NoneAddressing => {
-                panic!("mode {:?} is not supported :(", mode)
-            }
-        }
+            AddressingMode::NoneAddressing => return Err(self.fault()),
+        };
+        self.operand_address = Some(addr);
+        Ok(addr)
+    }
+}
+
+impl Default for CPU {
+    fn default() -> CPU {
+        CPU::new()
     }
 }
 
@@ -828,7 +1361,7 @@ mod test {
     fn test_lda_immidiate_load_data() {
        let mut cpu = CPU::new();
        
-       cpu.load_and_run(vec![0xA9, 0x05, 0x00]);
+       cpu.load_and_run(vec![0xA9, 0x05, 0x00]).unwrap();
 
        assert_eq!(cpu.reg_a, 5);
        assert!(cpu.status.bits() & 0b0000_0010 == 0b00);
@@ -839,7 +1372,7 @@ mod test {
     fn test_lda_zero_flag() {
         let mut cpu = CPU::new();
         
-        cpu.load_and_run(vec![0xA9, 0x00, 0x00]);
+        cpu.load_and_run(vec![0xA9, 0x00, 0x00]).unwrap();
         
         assert!(cpu.status.bits() & 0b0000_0010 == 0b10);
     }
@@ -851,9 +1384,9 @@ mod test {
         cpu.reset();
         cpu.reg_a = 0x55;
         
-        cpu.run();
+        cpu.run().unwrap();
         
-        assert_eq!(cpu.mem_read(0x10 as u16), 0x55);
+        assert_eq!(cpu.mem_read(0x10), 0x55);
     }
     
     #[test]
@@ -863,7 +1396,7 @@ mod test {
         cpu.reset();
         cpu.reg_a = 10;
 
-        cpu.run();
+        cpu.run().unwrap();
 
         assert_eq!(cpu.reg_x, 10)
     }
@@ -875,7 +1408,7 @@ mod test {
         cpu.reset();
         cpu.reg_x = 0xff;
         
-        cpu.run();
+        cpu.run().unwrap();
 
         assert_eq!(cpu.reg_x, 1)
     }
@@ -884,8 +1417,190 @@ mod test {
     fn test_5_ops_working_together() {
         let mut cpu = CPU::new();
 
-        cpu.load_and_run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
+        cpu.load_and_run(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]).unwrap();
 
         assert_eq!(cpu.reg_x, 0xc1)
     }
-}
\ No newline at end of file
+
+    fn run_variant(variant: CpuVariant, program: Vec<u8>) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.variant = variant;
+        cpu.load_and_run(program).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn test_decimal_mode_by_variant() {
+        // SED; CLC; LDA #$09; ADC #$01
+        let program = vec![0xF8, 0x18, 0xA9, 0x09, 0x69, 0x01, 0x00];
+        assert_eq!(run_variant(CpuVariant::Ricoh2A03, program.clone()).reg_a, 0x0A);
+        assert_eq!(run_variant(CpuVariant::Nmos6502, program.clone()).reg_a, 0x10);
+        assert_eq!(run_variant(CpuVariant::Cmos65C02, program).reg_a, 0x10);
+
+        // SED; SEC; LDA #$10; SBC #$01
+        let cpu = run_variant(CpuVariant::Nmos6502, vec![0xF8, 0x38, 0xA9, 0x10, 0xE9, 0x01, 0x00]);
+        assert_eq!(cpu.reg_a, 0x09);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+
+        // 99 + 1 wraps to 00 with carry; only the 65C02 sets Z, the NMOS
+        // chip looks at the binary sum $9A
+        let program = vec![0xF8, 0x18, 0xA9, 0x99, 0x69, 0x01, 0x00];
+        let nmos = run_variant(CpuVariant::Nmos6502, program.clone());
+        let cmos = run_variant(CpuVariant::Cmos65C02, program);
+        assert_eq!((nmos.reg_a, cmos.reg_a), (0x00, 0x00));
+        assert!(nmos.status.contains(CpuFlags::CARRY) && cmos.status.contains(CpuFlags::CARRY));
+        assert!(!nmos.status.contains(CpuFlags::ZERO) && nmos.status.contains(CpuFlags::NEGATIVE));
+        assert!(cmos.status.contains(CpuFlags::ZERO) && !cmos.status.contains(CpuFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_65c02_instructions() {
+        let mut cpu = run_variant(
+            CpuVariant::Cmos65C02,
+            vec![
+                0xA2, 0x42, // LDX #$42
+                0xDA, // PHX
+                0x7A, // PLY
+                0x85, 0x20, // STA $20 (A is 0)
+                0xA9, 0x0F, // LDA #$0F
+                0x04, 0x20, // TSB $20
+                0xC7, 0x20, // SMB4 $20
+                0x64, 0x21, // STZ $21
+                0x80, 0x02, // BRA +2
+                0xE6, 0x21, // INC $21, skipped
+                0xCF, 0x20, 0x02, // BBS4 $20, +2
+                0xE6, 0x21, // INC $21, skipped
+                0x1A, // INC A
+                0x00,
+            ],
+        );
+        assert_eq!(cpu.reg_y, 0x42);
+        assert_eq!(cpu.reg_a, 0x10);
+        assert_eq!(cpu.mem_read(0x20), 0x1F);
+        assert_eq!(cpu.mem_read(0x21), 0x00);
+    }
+
+    #[test]
+    fn test_jmp_indirect_page_bug() {
+        // JMP ($02FF) with the pointer split across $02FF/$0300, and a stray $0200
+        let run = |variant| {
+            let mut cpu = CPU::new();
+            cpu.variant = variant;
+            cpu.load(vec![0x6C, 0xFF, 0x02]);
+            cpu.reset();
+            cpu.mem_write(0x02FF, 0x00);
+            cpu.mem_write(0x0300, 0x90);
+            cpu.mem_write(0x0200, 0x80);
+            cpu.step().unwrap();
+            cpu.pc
+        };
+        assert_eq!(run(CpuVariant::Nmos6502), 0x8000);
+        assert_eq!(run(CpuVariant::Cmos65C02), 0x9000);
+        assert!(CpuVariant::Nmos6502.opcodes().get(&0xDA).is_none());
+    }
+
+    #[test]
+    fn test_errors_stop_at_the_opcode() {
+        let run = |program: Vec<u8>| {
+            let mut cpu = CPU::new();
+            cpu.load(program);
+            cpu.reset();
+            let err = cpu.run().unwrap_err();
+            assert_eq!(cpu.reg_x, 1);
+            (err, cpu.pc)
+        };
+        // INX; PHX, which only the 65C02 has
+        let (err, pc) = run(vec![0xE8, 0xDA]);
+        assert_eq!(err, CpuError::UnknownOpcode { pc: 0x8001, opcode: 0xDA });
+        assert_eq!(pc, 0x8001);
+        // INX; JAM
+        let (err, pc) = run(vec![0xE8, 0x12]);
+        assert_eq!(err, CpuError::Jam { pc: 0x8001, opcode: 0x12 });
+        assert_eq!(pc, 0x8001);
+        assert_eq!(err.to_string(), "CPU jammed by opcode $12 at $8001");
+    }
+
+    #[test]
+    fn test_step_info() {
+        // LDX #$01; LDA $0F,X; JMP $8000
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xA2, 0x01, 0xB5, 0x0F, 0x4C, 0x00, 0x80]);
+        cpu.reset();
+        cpu.mem_write(0x10, 0x42);
+        cpu.access_log = Some(Vec::new());
+
+        cpu.step().unwrap();
+        let step = cpu.step().unwrap();
+        assert_eq!(
+            step,
+            StepInfo {
+                pc: 0x8002,
+                opcode: 0xB5,
+                mnemonic: "LDA",
+                mode: AddressingMode::ZeroPageX,
+                address: Some(0x10),
+                cycles: 4,
+                accesses: vec![BusAccess::Read(0x8002, 0xB5), BusAccess::Read(0x8003, 0x0F), BusAccess::Read(0x10, 0x42)],
+                halted: false,
+            }
+        );
+
+        // whole instructions: JMP, LDX, LDA and JMP again
+        assert_eq!(cpu.run_for_cycles(10).unwrap(), 12);
+        assert_eq!(cpu.pc, 0x8000);
+        assert!(cpu.run_until(|cpu| cpu.pc == 0x8002).unwrap());
+        assert_eq!(cpu.reg_a, 0x42);
+    }
+
+    #[test]
+    fn test_stack_page_and_wraparound() {
+        // PHA; JSR $8005; BRK; RTS
+        let mut cpu = CPU::new();
+        cpu.load(vec![0x48, 0x20, 0x05, 0x80, 0x00, 0x60]);
+        cpu.reset();
+        cpu.reg_a = 0x42;
+        cpu.mem_write_u16(0xFFFE, 0x9000);
+        cpu.step().unwrap();
+        assert_eq!(cpu.mem_read(0x01FD), 0x42);
+        assert_eq!(cpu.mem_read(0x00FD), 0x00);
+        cpu.step().unwrap();
+        assert_eq!(cpu.mem_read_u16(0x01FB), 0x8003);
+
+        // BRK pushes the address past its padding byte and the flags with B set
+        cpu.run().unwrap();
+        assert_eq!(cpu.sp, STACK_RESET - 4);
+        assert_eq!(cpu.mem_read_u16(0x01FB), 0x8006);
+        assert_eq!(cpu.mem_read(0x01FA), 0b0011_0100);
+        assert_eq!(cpu.pc, 0x9000);
+        assert!(cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+
+        cpu.mem_write_u16(0xFFFF, 0x1234);
+        assert_eq!(cpu.mem_read(0xFFFF), 0x34);
+        assert_eq!(cpu.mem_read(0x0000), 0x12);
+        assert_eq!(cpu.mem_read_u16(0xFFFF), 0x1234);
+    }
+
+    #[test]
+    fn test_cycle_accurate_accesses() {
+        // LDX #$20; LDA $02F0,X, crossing into page 3; INC $10
+        let program = vec![0xA2, 0x20, 0xBD, 0xF0, 0x02, 0xE6, 0x10, 0x00];
+        let run = |cycle_accurate| {
+            let mut cpu = CPU::new();
+            cpu.cycle_accurate = cycle_accurate;
+            cpu.access_log = Some(Vec::new());
+            cpu.load_and_run(program.clone()).unwrap();
+            cpu
+        };
+
+        let cpu = run(false);
+        let log = cpu.access_log.unwrap();
+        assert!(!log.contains(&BusAccess::Read(0x0210, 0)));
+        assert!(!log.contains(&BusAccess::Write(0x10, 0)));
+
+        let cpu = run(true);
+        assert_eq!(cpu.bus.cycles, 2 + 5 + 5 + 7);
+        let log = cpu.access_log.unwrap();
+        assert_eq!(log[5], BusAccess::Read(0x0210, 0));
+        assert_eq!(log[9..12], [BusAccess::Read(0x10, 0), BusAccess::Write(0x10, 0), BusAccess::Write(0x10, 1)]);
+    }
+}
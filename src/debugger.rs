@@ -0,0 +1,264 @@
+use std::io::{self, BufRead, Write};
+
+use crate::cpu::{Mem, CPU};
+use crate::ramsearch::{CompareTo, Comparison, RamSearch, ValueSize};
+
+const HELP: &str = "\
+s, step                 execute one instruction
+c, continue             run until the next breakpoint
+b, break ADDR           toggle a breakpoint
+r, regs                 show registers
+m, mem ADDR [LEN]       dump memory
+w, poke ADDR VALUE      write a byte
+rs new [8|16] [s|u]     start a RAM search (size, signedness)
+rs OP [VALUE]           filter with eq/ne/lt/gt/le/ge against VALUE or the previous value
+rs changed|unchanged|inc|dec
+rs list [COUNT]         show remaining candidates
+q, quit                 exit";
+
+pub enum Reply {
+    Output(String),
+    Resume,
+    Quit,
+}
+
+pub struct Debugger {
+    pub paused: bool,
+    breakpoints: Vec<u16>,
+    ram_search: RamSearch,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            paused: true,
+            breakpoints: vec![],
+            ram_search: RamSearch::new(),
+        }
+    }
+
+    // Called before every instruction from run_with_callback
+    pub fn hook(&mut self, cpu: &mut CPU) {
+        if self.breakpoints.contains(&cpu.pc) {
+            self.paused = true;
+        }
+        if !self.paused {
+            return;
+        }
+
+        println!("{}", disassemble(cpu, cpu.pc));
+        let stdin = io::stdin();
+        loop {
+            print!("> ");
+            io::stdout().flush().unwrap();
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap() == 0 {
+                quit(cpu);
+            }
+
+            match self.execute(cpu, &line) {
+                Ok(Reply::Output(output)) => println!("{}", output),
+                Ok(Reply::Resume) => return,
+                Ok(Reply::Quit) => quit(cpu),
+                Err(err) => println!("error: {}", err),
+            }
+        }
+    }
+
+    pub fn execute(&mut self, cpu: &mut CPU, line: &str) -> Result<Reply, String> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let command = match args.first() {
+            Some(command) => *command,
+            None => return Ok(Reply::Output(String::new())),
+        };
+
+        match command {
+            "s" | "step" => {
+                self.paused = true;
+                Ok(Reply::Resume)
+            }
+            "c" | "continue" => {
+                self.paused = false;
+                Ok(Reply::Resume)
+            }
+            "b" | "break" => {
+                let addr = parse_addr(args.get(1))?;
+                if let Some(pos) = self.breakpoints.iter().position(|&b| b == addr) {
+                    self.breakpoints.remove(pos);
+                    Ok(Reply::Output(format!("breakpoint removed at ${:04X}", addr)))
+                } else {
+                    self.breakpoints.push(addr);
+                    Ok(Reply::Output(format!("breakpoint set at ${:04X}", addr)))
+                }
+            }
+            "r" | "regs" => Ok(Reply::Output(format!(
+                "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X}",
+                cpu.reg_a, cpu.reg_x, cpu.reg_y, cpu.status.bits(), cpu.sp, cpu.pc
+            ))),
+            "m" | "mem" => {
+                let addr = parse_addr(args.get(1))?;
+                let len = match args.get(2) {
+                    Some(len) => parse_value(len)? as u16,
+                    None => 16,
+                };
+                Ok(Reply::Output(dump(cpu, addr, len)))
+            }
+            "w" | "poke" => {
+                let addr = parse_addr(args.get(1))?;
+                let value = parse_value(args.get(2).ok_or("missing value")?)?;
+                cpu.mem_write(addr, value as u8);
+                Ok(Reply::Output(String::new()))
+            }
+            "rs" => self.ram_search_command(cpu, &args[1..]),
+            "h" | "help" => Ok(Reply::Output(HELP.to_string())),
+            "q" | "quit" => Ok(Reply::Quit),
+            _ => Err(format!("unknown command '{}', try 'help'", command)),
+        }
+    }
+
+    fn ram_search_command(&mut self, cpu: &mut CPU, args: &[&str]) -> Result<Reply, String> {
+        let search = &mut self.ram_search;
+        let op = args.first().copied().unwrap_or("list");
+
+        match op {
+            "new" => {
+                search.size = ValueSize::Byte;
+                search.signed = false;
+                for arg in &args[1..] {
+                    match *arg {
+                        "8" => search.size = ValueSize::Byte,
+                        "16" => search.size = ValueSize::Word,
+                        "s" => search.signed = true,
+                        "u" => search.signed = false,
+                        _ => return Err(format!("unknown search option '{}'", arg)),
+                    }
+                }
+                search.reset(cpu);
+            }
+            "list" => {
+                let count = match args.get(1) {
+                    Some(count) => parse_value(count)? as usize,
+                    None => 20,
+                };
+                let lines: Vec<String> = search
+                    .candidates()
+                    .iter()
+                    .take(count)
+                    .map(|c| format!("${:04X}: {} (was {})", c.addr, search.read_value(cpu, c.addr), c.previous))
+                    .collect();
+                return Ok(Reply::Output(format!("{}\n{} candidates", lines.join("\n"), search.candidates().len())));
+            }
+            "unchanged" => search.equal(cpu),
+            "changed" => search.changed(cpu),
+            "inc" => search.increased(cpu),
+            "dec" => search.decreased(cpu),
+            _ => {
+                let comparison = match op {
+                    "eq" => Comparison::Equal,
+                    "ne" => Comparison::NotEqual,
+                    "lt" => Comparison::Less,
+                    "gt" => Comparison::Greater,
+                    "le" => Comparison::LessOrEqual,
+                    "ge" => Comparison::GreaterOrEqual,
+                    _ => return Err(format!("unknown search operation '{}'", op)),
+                };
+                match (comparison, args.get(1)) {
+                    (Comparison::Equal, Some(value)) => search.specific(cpu, parse_value(value)?),
+                    (_, Some(value)) => search.filter(cpu, comparison, CompareTo::Value(parse_value(value)?)),
+                    (_, None) => search.filter(cpu, comparison, CompareTo::Previous),
+                }
+            }
+        }
+        Ok(Reply::Output(format!("{} candidates", search.candidates().len())))
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+// process::exit skips destructors, so battery RAM has to be written out by hand
+fn quit(cpu: &mut CPU) -> ! {
+    if let Err(err) = cpu.bus.flush_save() {
+        eprintln!("Failed to write save file: {}", err);
+    }
+    std::process::exit(0);
+}
+
+pub fn disassemble(cpu: &CPU, addr: u16) -> String {
+    let code = cpu.bus.peek(addr);
+    match cpu.variant.opcodes().get(&code) {
+        Some(op) => {
+            let bytes: Vec<String> = (0..op.len as u16)
+                .map(|i| format!("{:02X}", cpu.bus.peek(addr.wrapping_add(i))))
+                .collect();
+            format!("${:04X}  {:<9} {} ({} cycles)", addr, bytes.join(" "), op.mnemonic, op.cycles)
+        }
+        None => format!("${:04X}  {:02X}        ???", addr, code),
+    }
+}
+
+fn dump(cpu: &CPU, addr: u16, len: u16) -> String {
+    let mut lines = vec![];
+    for row in (0..len).step_by(16) {
+        let start = addr.wrapping_add(row);
+        let bytes: Vec<String> = (0..16.min(len - row))
+            .map(|i| format!("{:02X}", cpu.bus.peek(start.wrapping_add(i))))
+            .collect();
+        lines.push(format!("${:04X}: {}", start, bytes.join(" ")));
+    }
+    lines.join("\n")
+}
+
+// Addresses are always hex, with or without a '$'/'0x' prefix
+fn parse_addr(arg: Option<&&str>) -> Result<u16, String> {
+    let arg = arg.ok_or("missing address")?;
+    let digits = arg.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("bad address '{}'", arg))
+}
+
+// Values are decimal unless prefixed with '$' or '0x'
+fn parse_value(arg: &str) -> Result<i32, String> {
+    let parsed = if let Some(hex) = arg.strip_prefix('$').or_else(|| arg.strip_prefix("0x")) {
+        i32::from_str_radix(hex, 16)
+    } else {
+        arg.parse::<i32>()
+    };
+    parsed.map_err(|_| format!("bad value '{}'", arg))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(debugger: &mut Debugger, cpu: &mut CPU, line: &str) -> String {
+        match debugger.execute(cpu, line) {
+            Ok(Reply::Output(output)) => output,
+            Ok(_) => panic!("'{}' did not produce output", line),
+            Err(err) => panic!("'{}' failed: {}", line, err),
+        }
+    }
+
+    #[test]
+    fn test_ram_search_commands() {
+        let mut cpu = CPU::new();
+        let mut debugger = Debugger::new();
+        cpu.mem_write(0x10, 3);
+        cpu.mem_write(0x6010, 3);
+
+        assert_eq!(run(&mut debugger, &mut cpu, "rs new 8 u"), "10240 candidates");
+        assert_eq!(run(&mut debugger, &mut cpu, "rs eq 3"), "2 candidates");
+
+        cpu.mem_write(0x10, 4);
+        assert_eq!(run(&mut debugger, &mut cpu, "rs changed"), "1 candidates");
+        assert_eq!(run(&mut debugger, &mut cpu, "rs list"), "$0010: 4 (was 4)\n1 candidates");
+
+        assert_eq!(run(&mut debugger, &mut cpu, "rs new 16 s"), "10238 candidates");
+        assert!(debugger.execute(&mut cpu, "rs new 32").is_err());
+        assert!(debugger.execute(&mut cpu, "rs almost 3").is_err());
+        assert!(debugger.execute(&mut cpu, "rs eq x").is_err());
+    }
+}
@@ -0,0 +1,719 @@
+use std::fmt;
+
+use crate::cartridge::{Cartridge, Rom};
+use crate::cdl::CodeDataLog;
+use crate::cpu::{CpuError, Mem, CPU};
+use crate::joypad::{Joypad, JoypadButton};
+use crate::nsf::Nsf;
+use crate::recorder::{Recorder, Recording};
+use crate::region::Region;
+use crate::render;
+use crate::render::filter::{Filter, Image};
+use crate::render::frame::Frame;
+use crate::render::palette::Palette;
+use crate::render::text::{self, OverlayText};
+use crate::savestate;
+
+// Rate of the samples returned by `take_audio_samples`.
+pub const AUDIO_SAMPLE_RATE: u32 = 44_100;
+
+// NSF driver, in the unmapped space at $4100: INIT and PLAY are called with
+// JSR and return to an idle loop, where the emulator waits for the next PLAY
+const NSF_INIT: u16 = 0x4100;
+const NSF_IDLE: u16 = 0x4103;
+const NSF_PLAY: u16 = 0x4106;
+
+struct NsfPlayer {
+    nsf: Nsf,
+    track: u8,
+    // bus cycle at which PLAY is due
+    next_play: f64,
+    buttons: JoypadButton,
+}
+
+// Why a ROM could not be loaded or the emulated machine stopped.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum EmuError {
+    // The CPU hit an instruction it cannot execute.
+    Cpu(CpuError),
+    // The file is not a valid iNES or NSF image.
+    BadRom(String),
+    // The cartridge uses a mapper that is not emulated.
+    UnsupportedMapper(u8),
+    // Reading the ROM or its save file failed.
+    Io(String),
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmuError::Cpu(err) => err.fmt(f),
+            EmuError::BadRom(err) => write!(f, "Bad ROM: {}", err),
+            EmuError::UnsupportedMapper(mapper) => write!(f, "Mapper {} is not supported", mapper),
+            EmuError::Io(err) => f.write_str(err),
+        }
+    }
+}
+
+impl std::error::Error for EmuError {}
+
+impl From<CpuError> for EmuError {
+    fn from(err: CpuError) -> EmuError {
+        EmuError::Cpu(err)
+    }
+}
+
+// Entry point for using pabnes as a library: owns a CPU and everything
+// behind its bus, and exposes the operations a frontend needs.
+pub struct Emulator {
+    pub cpu: CPU,
+    frame: Frame,
+    palette: Palette,
+    audio_samples: Vec<f32>,
+    // how many of audio_samples the recorder has already written
+    recorded_samples: usize,
+    recorder: Option<Recorder>,
+    recording_error: Option<String>,
+    nsf: Option<NsfPlayer>,
+    halted: bool,
+    overlay: Vec<OverlayText>,
+}
+
+impl Emulator {
+    pub fn new() -> Emulator {
+        Emulator {
+            cpu: CPU::new(),
+            frame: Frame::new(),
+            palette: Palette::new(),
+            audio_samples: vec![],
+            recorded_samples: 0,
+            recorder: None,
+            recording_error: None,
+            nsf: None,
+            halted: false,
+            overlay: vec![],
+        }
+    }
+
+    // Inserts an iNES image and resets the CPU. The region comes from the
+    // header, NTSC if it does not say. NSF and NSFe rips are recognized and
+    // go to `load_nsf`.
+    pub fn load_rom(&mut self, raw: &[u8]) -> Result<(), EmuError> {
+        if Nsf::is_nsf(raw) {
+            return self.load_nsf(raw);
+        }
+        let cartridge = Self::cartridge(raw)?;
+        let region = cartridge.rom.region.unwrap_or_default();
+        self.insert_cartridge(cartridge, region);
+        Ok(())
+    }
+
+    // Like `load_rom`, but battery-backed RAM is kept in a .sav file next to
+    // `path`, and a region tag in the file name like "(Europe)" is used when
+    // the header does not give one.
+    pub fn load_rom_file(&mut self, path: &str) -> Result<(), EmuError> {
+        let raw = std::fs::read(path).map_err(|err| EmuError::Io(format!("{}: {}", path, err)))?;
+        if Nsf::is_nsf(&raw) {
+            return self.load_nsf(&raw);
+        }
+        let cartridge = Self::cartridge(&raw)?
+            .with_save_file(path)
+            .map_err(|err| EmuError::Io(format!("Failed to read save file: {}", err)))?;
+        let region = cartridge.rom.region.or_else(|| Region::from_file_name(path)).unwrap_or_default();
+        self.insert_cartridge(cartridge, region);
+        Ok(())
+    }
+
+    // Inserts an NSF or NSFe music rip and starts its first song. Tracks
+    // are changed with `set_track`, or with left and right on controller 1.
+    pub fn load_nsf(&mut self, raw: &[u8]) -> Result<(), EmuError> {
+        let nsf = Nsf::new(raw).map_err(EmuError::BadRom)?;
+        self.insert_cartridge(nsf.cartridge(), nsf.region);
+        let track = nsf.start_song;
+        self.nsf = Some(NsfPlayer {
+            nsf,
+            track,
+            next_play: 0.0,
+            buttons: JoypadButton::empty(),
+        });
+        self.set_track(track);
+        Ok(())
+    }
+
+    fn cartridge(raw: &[u8]) -> Result<Cartridge, EmuError> {
+        let rom = Rom::new(raw).map_err(EmuError::BadRom)?;
+        if !Cartridge::supports_mapper(rom.mapper) {
+            return Err(EmuError::UnsupportedMapper(rom.mapper));
+        }
+        Cartridge::new(rom).map_err(EmuError::BadRom)
+    }
+
+    // The loaded music rip, if one was loaded with `load_nsf`.
+    pub fn nsf(&self) -> Option<&Nsf> {
+        self.nsf.as_ref().map(|player| &player.nsf)
+    }
+
+    // The NSF song playing, counting from 1.
+    pub fn track(&self) -> Option<u8> {
+        self.nsf.as_ref().map(|player| player.track)
+    }
+
+    // Starts song `track` of the loaded NSF from the beginning, clamped to
+    // the songs it has. Does nothing for cartridges.
+    pub fn set_track(&mut self, track: u8) {
+        let player = match &mut self.nsf {
+            Some(player) => player,
+            None => return,
+        };
+        player.track = track.clamp(1, player.nsf.songs);
+        let nsf = &player.nsf;
+        let bus = &mut self.cpu.bus;
+        bus.work_ram_mut().fill(0);
+        if let Some(cartridge) = &mut bus.cartridge {
+            cartridge.prg_ram_mut().fill(0);
+        }
+        for addr in 0x4000..=0x4013 {
+            bus.mem_write(addr, 0);
+        }
+        bus.mem_write(0x4015, 0x00);
+        bus.mem_write(0x4015, 0x0F);
+        bus.mem_write(0x4017, 0x40);
+        if let Some(banks) = nsf.banks {
+            for (addr, bank) in (0x5FF8..).zip(banks) {
+                bus.mem_write(addr, bank);
+            }
+        }
+        let [init_lo, init_hi] = nsf.init_addr.to_le_bytes();
+        let [play_lo, play_hi] = nsf.play_addr.to_le_bytes();
+        let [idle_lo, idle_hi] = NSF_IDLE.to_le_bytes();
+        #[rustfmt::skip]
+        bus.load(NSF_INIT, &[
+            0x20, init_lo, init_hi, // JSR init
+            0x4C, idle_lo, idle_hi, // JMP idle
+            0x20, play_lo, play_hi, // JSR play
+            0x4C, idle_lo, idle_hi, // JMP idle
+        ]);
+        player.next_play = bus.cycles as f64;
+
+        self.cpu.reset();
+        self.cpu.reg_a = player.track - 1;
+        self.cpu.reg_x = (self.region() != Region::Ntsc) as u8;
+        self.cpu.pc = NSF_INIT;
+        self.halted = false;
+    }
+
+    // Calls PLAY once the last call has returned and its time has come
+    fn nsf_play(&mut self) {
+        let region = self.region();
+        if let Some(player) = &mut self.nsf {
+            let cycles = self.cpu.bus.cycles as f64;
+            if self.cpu.pc == NSF_IDLE && cycles >= player.next_play {
+                let period = player.nsf.play_rate(region) as f64 * region.cpu_clock_rate() as f64 / 1_000_000.0;
+                // a PLAY that overran its time is not caught up on
+                player.next_play = (player.next_play + period).max(cycles);
+                self.cpu.pc = NSF_PLAY;
+            }
+        }
+    }
+
+    // Left and right on controller 1 change the song
+    fn nsf_controls(&mut self) {
+        if let Some(player) = &mut self.nsf {
+            let buttons = self.cpu.bus.joypad1.button_status;
+            let pressed = buttons - player.buttons;
+            player.buttons = buttons;
+            let track = player.track;
+            if pressed.contains(JoypadButton::RIGHT) && track < player.nsf.songs {
+                self.set_track(track + 1);
+            } else if pressed.contains(JoypadButton::LEFT) && track > 1 {
+                self.set_track(track - 1);
+            }
+        }
+    }
+
+    fn insert_cartridge(&mut self, cartridge: Cartridge, region: Region) {
+        self.nsf = None;
+        self.cpu = CPU::new();
+        self.cpu.bus.set_region(region);
+        self.cpu.bus.insert_cartridge(cartridge);
+        self.reset();
+    }
+
+    pub fn region(&self) -> Region {
+        self.cpu.bus.region()
+    }
+
+    // Overrides the detected region. Takes effect immediately; a reset is
+    // not needed.
+    pub fn set_region(&mut self, region: Region) {
+        self.cpu.bus.set_region(region);
+    }
+
+    // Resets the console, or restarts the song for an NSF.
+    pub fn reset(&mut self) {
+        match self.track() {
+            Some(track) => self.set_track(track),
+            None => {
+                self.cpu.reset();
+                self.halted = false;
+            }
+        }
+    }
+
+    // True once the CPU has hit BRK or failed; stepping does nothing until
+    // the next reset.
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    // Executes one instruction. Returns false if the CPU is halted, and an
+    // error if the instruction could not be executed, which halts it too.
+    pub fn step_instruction(&mut self) -> Result<bool, EmuError> {
+        if !self.halted {
+            let frame = self.cpu.bus.frame_count();
+            self.nsf_play();
+            match self.cpu.step() {
+                Ok(step) => self.halted = step.halted,
+                Err(err) => {
+                    self.halted = true;
+                    return Err(err.into());
+                }
+            }
+            if self.cpu.bus.frame_count() != frame {
+                self.audio_samples.extend(self.cpu.bus.apu.take_samples());
+                self.render();
+                self.record_frame();
+                self.nsf_controls();
+            }
+        }
+        Ok(!self.halted)
+    }
+
+    fn record_frame(&mut self) {
+        if let Some(recorder) = &mut self.recorder {
+            let filter = &recorder.recording().filter;
+            let image = filter.apply(&self.cpu.bus.ppu.screen, &self.frame, self.cpu.bus.frame_count());
+            let samples = &self.audio_samples[self.recorded_samples..];
+            if let Err(err) = recorder.record_frame(&image, samples) {
+                // the error is kept for stop_recording to report
+                self.recording_error = Some(err);
+                self.recorder = None;
+            }
+            self.recorded_samples = self.audio_samples.len();
+        }
+    }
+
+    // Starts writing every frame and its sound to the files in `recording`,
+    // until `stop_recording`. A recording already running is stopped first.
+    pub fn start_recording(&mut self, recording: Recording) -> Result<(), String> {
+        self.stop_recording()?;
+        let frame_rate = self.region().frame_rate();
+        self.recorder = Some(Recorder::start(recording, frame_rate, AUDIO_SAMPLE_RATE)?);
+        self.recorded_samples = self.audio_samples.len();
+        Ok(())
+    }
+
+    // Finishes the files and returns how many frames went into them. Also
+    // reports a recording that stopped early because writing failed.
+    pub fn stop_recording(&mut self) -> Result<u64, String> {
+        if let Some(err) = self.recording_error.take() {
+            return Err(err);
+        }
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(0),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // Starts marking which PRG-ROM bytes run as code and which are read as
+    // data, and which CHR-ROM bytes are drawn or read through PPUDATA, for
+    // disassemblers. `saved` is a .cdl file from an earlier session to add
+    // to. Loading another ROM stops the log.
+    pub fn start_code_data_log(&mut self, saved: Option<&[u8]>) -> Result<(), String> {
+        let rom = match &self.cpu.bus.cartridge {
+            Some(cartridge) => &cartridge.rom,
+            None => return Err("No ROM loaded".to_string()),
+        };
+        let (prg_size, chr_size) = (rom.prg_rom.len(), rom.chr_rom.len());
+        let log = match saved {
+            Some(data) => CodeDataLog::from_bytes(data, prg_size, chr_size)?,
+            None => CodeDataLog::new(prg_size, chr_size),
+        };
+        self.cpu.bus.set_code_data_log(Some(log));
+        Ok(())
+    }
+
+    // The code/data log so far, in FCEUX's .cdl format with `to_bytes`.
+    pub fn code_data_log(&self) -> Option<CodeDataLog> {
+        self.cpu.bus.code_data_log()
+    }
+
+    pub fn stop_code_data_log(&mut self) -> Option<CodeDataLog> {
+        let log = self.cpu.bus.code_data_log();
+        self.cpu.bus.set_code_data_log(None);
+        log
+    }
+
+    // Runs until the next frame boundary. Returns false if the CPU halted
+    // on the way, and an error if it failed.
+    pub fn step_frame(&mut self) -> Result<bool, EmuError> {
+        self.step_frame_with_callback(|_| {})
+    }
+
+    // Like `step_frame`, calling `callback` before every instruction, the
+    // same way `CPU::run_with_callback` does.
+    pub fn step_frame_with_callback<F>(&mut self, mut callback: F) -> Result<bool, EmuError>
+    where F: FnMut(&mut CPU),
+    {
+        let frame = self.cpu.bus.frame_count();
+        while self.cpu.bus.frame_count() == frame {
+            callback(&mut self.cpu);
+            if !self.step_instruction()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.cpu.bus.frame_count()
+    }
+
+    fn joypad(&mut self, player: u8) -> Option<&mut Joypad> {
+        match player {
+            1 => Some(&mut self.cpu.bus.joypad1),
+            2 => Some(&mut self.cpu.bus.joypad2),
+            _ => None,
+        }
+    }
+
+    // Sets the full button state of controller 1 or 2. Other players are
+    // ignored, the console only has two ports.
+    pub fn set_input(&mut self, player: u8, buttons: JoypadButton) {
+        if let Some(joypad) = self.joypad(player) {
+            joypad.button_status = buttons;
+        }
+    }
+
+    pub fn set_button(&mut self, player: u8, button: JoypadButton, pressed: bool) {
+        if let Some(joypad) = self.joypad(player) {
+            joypad.set_button_pressed_status(button, pressed);
+        }
+    }
+
+    fn render(&mut self) {
+        render::render(&self.cpu.bus.ppu, &self.palette, &mut self.frame);
+    }
+
+    // The last completed frame run through a video filter, for display or
+    // screenshots, with the overlay drawn on top. `Filter::None` gives a
+    // plain copy.
+    pub fn filtered_frame(&self, filter: &Filter) -> Image {
+        let mut image = filter.apply(&self.cpu.bus.ppu.screen, &self.frame, self.frame_count());
+        text::draw_overlay(&mut image, &self.overlay);
+        image
+    }
+
+    // Text to draw over `filtered_frame`, like a script's `gui.text`, until
+    // the next call. Recordings and `frame_buffer` stay clean.
+    pub fn set_overlay(&mut self, overlay: Vec<OverlayText>) {
+        self.overlay = overlay;
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    // Switches the colors used for the frame buffer. The current frame is
+    // redrawn so the change shows even while paused.
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        self.render();
+    }
+
+    // The last completed frame, 256x240 packed RGB.
+    pub fn frame_buffer(&self) -> &[u8] {
+        &self.frame.data
+    }
+
+    // Mono samples at AUDIO_SAMPLE_RATE produced by the frames completed
+    // since the last call.
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        self.recorded_samples = 0;
+        std::mem::take(&mut self.audio_samples)
+    }
+
+    // The console's 2K of work RAM. The slice stays put for as long as the
+    // emulator does, so it can be handed to frontends that peek at it.
+    pub fn work_ram_mut(&mut self) -> &mut [u8] {
+        self.cpu.bus.work_ram_mut()
+    }
+
+    // Battery-backed PRG-RAM, if the loaded cartridge has any.
+    pub fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        match &mut self.cpu.bus.cartridge {
+            Some(cartridge) if cartridge.rom.battery => Some(cartridge.prg_ram_mut()),
+            _ => None,
+        }
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        savestate::save(&self.cpu)
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        savestate::load(&mut self.cpu, data)?;
+        if let Some(player) = &mut self.nsf {
+            player.next_play = self.cpu.bus.cycles as f64;
+        }
+        self.halted = false;
+        Ok(())
+    }
+}
+
+impl Default for Emulator {
+    fn default() -> Emulator {
+        Emulator::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::nrom;
+    use crate::cdl;
+    use crate::cpu::Mem;
+
+    // INC $6000; JMP $8000
+    fn test_rom() -> Vec<u8> {
+        nrom(&[0xEE, 0x00, 0x60, 0x4C, 0x00, 0x80], &[])
+    }
+
+    #[test]
+    fn test_step_frame_and_snapshot() {
+        let mut emu = Emulator::new();
+        emu.load_rom(&test_rom()).unwrap();
+
+        assert_eq!(emu.step_frame(), Ok(true));
+        assert_eq!(emu.frame_count(), 1);
+        let state = emu.save_state();
+        let counter = emu.cpu.mem_read(0x6000);
+
+        emu.step_frame().unwrap();
+        assert_ne!(emu.cpu.mem_read(0x6000), counter);
+
+        emu.load_state(&state).unwrap();
+        assert_eq!(emu.cpu.mem_read(0x6000), counter);
+        assert_eq!(emu.frame_buffer().len(), 256 * 240 * 3);
+    }
+
+    #[test]
+    fn test_region_sets_frame_length() {
+        let cycles_per_frame = |emu: &mut Emulator| {
+            emu.step_frame().unwrap();
+            let start = emu.cpu.bus.cycles;
+            for _ in 0..10 {
+                emu.step_frame().unwrap();
+            }
+            (emu.cpu.bus.cycles - start) as f64 / 10.0
+        };
+
+        let mut raw = test_rom();
+        // NES 2.0, PAL
+        raw[7] = 0b1000;
+        raw[12] = 1;
+        let mut emu = Emulator::new();
+        emu.load_rom(&raw).unwrap();
+        assert_eq!(emu.region(), Region::Pal);
+        assert!((cycles_per_frame(&mut emu) - 33247.5).abs() < 2.0);
+
+        emu.set_region(Region::Ntsc);
+        assert!((cycles_per_frame(&mut emu) - 29780.7).abs() < 2.0);
+
+        let state = emu.save_state();
+        emu.set_region(Region::Dendy);
+        assert!((cycles_per_frame(&mut emu) - 35464.0).abs() < 2.0);
+        emu.load_state(&state).unwrap();
+        assert_eq!(emu.region(), Region::Ntsc);
+    }
+
+    #[test]
+    fn test_input_reaches_controller_port() {
+        let mut emu = Emulator::new();
+        emu.load_rom(&test_rom()).unwrap();
+        emu.set_input(1, JoypadButton::BUTTON_A | JoypadButton::START);
+        emu.set_button(1, JoypadButton::START, false);
+        // there is no third port
+        emu.set_input(3, JoypadButton::SELECT);
+        emu.set_button(0, JoypadButton::SELECT, true);
+
+        emu.cpu.mem_write(0x4016, 1);
+        emu.cpu.mem_write(0x4016, 0);
+        assert_eq!(emu.cpu.mem_read(0x4016), 1);
+        assert_eq!(emu.cpu.mem_read(0x4016), 0);
+        assert_eq!(emu.cpu.bus.joypad2.button_status, JoypadButton::empty());
+    }
+
+    #[test]
+    fn test_program_draws_through_ppu_and_gets_nmi() {
+        #[rustfmt::skip]
+        let program = [
+            0x78,                                     // SEI
+            0xA9, 0x3F, 0x8D, 0x06, 0x20,             // palette at $3F00
+            0xA9, 0x00, 0x8D, 0x06, 0x20,
+            0xA9, 0x0F, 0x8D, 0x07, 0x20,             // backdrop black
+            0xA9, 0x30, 0x8D, 0x07, 0x20,             // color 1 white
+            0xA9, 0x20, 0x8D, 0x06, 0x20,             // nametable at $2000
+            0xA9, 0x00, 0x8D, 0x06, 0x20,
+            0xA9, 0x01, 0x8D, 0x07, 0x20,             // tile 1 top-left
+            0xA9, 0x00, 0x8D, 0x05, 0x20, 0x8D, 0x05, 0x20, // scroll 0,0
+            0xA9, 0x0A, 0x8D, 0x01, 0x20,             // show background
+            0xA9, 0x80, 0x8D, 0x00, 0x20,             // NMI on
+            0x4C, 0x36, 0x80,                         // JMP $8036
+        ];
+        let mut raw = test_rom();
+        raw[16..16 + program.len()].copy_from_slice(&program);
+        // NMI handler at $8040: INC $00; RTI
+        raw[16 + 0x40..16 + 0x43].copy_from_slice(&[0xE6, 0x00, 0x40]);
+        raw[16 + 0x3FFA] = 0x40;
+        raw[16 + 0x3FFB] = 0x80;
+        // tile 1 is solid color 1
+        raw[16 + 0x4000 + 16..16 + 0x4000 + 24].fill(0xFF);
+
+        let mut emu = Emulator::new();
+        emu.load_rom(&raw).unwrap();
+        for _ in 0..3 {
+            emu.step_frame().unwrap();
+        }
+
+        assert_eq!(emu.cpu.mem_read(0x00), 2);
+        assert_eq!(emu.frame_buffer()[..3], [0xFF, 0xFF, 0xFF]);
+        assert_eq!(emu.frame_buffer()[8 * 3..9 * 3], [0x05, 0x05, 0x05]);
+    }
+
+    #[test]
+    fn test_halts_on_brk() {
+        let mut emu = Emulator::new();
+        emu.cpu.load(vec![0xE8, 0x00]);
+        emu.reset();
+
+        assert_eq!(emu.step_instruction(), Ok(true));
+        assert_eq!(emu.step_instruction(), Ok(false));
+        assert!(emu.halted());
+        assert_eq!(emu.step_frame(), Ok(false));
+        assert_eq!(emu.cpu.reg_x, 1);
+    }
+
+    #[test]
+    fn test_reports_cpu_and_rom_errors() {
+        let mut emu = Emulator::new();
+        // INX; JAM
+        emu.cpu.load(vec![0xE8, 0x02]);
+        emu.reset();
+
+        let err = emu.step_frame().unwrap_err();
+        assert_eq!(err, EmuError::Cpu(CpuError::Jam { pc: 0x8001, opcode: 0x02 }));
+        assert_eq!(err.to_string(), "CPU jammed by opcode $02 at $8001");
+        assert!(emu.halted());
+        assert_eq!(emu.step_instruction(), Ok(false));
+
+        let mut raw = test_rom();
+        raw[6] = 0x10;
+        assert_eq!(emu.load_rom(&raw), Err(EmuError::UnsupportedMapper(1)));
+        assert!(matches!(emu.load_rom(&[0; 16]), Err(EmuError::BadRom(_))));
+    }
+
+    #[test]
+    fn test_code_data_log() {
+        #[rustfmt::skip]
+        let program = [
+            0xA9, 0x60, 0x85, 0x00, 0xA9, 0x80, 0x85, 0x01, // pointer to $8060 at $00
+            0xA0, 0x00, 0xB1, 0x00,                         // LDA ($00),Y
+            0xAD, 0x70, 0x80,                               // LDA $8070
+            0xA9, 0x00, 0x8D, 0x06, 0x20,                   // PPUADDR $0010
+            0xA9, 0x10, 0x8D, 0x06, 0x20,
+            0xAD, 0x07, 0x20,                               // LDA $2007
+            0xA9, 0x0A, 0x8D, 0x01, 0x20,                   // show background
+            0x6C, 0x80, 0x80,                               // JMP ($8080)
+        ];
+        let mut raw = test_rom();
+        raw[16..16 + program.len()].copy_from_slice(&program);
+        // $8080 points at JMP $8090
+        raw[16 + 0x80..16 + 0x82].copy_from_slice(&[0x90, 0x80]);
+        raw[16 + 0x90..16 + 0x93].copy_from_slice(&[0x4C, 0x90, 0x80]);
+
+        let mut emu = Emulator::new();
+        emu.load_rom(&raw).unwrap();
+        emu.start_code_data_log(None).unwrap();
+        for _ in 0..2 {
+            emu.step_frame().unwrap();
+        }
+        let log = emu.stop_code_data_log().unwrap();
+        assert!(emu.code_data_log().is_none());
+
+        assert_eq!(log.prg.len(), 0x4000);
+        assert_eq!(log.prg[0x0B], cdl::PRG_CODE);
+        assert_eq!(log.prg_count(cdl::PRG_CODE), program.len() + 3);
+        assert_eq!(log.prg[0x60], cdl::PRG_DATA | cdl::PRG_INDIRECT_DATA);
+        assert_eq!(log.prg[0x70], cdl::PRG_DATA);
+        assert_eq!(log.prg[0x80..0x82], [cdl::PRG_DATA, cdl::PRG_DATA]);
+        assert_eq!(log.prg[0x90], cdl::PRG_CODE | cdl::PRG_INDIRECT_CODE);
+        assert_eq!(log.chr[0x10], cdl::CHR_READ);
+        // tile 0 fills the screen
+        assert_eq!(log.chr_count(cdl::CHR_RENDERED), 16);
+
+        let saved = log.to_bytes();
+        emu.start_code_data_log(Some(&saved)).unwrap();
+        assert_eq!(emu.code_data_log(), Some(log));
+        assert!(emu.start_code_data_log(Some(&saved[1..])).is_err());
+    }
+
+    #[test]
+    fn test_records_frames() {
+        let mut emu = Emulator::new();
+        emu.load_rom(&test_rom()).unwrap();
+        let path = std::env::temp_dir().join(format!("pabnes_{}_emulator.rgb", std::process::id()));
+        emu.start_recording(Recording::new(&path)).unwrap();
+        for _ in 0..3 {
+            emu.step_frame().unwrap();
+        }
+        assert_eq!(emu.stop_recording(), Ok(3));
+        assert!(!emu.is_recording());
+
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 3 * 256 * 240 * 3);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("wav")).unwrap();
+    }
+
+    #[test]
+    fn test_plays_nsf() {
+        let mut emu = Emulator::new();
+        emu.load_nsf(&crate::nsf::test::test_nsf()).unwrap();
+        assert_eq!(emu.track(), Some(2));
+        for _ in 0..10 {
+            emu.step_frame().unwrap();
+        }
+        // INIT got the 0-based song, PLAY ran once a frame
+        assert_eq!(emu.cpu.mem_read(0x00), 2);
+        let plays = emu.cpu.mem_read(0x01);
+        assert!((9..=11).contains(&plays), "{} plays", plays);
+        let samples = emu.take_audio_samples();
+        // 735 samples a frame, the first frame is a little short
+        assert!((7200..=7350).contains(&samples.len()), "{} samples", samples.len());
+        assert!(samples.iter().any(|&sample| sample.abs() > 0.05));
+
+        emu.set_button(1, JoypadButton::RIGHT, true);
+        emu.step_frame().unwrap();
+        assert_eq!(emu.track(), Some(3));
+        emu.set_button(1, JoypadButton::RIGHT, false);
+        emu.step_frame().unwrap();
+        assert_eq!(emu.cpu.mem_read(0x00), 3);
+        emu.set_button(1, JoypadButton::RIGHT, true);
+        emu.step_frame().unwrap();
+        assert_eq!(emu.track(), Some(3));
+    }
+}
@@ -0,0 +1,331 @@
+use glutin::window::Fullscreen;
+use piston_window::texture::{CreateTexture, Format, UpdateTexture};
+use piston_window::*;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::audio::AudioOutput;
+use crate::clock::Clock;
+use crate::cpu::CPU;
+use crate::emulator::Emulator;
+use crate::input::{Hotkey, InputMap};
+use crate::recorder::Recording;
+use crate::render::filter::Filter;
+use crate::render::frame::Frame;
+use crate::render::text::OverlayText;
+
+// NES pixels are a little wider than they are tall
+const PIXEL_ASPECT: f64 = 8.0 / 7.0;
+// TVs hid roughly this many pixels on every edge, and games left garbage there
+const OVERSCAN: f64 = 8.0;
+const RENDER_RATE: u64 = 60;
+// Updates are polled this often and the clock decides when frames run
+const UPDATE_RATE: u64 = 1000;
+// How often the input config is checked for changes
+const CONFIG_POLL_UPDATES: u64 = 1000;
+
+pub struct VideoSettings {
+    pub scale: u32,
+    pub aspect_correction: bool,
+    pub crop_overscan: bool,
+    pub fullscreen: bool,
+    pub filter: Filter,
+}
+
+impl VideoSettings {
+    pub fn new() -> VideoSettings {
+        VideoSettings {
+            scale: 3,
+            aspect_correction: true,
+            crop_overscan: false,
+            fullscreen: false,
+            filter: Filter::None,
+        }
+    }
+
+    // The part of the frame that is shown, as [x, y, w, h]
+    fn source_rect(&self) -> [f64; 4] {
+        let (width, height) = (Frame::WIDTH as f64, Frame::HEIGHT as f64);
+        if self.crop_overscan {
+            [OVERSCAN, OVERSCAN, width - 2.0 * OVERSCAN, height - 2.0 * OVERSCAN]
+        } else {
+            [0.0, 0.0, width, height]
+        }
+    }
+
+    fn pixel_aspect(&self) -> f64 {
+        if self.aspect_correction {
+            PIXEL_ASPECT
+        } else {
+            1.0
+        }
+    }
+
+    fn window_size(&self) -> [u32; 2] {
+        let [_, _, width, height] = self.source_rect();
+        let scale = self.scale.max(1) as f64;
+        [(width * self.pixel_aspect() * scale).round() as u32, (height * scale) as u32]
+    }
+
+    // The largest whole-number scale of the picture that fits the window, centered
+    pub fn viewport(&self, window_size: [f64; 2]) -> [f64; 4] {
+        let [_, _, width, height] = self.source_rect();
+        let width = width * self.pixel_aspect();
+        let scale = (window_size[0] / width).min(window_size[1] / height).floor().max(1.0);
+        let (width, height) = (width * scale, height * scale);
+        [(window_size[0] - width) / 2.0, (window_size[1] - height) / 2.0, width, height]
+    }
+}
+
+impl Default for VideoSettings {
+    fn default() -> VideoSettings {
+        VideoSettings::new()
+    }
+}
+
+fn set_fullscreen(window: &PistonWindow, fullscreen: bool) {
+    let mode = if fullscreen { Some(Fullscreen::Borderless(None)) } else { None };
+    window.window.ctx.window().set_fullscreen(mode);
+}
+
+// Starts a recording named after the ROM, "game-1.y4m", "game-2.y4m", ..., or
+// stops the one that is running
+fn toggle_recording(emulator: &mut Emulator, rom_path: Option<&Path>, filter: &Filter) {
+    if emulator.is_recording() {
+        match emulator.stop_recording() {
+            Ok(frames) => println!("Recorded {} frames", frames),
+            Err(err) => eprintln!("Recording failed: {}", err),
+        }
+        return;
+    }
+    let base = rom_path.map_or_else(|| PathBuf::from("pabnes"), |path| path.with_extension(""));
+    let path = (1..)
+        .map(|n| PathBuf::from(format!("{}-{}.y4m", base.display(), n)))
+        .find(|path| !path.exists())
+        .expect("some recording name is free");
+    let recording = Recording { filter: filter.clone(), ..Recording::new(&path) };
+    match emulator.start_recording(recording) {
+        Ok(()) => println!("Recording to {}", path.display()),
+        Err(err) => eprintln!("Failed to start recording: {}", err),
+    }
+}
+
+// "Title - 3/12 Song name" while an NSF is playing
+fn nsf_title(emulator: &Emulator) -> Option<String> {
+    let (nsf, track) = (emulator.nsf()?, emulator.track()?);
+    let mut title = format!("{} - {}/{}", nsf.title, track, nsf.songs);
+    if let Some(name) = nsf.track_title(track) {
+        title = format!("{} {}", title, name);
+    }
+    Some(title)
+}
+
+// Quick save slot, kept in memory and mirrored to `path` when there is one
+struct StateSlot {
+    path: Option<PathBuf>,
+    data: Option<Vec<u8>>,
+}
+
+impl StateSlot {
+    fn save(&mut self, emulator: &Emulator) {
+        let data = emulator.save_state();
+        if let Some(path) = &self.path {
+            match fs::write(path, &data) {
+                Ok(()) => println!("State saved to {}", path.display()),
+                Err(err) => eprintln!("Failed to write {}: {}", path.display(), err),
+            }
+        }
+        self.data = Some(data);
+    }
+
+    fn load(&mut self, emulator: &mut Emulator) {
+        if self.data.is_none() {
+            self.data = self.path.as_ref().and_then(|path| fs::read(path).ok());
+        }
+        let result = match &self.data {
+            Some(data) => emulator.load_state(data),
+            None => Err("No state saved yet".to_string()),
+        };
+        if let Err(err) = result {
+            eprintln!("Failed to load state: {}", err);
+        }
+    }
+}
+
+// Debuggers and scripts riding along with `run`
+pub trait Hooks {
+    // Called before every instruction, like the callback of
+    // `CPU::run_with_callback`
+    fn instruction(&mut self, cpu: &mut CPU);
+
+    // Text to show over the frame that just finished
+    fn overlay(&self) -> Vec<OverlayText> {
+        vec![]
+    }
+}
+
+// Runs the emulator in a window until it is closed, paced by `clock`, with
+// sound going to `audio`. Save states and recordings go next to `rom_path`.
+pub fn run<H>(
+    emulator: &mut Emulator,
+    clock: &mut Clock,
+    mut settings: VideoSettings,
+    mut input: InputMap,
+    rom_path: Option<&Path>,
+    mut audio: Option<AudioOutput>,
+    mut hooks: H,
+) where
+    H: Hooks,
+{
+    let mut window: PistonWindow = WindowSettings::new("pabnes", settings.window_size())
+        .exit_on_esc(true)
+        .fullscreen(settings.fullscreen)
+        .build()
+        .unwrap_or_else(|err| panic!("Failed to create window: {}", err));
+    window.set_ups(UPDATE_RATE);
+    // late updates are skipped, the clock keeps time on its own
+    window.set_ups_reset(1);
+    window.set_max_fps(RENDER_RATE);
+    let mut fullscreen = settings.fullscreen;
+
+    let mut texture_context = window.create_texture_context();
+    let mut rgba = vec![];
+    let mut texture: Option<(G2dTexture, [u32; 2])> = None;
+    // frame and filter the texture was last filled from
+    let mut shown: Option<(u64, Filter)> = None;
+    let state_path = rom_path.map(|path| path.with_extension("state"));
+    let mut slot = StateSlot { path: state_path, data: None };
+    let mut updates: u64 = 0;
+    let mut title = None;
+
+    while let Some(event) = window.next() {
+        for hotkey in input.event(&event) {
+            match hotkey {
+                Hotkey::SaveState => slot.save(emulator),
+                Hotkey::LoadState => slot.load(emulator),
+                Hotkey::Pause => clock.toggle_pause(),
+                Hotkey::FrameAdvance => clock.advance_frame(),
+                Hotkey::SlowMotion => {
+                    let speed = if clock.speed() < 1.0 { 1.0 } else { input.slow_motion_speed() };
+                    if let Err(err) = clock.set_speed(speed) {
+                        eprintln!("{}", err);
+                    }
+                }
+                Hotkey::Reset => emulator.reset(),
+                Hotkey::Fullscreen => {
+                    fullscreen = !fullscreen;
+                    set_fullscreen(&window, fullscreen);
+                }
+                Hotkey::Record => toggle_recording(emulator, rom_path, &settings.filter),
+                Hotkey::NextFilter => {
+                    settings.filter = settings.filter.next();
+                    println!("Filter: {}", settings.filter.name());
+                }
+                // held, checked below
+                Hotkey::FastForward => {}
+            }
+        }
+
+        if event.update_args().is_some() {
+            updates += 1;
+            if updates.is_multiple_of(CONFIG_POLL_UPDATES) {
+                match input.reload_if_changed() {
+                    Ok(true) => println!("Input config reloaded"),
+                    Ok(false) => {}
+                    Err(err) => eprintln!("Failed to reload input config: {}", err),
+                }
+            }
+
+            clock.set_fast_forward(input.is_held(Hotkey::FastForward));
+            while !emulator.halted() && clock.should_run_frame(Instant::now()) {
+                input.apply(emulator);
+                match emulator.step_frame_with_callback(|cpu| hooks.instruction(cpu)) {
+                    Ok(true) => {}
+                    Ok(false) => println!("CPU halted"),
+                    Err(err) => eprintln!("CPU halted: {}", err),
+                }
+                emulator.set_overlay(hooks.overlay());
+                let samples = emulator.take_audio_samples();
+                if let Some(Err(err)) = audio.as_mut().map(|audio| audio.push(&samples)) {
+                    eprintln!("Audio output failed, muting: {}", err);
+                    audio = None;
+                }
+            }
+            if let Some(nsf_title) = nsf_title(emulator) {
+                if title.as_ref() != Some(&nsf_title) {
+                    window.set_title(nsf_title.clone());
+                    title = Some(nsf_title);
+                }
+            }
+            if let Err(err) = emulator.cpu.bus.update_save() {
+                eprintln!("Failed to write save file: {}", err);
+            }
+        }
+
+        if let Some(args) = event.render_args() {
+            let current = (emulator.frame_count(), settings.filter.clone());
+            if shown.as_ref() != Some(&current) {
+                let image = emulator.filtered_frame(&settings.filter);
+                rgba.resize(image.width * image.height * 4, 0xFF);
+                for (rgba, rgb) in rgba.chunks_exact_mut(4).zip(image.data.chunks_exact(3)) {
+                    rgba[..3].copy_from_slice(rgb);
+                }
+                let size = [image.width as u32, image.height as u32];
+                match &mut texture {
+                    Some((texture, texture_size)) if *texture_size == size => {
+                        if let Err(err) = UpdateTexture::update(texture, &mut texture_context, Format::Rgba8, &rgba, [0, 0], size) {
+                            eprintln!("Failed to upload frame: {}", err);
+                        }
+                    }
+                    _ => {
+                        // filters change the picture size, which needs a new texture
+                        let texture_settings = TextureSettings::new().filter(texture::Filter::Nearest);
+                        let created = Texture::create(&mut texture_context, Format::Rgba8, &rgba, size, &texture_settings)
+                            .unwrap_or_else(|err| panic!("Failed to create texture: {}", err));
+                        texture = Some((created, size));
+                    }
+                }
+                shown = Some(current);
+            }
+
+            let (texture, [width, _]) = texture.as_ref().expect("texture is created before drawing");
+            // the source rect is in NES pixels, filters scale the picture up
+            let scale = *width as f64 / Frame::WIDTH as f64;
+            let source = settings.source_rect().map(|value| value * scale);
+            let viewport = settings.viewport(args.window_size);
+            window.draw_2d(&event, |context, graphics, device| {
+                texture_context.encoder.flush(device);
+                clear([0.0, 0.0, 0.0, 1.0], graphics);
+                Image::new()
+                    .src_rect(source)
+                    .rect(viewport)
+                    .draw(texture, &context.draw_state, context.transform, graphics);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_viewport_uses_whole_scales() {
+        let settings = VideoSettings::new();
+        assert_eq!(settings.window_size(), [878, 720]);
+
+        // one pixel short of 3x leaves room for 2x only, centered
+        let viewport = settings.viewport([878.0, 719.0]);
+        assert_eq!(viewport[3], 480.0);
+        assert_eq!(viewport[1], 119.5);
+
+        let settings = VideoSettings {
+            aspect_correction: false,
+            crop_overscan: true,
+            ..VideoSettings::new()
+        };
+        assert_eq!(settings.viewport([1000.0, 1000.0]), [20.0, 52.0, 960.0, 896.0]);
+    }
+}
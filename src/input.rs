@@ -0,0 +1,509 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use piston_window::{Button, ButtonArgs, ButtonState, ControllerAxisArgs, GenericEvent, HatState, Key};
+use serde::Deserialize;
+
+use crate::emulator::Emulator;
+use crate::joypad::JoypadButton;
+
+// Sticks count as pressed once they are pushed this far
+const AXIS_THRESHOLD: f64 = 0.5;
+
+// Written to the config dir on first run, so there is something to edit
+pub const DEFAULT_CONFIG: &str = r#"# pabnes input bindings. Changes are picked up while the emulator runs.
+#
+# Keys use piston's key names: "X", "Return", "RShift", "Up", "F5", "D1", "NumPad8", ...
+# Gamepads: "pad0:button3", "pad0:axis1+", "pad0:axis1-", "pad0:hat0:up"
+
+# Turbo buttons toggle every this many frames
+turbo_period = 2
+# Speed while slow motion is on, as a fraction of full speed
+slow_motion_speed = 0.25
+
+[player1]
+a = ["X", "pad0:button1"]
+b = ["Z", "pad0:button0"]
+select = ["RShift", "pad0:button6"]
+start = ["Return", "pad0:button7"]
+up = ["Up", "pad0:axis1-", "pad0:hat0:up"]
+down = ["Down", "pad0:axis1+", "pad0:hat0:down"]
+left = ["Left", "pad0:axis0-", "pad0:hat0:left"]
+right = ["Right", "pad0:axis0+", "pad0:hat0:right"]
+turbo_a = ["S", "pad0:button3"]
+turbo_b = ["A", "pad0:button2"]
+
+[player2]
+a = ["pad1:button1"]
+b = ["pad1:button0"]
+select = ["pad1:button6"]
+start = ["pad1:button7"]
+up = ["pad1:axis1-", "pad1:hat0:up"]
+down = ["pad1:axis1+", "pad1:hat0:down"]
+left = ["pad1:axis0-", "pad1:hat0:left"]
+right = ["pad1:axis0+", "pad1:hat0:right"]
+turbo_a = ["pad1:button3"]
+turbo_b = ["pad1:button2"]
+
+[hotkeys]
+save_state = ["F5"]
+load_state = ["F7"]
+pause = ["P"]
+reset = ["F3"]
+fast_forward = ["Tab"]
+slow_motion = ["Backquote"]
+frame_advance = ["Backslash"]
+fullscreen = ["F11"]
+next_filter = ["F8"]
+record = ["F12"]
+"#;
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct PlayerConfig {
+    a: Vec<String>,
+    b: Vec<String>,
+    select: Vec<String>,
+    start: Vec<String>,
+    up: Vec<String>,
+    down: Vec<String>,
+    left: Vec<String>,
+    right: Vec<String>,
+    turbo_a: Vec<String>,
+    turbo_b: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct HotkeyConfig {
+    save_state: Vec<String>,
+    load_state: Vec<String>,
+    pause: Vec<String>,
+    reset: Vec<String>,
+    fast_forward: Vec<String>,
+    slow_motion: Vec<String>,
+    frame_advance: Vec<String>,
+    fullscreen: Vec<String>,
+    next_filter: Vec<String>,
+    record: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    turbo_period: u32,
+    slow_motion_speed: f64,
+    player1: PlayerConfig,
+    player2: PlayerConfig,
+    hotkeys: HotkeyConfig,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            turbo_period: 2,
+            slow_motion_speed: 0.25,
+            player1: PlayerConfig::default(),
+            player2: PlayerConfig::default(),
+            hotkeys: HotkeyConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HatDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Input {
+    Key(Key),
+    PadButton { pad: u32, button: u8 },
+    PadAxis { pad: u32, axis: u8, positive: bool },
+    PadHat { pad: u32, hat: u8, direction: HatDirection },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hotkey {
+    SaveState,
+    LoadState,
+    Pause,
+    Reset,
+    FastForward,
+    SlowMotion,
+    FrameAdvance,
+    Fullscreen,
+    NextFilter,
+    Record,
+}
+
+#[derive(Clone, Copy)]
+enum Action {
+    Button { player: u8, button: JoypadButton, turbo: bool },
+    Hotkey(Hotkey),
+}
+
+// Bindings from a config file plus the inputs currently held down
+pub struct InputMap {
+    bindings: HashMap<Input, Vec<Action>>,
+    turbo_period: u32,
+    slow_motion_speed: f64,
+    active: HashSet<Input>,
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+}
+
+impl InputMap {
+    pub fn parse(source: &str) -> Result<InputMap, String> {
+        let config: Config = toml::from_str(source).map_err(|err| err.to_string())?;
+        if config.turbo_period == 0 {
+            return Err("turbo_period must be at least 1".to_string());
+        }
+        if !(config.slow_motion_speed > 0.0 && config.slow_motion_speed <= 1.0) {
+            return Err("slow_motion_speed must be above 0 and at most 1".to_string());
+        }
+
+        let mut bindings: HashMap<Input, Vec<Action>> = HashMap::new();
+        let mut bind = |names: &[String], action: Action| -> Result<(), String> {
+            for name in names {
+                bindings.entry(parse_input(name)?).or_default().push(action);
+            }
+            Ok(())
+        };
+        for (player, buttons) in [(1, &config.player1), (2, &config.player2)] {
+            let mapping = [
+                (&buttons.a, JoypadButton::BUTTON_A, false),
+                (&buttons.b, JoypadButton::BUTTON_B, false),
+                (&buttons.select, JoypadButton::SELECT, false),
+                (&buttons.start, JoypadButton::START, false),
+                (&buttons.up, JoypadButton::UP, false),
+                (&buttons.down, JoypadButton::DOWN, false),
+                (&buttons.left, JoypadButton::LEFT, false),
+                (&buttons.right, JoypadButton::RIGHT, false),
+                (&buttons.turbo_a, JoypadButton::BUTTON_A, true),
+                (&buttons.turbo_b, JoypadButton::BUTTON_B, true),
+            ];
+            for (names, button, turbo) in mapping.iter() {
+                bind(names, Action::Button { player, button: *button, turbo: *turbo })?;
+            }
+        }
+        let hotkeys = &config.hotkeys;
+        let mapping = [
+            (&hotkeys.save_state, Hotkey::SaveState),
+            (&hotkeys.load_state, Hotkey::LoadState),
+            (&hotkeys.pause, Hotkey::Pause),
+            (&hotkeys.reset, Hotkey::Reset),
+            (&hotkeys.fast_forward, Hotkey::FastForward),
+            (&hotkeys.slow_motion, Hotkey::SlowMotion),
+            (&hotkeys.frame_advance, Hotkey::FrameAdvance),
+            (&hotkeys.fullscreen, Hotkey::Fullscreen),
+            (&hotkeys.next_filter, Hotkey::NextFilter),
+            (&hotkeys.record, Hotkey::Record),
+        ];
+        for (names, hotkey) in mapping.iter() {
+            bind(names, Action::Hotkey(*hotkey))?;
+        }
+
+        Ok(InputMap {
+            bindings,
+            turbo_period: config.turbo_period,
+            slow_motion_speed: config.slow_motion_speed,
+            active: HashSet::new(),
+            path: None,
+            modified: None,
+        })
+    }
+
+    // $XDG_CONFIG_HOME/pabnes/input.toml or the platform's equivalent
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("pabnes").join("input.toml"))
+    }
+
+    // Reads `path`, writing the default bindings there first if it does not exist
+    pub fn load(path: &Path) -> Result<InputMap, String> {
+        if !path.exists() {
+            let created = path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| fs::write(path, DEFAULT_CONFIG));
+            if let Err(err) = created {
+                eprintln!("Failed to write {}: {}", path.display(), err);
+            }
+        }
+
+        let source = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        let mut map = InputMap::parse(&source).map_err(|err| format!("{}: {}", path.display(), err))?;
+        map.path = Some(path.to_path_buf());
+        map.modified = modified_time(path);
+        Ok(map)
+    }
+
+    // Picks up edits to the config file. Returns true if the bindings were replaced;
+    // a broken file is reported and the old bindings stay.
+    pub fn reload_if_changed(&mut self) -> Result<bool, String> {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return Ok(false),
+        };
+        let modified = modified_time(&path);
+        if modified == self.modified {
+            return Ok(false);
+        }
+        self.modified = modified;
+
+        let mut map = InputMap::load(&path)?;
+        map.active = std::mem::take(&mut self.active);
+        *self = map;
+        Ok(true)
+    }
+
+    // Feeds a window event through the bindings, returning hotkeys that were just pressed
+    pub fn event<E: GenericEvent>(&mut self, event: &E) -> Vec<Hotkey> {
+        let mut pressed = vec![];
+        if let Some(ButtonArgs { button, state, .. }) = event.button_args() {
+            match button {
+                Button::Keyboard(key) => self.set(Input::Key(key), state == ButtonState::Press, &mut pressed),
+                Button::Controller(controller) => {
+                    let input = Input::PadButton { pad: controller.id, button: controller.button };
+                    self.set(input, state == ButtonState::Press, &mut pressed);
+                }
+                Button::Hat(hat) => {
+                    for direction in [HatDirection::Up, HatDirection::Down, HatDirection::Left, HatDirection::Right] {
+                        let held = state == ButtonState::Press && hat_points(hat.state, direction);
+                        let input = Input::PadHat { pad: hat.id, hat: hat.which, direction };
+                        self.set(input, held, &mut pressed);
+                    }
+                }
+                Button::Mouse(_) => {}
+            }
+        }
+        if let Some(ControllerAxisArgs { id, axis, position }) = event.controller_axis_args() {
+            let positive = Input::PadAxis { pad: id, axis, positive: true };
+            let negative = Input::PadAxis { pad: id, axis, positive: false };
+            self.set(positive, position > AXIS_THRESHOLD, &mut pressed);
+            self.set(negative, position < -AXIS_THRESHOLD, &mut pressed);
+        }
+        pressed
+    }
+
+    fn set(&mut self, input: Input, held: bool, pressed: &mut Vec<Hotkey>) {
+        if !held {
+            self.active.remove(&input);
+            return;
+        }
+        // key repeat and stick jitter must not fire hotkeys again
+        if !self.active.insert(input) {
+            return;
+        }
+        for action in self.bindings.get(&input).into_iter().flatten() {
+            if let Action::Hotkey(hotkey) = action {
+                pressed.push(*hotkey);
+            }
+        }
+    }
+
+    pub fn slow_motion_speed(&self) -> f64 {
+        self.slow_motion_speed
+    }
+
+    pub fn is_held(&self, hotkey: Hotkey) -> bool {
+        self.active_actions().any(|action| matches!(action, Action::Hotkey(held) if *held == hotkey))
+    }
+
+    fn active_actions(&self) -> impl Iterator<Item = &Action> {
+        self.active.iter().filter_map(move |input| self.bindings.get(input)).flatten()
+    }
+
+    // Sets both controllers from what is held, with turbo buttons pulsing by frame
+    pub fn apply(&self, emulator: &mut Emulator) {
+        let turbo_on = (emulator.frame_count() / self.turbo_period as u64).is_multiple_of(2);
+        let mut buttons = [JoypadButton::empty(); 2];
+        for action in self.active_actions() {
+            if let Action::Button { player, button, turbo } = action {
+                if !turbo || turbo_on {
+                    buttons[*player as usize - 1].insert(*button);
+                }
+            }
+        }
+        emulator.set_input(1, buttons[0]);
+        emulator.set_input(2, buttons[1]);
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn hat_points(state: HatState, direction: HatDirection) -> bool {
+    use HatDirection::*;
+    matches!(
+        (state, direction),
+        (HatState::Up, Up)
+            | (HatState::Down, Down)
+            | (HatState::Left, Left)
+            | (HatState::Right, Right)
+            | (HatState::RightUp, Right | Up)
+            | (HatState::RightDown, Right | Down)
+            | (HatState::LeftUp, Left | Up)
+            | (HatState::LeftDown, Left | Down)
+    )
+}
+
+// SDL keycodes: printable ASCII, then the keys numbered after scancodes
+fn key_from_name(name: &str) -> Option<Key> {
+    (0x01..0x80)
+        .chain(0x4000_0039..=0x4000_011A)
+        .map(Key::from)
+        .find(|key| *key != Key::Unknown && format!("{:?}", key).eq_ignore_ascii_case(name))
+}
+
+fn parse_input(name: &str) -> Result<Input, String> {
+    let invalid = || format!("Unknown input '{}'", name);
+    let rest = match name.strip_prefix("pad") {
+        Some(rest) => rest,
+        None => return key_from_name(name).map(Input::Key).ok_or_else(invalid),
+    };
+
+    let mut parts = rest.split(':');
+    let pad = parts.next().and_then(|pad| pad.parse().ok()).ok_or_else(invalid)?;
+    let control = parts.next().ok_or_else(invalid)?;
+    let input = if let Some(button) = control.strip_prefix("button") {
+        let button = button.parse().map_err(|_| invalid())?;
+        Input::PadButton { pad, button }
+    } else if let Some(axis) = control.strip_prefix("axis") {
+        let (axis, sign) = axis.split_at(axis.len().saturating_sub(1));
+        let positive = match sign {
+            "+" => true,
+            "-" => false,
+            _ => return Err(invalid()),
+        };
+        let axis = axis.parse().map_err(|_| invalid())?;
+        Input::PadAxis { pad, axis, positive }
+    } else if let Some(hat) = control.strip_prefix("hat") {
+        let hat = hat.parse().map_err(|_| invalid())?;
+        let direction = match parts.next() {
+            Some("up") => HatDirection::Up,
+            Some("down") => HatDirection::Down,
+            Some("left") => HatDirection::Left,
+            Some("right") => HatDirection::Right,
+            _ => return Err(invalid()),
+        };
+        Input::PadHat { pad, hat, direction }
+    } else {
+        return Err(invalid());
+    };
+
+    if parts.next().is_some() {
+        return Err(invalid());
+    }
+    Ok(input)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use piston_window::{ControllerButton, Event, Input as PistonInput, Loop, UpdateArgs};
+
+    fn button_event(button: Button, state: ButtonState) -> Event {
+        Event::Input(PistonInput::Button(ButtonArgs { state, button, scancode: None }), None)
+    }
+
+    #[test]
+    fn test_parse_inputs() {
+        assert_eq!(parse_input("return"), Ok(Input::Key(Key::Return)));
+        assert_eq!(parse_input("F11"), Ok(Input::Key(Key::F11)));
+        assert_eq!(parse_input("pad1:button3"), Ok(Input::PadButton { pad: 1, button: 3 }));
+        assert_eq!(
+            parse_input("pad0:axis1-"),
+            Ok(Input::PadAxis { pad: 0, axis: 1, positive: false })
+        );
+        assert_eq!(
+            parse_input("pad0:hat0:left"),
+            Ok(Input::PadHat { pad: 0, hat: 0, direction: HatDirection::Left })
+        );
+        assert!(parse_input("NoSuchKey").is_err());
+        assert!(parse_input("pad0:axis1").is_err());
+        assert!(parse_input("pad0:button1:extra").is_err());
+
+        assert!(InputMap::parse(DEFAULT_CONFIG).is_ok());
+        assert!(InputMap::parse("[player3]").is_err());
+        assert!(InputMap::parse("[hotkeys]\npause = [\"Nope\"]").is_err());
+        assert!(InputMap::parse("slow_motion_speed = 0.0").is_err());
+        assert_eq!(InputMap::parse("slow_motion_speed = 0.5").unwrap().slow_motion_speed(), 0.5);
+    }
+
+    #[test]
+    fn test_buttons_and_turbo_reach_controllers() {
+        let mut map = InputMap::parse(DEFAULT_CONFIG).unwrap();
+        let mut emu = Emulator::new();
+        emu.cpu.load(vec![0x4C, 0x00, 0x80]);
+        emu.reset();
+
+        map.event(&button_event(Button::Keyboard(Key::X), ButtonState::Press));
+        map.event(&button_event(Button::Keyboard(Key::A), ButtonState::Press));
+        let pad = Button::Controller(ControllerButton::new(1, 7));
+        map.event(&button_event(pad, ButtonState::Press));
+        map.apply(&mut emu);
+        assert_eq!(
+            emu.cpu.bus.joypad1.button_status,
+            JoypadButton::BUTTON_A | JoypadButton::BUTTON_B
+        );
+        assert_eq!(emu.cpu.bus.joypad2.button_status, JoypadButton::START);
+
+        // turbo B is off for the next turbo_period frames
+        emu.step_frame().unwrap();
+        emu.step_frame().unwrap();
+        map.apply(&mut emu);
+        assert_eq!(emu.cpu.bus.joypad1.button_status, JoypadButton::BUTTON_A);
+
+        map.event(&button_event(Button::Keyboard(Key::X), ButtonState::Release));
+        map.apply(&mut emu);
+        assert_eq!(emu.cpu.bus.joypad1.button_status, JoypadButton::empty());
+    }
+
+    #[test]
+    fn test_hotkeys_and_axes() {
+        let mut map = InputMap::parse(DEFAULT_CONFIG).unwrap();
+        let press = button_event(Button::Keyboard(Key::F5), ButtonState::Press);
+        assert_eq!(map.event(&press), vec![Hotkey::SaveState]);
+        // a repeated press while held does nothing
+        assert_eq!(map.event(&press), vec![]);
+
+        map.event(&button_event(Button::Keyboard(Key::Tab), ButtonState::Press));
+        assert!(map.is_held(Hotkey::FastForward));
+        assert!(map.event(&Event::Loop(Loop::Update(UpdateArgs { dt: 0.0 }))).is_empty());
+
+        let mut emu = Emulator::new();
+        let axis = |position| {
+            let args = ControllerAxisArgs::new(0, 0, position);
+            Event::Input(PistonInput::Move(piston_window::Motion::ControllerAxis(args)), None)
+        };
+        map.event(&axis(-0.9));
+        map.apply(&mut emu);
+        assert_eq!(emu.cpu.bus.joypad1.button_status, JoypadButton::LEFT);
+        map.event(&axis(0.1));
+        map.apply(&mut emu);
+        assert_eq!(emu.cpu.bus.joypad1.button_status, JoypadButton::empty());
+    }
+
+    #[test]
+    fn test_load_writes_defaults_and_reloads() {
+        let dir = std::env::temp_dir().join(format!("pabnes_input_{}", std::process::id()));
+        let path = dir.join("input.toml");
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut map = InputMap::load(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), DEFAULT_CONFIG);
+        assert_eq!(map.reload_if_changed(), Ok(false));
+
+        fs::write(&path, "[hotkeys]\npause = [\"Space\"]\n").unwrap();
+        map.modified = None;
+        assert_eq!(map.reload_if_changed(), Ok(true));
+        let press = button_event(Button::Keyboard(Key::Space), ButtonState::Press);
+        assert_eq!(map.event(&press), vec![Hotkey::Pause]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
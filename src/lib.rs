@@ -0,0 +1,33 @@
+#[macro_use]
+extern crate bitflags;
+
+pub mod apu;
+pub mod audio;
+pub mod bus;
+pub mod cartridge;
+pub mod cdl;
+pub mod clock;
+pub mod cpu;
+pub mod debugger;
+pub mod emulator;
+#[cfg(feature = "window")]
+pub mod frontend;
+#[cfg(feature = "window")]
+pub mod input;
+pub mod joypad;
+pub mod nsf;
+pub mod opcodes;
+pub mod ppu;
+pub mod ramsearch;
+pub mod recorder;
+pub mod region;
+pub mod render;
+pub mod savestate;
+#[cfg(feature = "lua")]
+pub mod script;
+pub mod testrom;
+
+pub use cpu::{AddressingMode, CpuError, Mem, StepInfo, CPU};
+pub use clock::Clock;
+pub use emulator::{EmuError, Emulator};
+pub use region::Region;
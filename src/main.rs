@@ -1,14 +1,21 @@
-mod cpu;
-mod opcodes;
+use std::path::{Path, PathBuf};
 
-#[macro_use]
-extern crate bitflags;
-
-use cpu::CPU;
-
-extern crate piston_window;
-
-use piston_window::*;
+use pabnes::audio::wav::WavSink;
+use pabnes::audio::{AudioOutput, AudioSink};
+use pabnes::cdl;
+use pabnes::cpu::{CpuVariant, CPU};
+use pabnes::debugger::Debugger;
+use pabnes::emulator::AUDIO_SAMPLE_RATE;
+use pabnes::frontend::{self, Hooks as _, VideoSettings};
+use pabnes::input::{InputMap, DEFAULT_CONFIG};
+use pabnes::nsf::Nsf;
+use pabnes::recorder::Recording;
+use pabnes::render::filter::Filter;
+use pabnes::render::palette::Palette;
+use pabnes::render::text::OverlayText;
+#[cfg(feature = "lua")]
+use pabnes::script::ScriptHost;
+use pabnes::{Clock, Emulator, Region};
 
 fn main() {
     let game_code = vec![
@@ -34,26 +41,365 @@ fn main() {
         0xea, 0xca, 0xd0, 0xfb, 0x60
     ];
 
-    //load the game
-    let mut cpu = CPU::new();
-    cpu.load(game_code);
-    cpu.reset();
+    let options = parse_args();
+
+    //load and run the game
+    match &options.rom_path {
+        Some(path) => {
+            let mut emulator = Emulator::new();
+            emulator.load_rom_file(path).unwrap_or_else(|err| panic!("Failed to load ROM: {}", err));
+            if let Some(region) = options.region {
+                emulator.set_region(region);
+            }
+            if let Some(spec) = &options.palette {
+                emulator.set_palette(Palette::from_spec(spec).unwrap_or_else(|err| panic!("Bad palette: {}", err)));
+            }
+            emulator.cpu.variant = options.cpu;
+            emulator.cpu.cycle_accurate = options.cycle_accurate;
+            if let Some(track) = options.track {
+                emulator.set_track(track);
+            }
+            if let (Some(nsf), Some(track)) = (emulator.nsf(), emulator.track()) {
+                print_nsf_info(nsf, track);
+            } else if let Some(cartridge) = &emulator.cpu.bus.cartridge {
+                let rom = &cartridge.rom;
+                println!(
+                    "mapper {}, {}K PRG-ROM, {}K CHR-ROM, {:?} mirroring, {}{}",
+                    rom.mapper,
+                    rom.prg_rom.len() / 1024,
+                    rom.chr_rom.len() / 1024,
+                    rom.screen_mirroring,
+                    emulator.region().name(),
+                    if rom.battery { ", battery" } else { "" }
+                );
+            }
+            if let Some(path) = &options.cdl {
+                start_code_data_log(&mut emulator, path);
+            }
+            let audio = open_audio(&options);
+            if let Some(path) = &options.record {
+                let recording = Recording { filter: options.video.filter.clone(), ..Recording::new(path) };
+                emulator.start_recording(recording).unwrap_or_else(|err| panic!("Failed to start recording: {}", err));
+            }
+            if options.headless {
+                run_headless(&mut emulator, &options, audio);
+                return;
+            }
+            let input = load_input_config(&options);
+            let rom_path = PathBuf::from(path);
+            let hooks = Hooks::new(&options);
+            let mut clock = Clock::new(emulator.region().frame_rate());
+            clock.set_speed(options.speed).unwrap_or_else(|err| panic!("{}", err));
+            frontend::run(&mut emulator, &mut clock, options.video, input, Some(&rom_path), audio, hooks);
+            finish(&mut emulator, options.cdl.as_deref());
+        }
+        None if options.debug || options.script.is_some() => {
+            let mut cpu = CPU::new();
+            cpu.variant = options.cpu;
+            cpu.cycle_accurate = options.cycle_accurate;
+            cpu.load(game_code);
+            cpu.reset();
+            run(&mut cpu, &options);
+        }
+        None => {
+            let mut cpu = CPU::new();
+            cpu.variant = options.cpu;
+            cpu.cycle_accurate = options.cycle_accurate;
+            if let Err(err) = cpu.load_and_run(game_code) {
+                eprintln!("CPU halted: {}", err);
+            }
+        }
+    }
+}
+
+struct Options {
+    rom_path: Option<String>,
+    debug: bool,
+    script: Option<String>,
+    video: VideoSettings,
+    input_config: Option<PathBuf>,
+    speed: f64,
+    region: Option<Region>,
+    palette: Option<String>,
+    headless: bool,
+    frames: u64,
+    screenshot: Option<PathBuf>,
+    audio_out: Option<PathBuf>,
+    record: Option<PathBuf>,
+    cdl: Option<PathBuf>,
+    track: Option<u8>,
+    cpu: CpuVariant,
+    cycle_accurate: bool,
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        rom_path: None,
+        debug: false,
+        script: None,
+        video: VideoSettings::new(),
+        input_config: None,
+        speed: 1.0,
+        region: None,
+        palette: None,
+        headless: false,
+        frames: 60,
+        screenshot: None,
+        audio_out: None,
+        record: None,
+        cdl: None,
+        track: None,
+        cpu: CpuVariant::default(),
+        cycle_accurate: false,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--debug" => options.debug = true,
+            "--script" => options.script = Some(args.next().expect("--script needs a file")),
+            "--scale" => {
+                let scale = args.next().expect("--scale needs a number");
+                options.video.scale = scale.parse().unwrap_or_else(|_| panic!("Invalid scale '{}'", scale));
+            }
+            "--speed" => {
+                let speed = args.next().expect("--speed needs a number");
+                options.speed = speed.parse().unwrap_or_else(|_| panic!("Invalid speed '{}'", speed));
+            }
+            "--region" => {
+                let region = args.next().expect("--region needs ntsc, pal or dendy");
+                options.region = Some(Region::parse(&region).unwrap_or_else(|err| panic!("{}", err)));
+            }
+            "--cpu" => {
+                let variant = args.next().expect("--cpu needs 2a03, 6502 or 65c02");
+                options.cpu = CpuVariant::parse(&variant).unwrap_or_else(|err| panic!("{}", err));
+            }
+            "--cycle-accurate" => options.cycle_accurate = true,
+            "--palette" => options.palette = Some(args.next().expect("--palette needs a name or file")),
+            "--filter" => {
+                let filter = args.next().expect("--filter needs a name");
+                options.video.filter = Filter::parse(&filter).unwrap_or_else(|err| panic!("{}", err));
+            }
+            "--headless" => options.headless = true,
+            "--frames" => {
+                let frames = args.next().expect("--frames needs a number");
+                options.frames = frames.parse().unwrap_or_else(|_| panic!("Invalid frame count '{}'", frames));
+            }
+            "--screenshot" => options.screenshot = Some(PathBuf::from(args.next().expect("--screenshot needs a file"))),
+            "--audio-out" => options.audio_out = Some(PathBuf::from(args.next().expect("--audio-out needs a file"))),
+            "--track" => {
+                let track = args.next().expect("--track needs a number");
+                options.track = Some(track.parse().unwrap_or_else(|_| panic!("Invalid track '{}'", track)));
+            }
+            "--record" => options.record = Some(PathBuf::from(args.next().expect("--record needs a file or directory"))),
+            "--cdl" => options.cdl = Some(PathBuf::from(args.next().expect("--cdl needs a file"))),
+            "--no-aspect" => options.video.aspect_correction = false,
+            "--crop-overscan" => options.video.crop_overscan = true,
+            "--fullscreen" => options.video.fullscreen = true,
+            "--input-config" => {
+                options.input_config = Some(PathBuf::from(args.next().expect("--input-config needs a file")))
+            }
+            _ => options.rom_path = Some(arg),
+        }
+    }
+    options
+}
+
+fn load_input_config(options: &Options) -> InputMap {
+    let path = options.input_config.clone().or_else(InputMap::default_path);
+    let loaded = match &path {
+        Some(path) => InputMap::load(path),
+        None => InputMap::parse(DEFAULT_CONFIG),
+    };
+    loaded.unwrap_or_else(|err| {
+        eprintln!("Bad input config, using the defaults: {}", err);
+        InputMap::parse(DEFAULT_CONFIG).expect("default input config is valid")
+    })
+}
 
-    //run the game
-    cpu.run_with_callback(move |cpu| {
-        
+// Debugger and script, called before every instruction
+struct Hooks {
+    debugger: Option<Debugger>,
+    script: Option<Script>,
+}
+
+impl Hooks {
+    fn new(options: &Options) -> Hooks {
+        Hooks {
+            debugger: if options.debug { Some(Debugger::new()) } else { None },
+            script: options.script.as_ref().map(|path| load_script(path)),
+        }
+    }
+}
+
+impl frontend::Hooks for Hooks {
+    fn instruction(&mut self, cpu: &mut CPU) {
+        if let Some(debugger) = &mut self.debugger {
+            debugger.hook(cpu);
+        }
+        run_script(&mut self.script, cpu);
+    }
+
+    fn overlay(&self) -> Vec<OverlayText> {
+        script_overlay(&self.script)
+    }
+}
+
+fn run(cpu: &mut CPU, options: &Options) {
+    let mut hooks = Hooks::new(options);
+    let result = cpu.run_with_callback(move |cpu| {
+        hooks.instruction(cpu);
+        if let Err(err) = cpu.bus.update_save() {
+            eprintln!("Failed to write save file: {}", err);
+        }
     });
+    if let Err(err) = result {
+        eprintln!("CPU halted: {}", err);
+    }
+    cpu.bus.flush_save().expect("Failed to write save file");
+}
+
+fn print_nsf_info(nsf: &Nsf, track: u8) {
+    for line in [&nsf.title, &nsf.artist, &nsf.copyright] {
+        if !line.is_empty() {
+            println!("{}", line);
+        }
+    }
+    println!("Track {} of {}, {}", track, nsf.songs, nsf.region.name());
+    let expansion = nsf.expansion.names();
+    if !expansion.is_empty() {
+        println!("Expansion audio not emulated: {}", expansion.join(", "));
+    }
+}
+
+// A WAV file when asked for one, otherwise the sound card if pabnes was
+// built with it
+fn open_audio(options: &Options) -> Option<AudioOutput> {
+    let sink: Result<Box<dyn AudioSink>, String> = match &options.audio_out {
+        Some(path) => WavSink::create(path, AUDIO_SAMPLE_RATE).map(|sink| Box::new(sink) as Box<dyn AudioSink>),
+        None if options.headless => return None,
+        None => open_device(),
+    };
+    match sink {
+        Ok(sink) => Some(AudioOutput::new(sink, AUDIO_SAMPLE_RATE)),
+        Err(err) => {
+            eprintln!("No sound: {}", err);
+            None
+        }
+    }
+}
+
+#[cfg(feature = "cpal")]
+fn open_device() -> Result<Box<dyn AudioSink>, String> {
+    pabnes::audio::device::DeviceSink::new().map(|sink| Box::new(sink) as Box<dyn AudioSink>)
+}
+
+#[cfg(not(feature = "cpal"))]
+fn open_device() -> Result<Box<dyn AudioSink>, String> {
+    Err("pabnes was built without the 'cpal' feature".to_string())
+}
+
+// Runs a fixed number of frames as fast as possible, without a window
+fn run_headless(emulator: &mut Emulator, options: &Options, mut audio: Option<AudioOutput>) {
+    let mut hooks = Hooks::new(options);
+    for _ in 0..options.frames {
+        match emulator.step_frame_with_callback(|cpu| hooks.instruction(cpu)) {
+            Ok(true) => {}
+            Ok(false) => {
+                println!("CPU halted");
+                break;
+            }
+            Err(err) => {
+                eprintln!("CPU halted: {}", err);
+                break;
+            }
+        }
+        emulator.set_overlay(hooks.overlay());
+        // taken even without an output, the emulator keeps them until then
+        let samples = emulator.take_audio_samples();
+        if let Some(audio) = &mut audio {
+            audio.push(&samples).unwrap_or_else(|err| panic!("Audio output failed: {}", err));
+        }
+    }
+    if let Some(path) = &options.screenshot {
+        emulator
+            .filtered_frame(&options.video.filter)
+            .save_png(path)
+            .unwrap_or_else(|err| panic!("Failed to save screenshot: {}", err));
+    }
+    finish(emulator, options.cdl.as_deref());
+}
+
+// Continues the log in `path` if there is one
+fn start_code_data_log(emulator: &mut Emulator, path: &Path) {
+    let saved = match std::fs::read(path) {
+        Ok(data) => Some(data),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => panic!("Failed to read code/data log: {}", err),
+    };
+    emulator
+        .start_code_data_log(saved.as_deref())
+        .unwrap_or_else(|err| panic!("Failed to start code/data log: {}", err));
+}
+
+// Writes out what is still pending when the emulator stops
+fn finish(emulator: &mut Emulator, cdl_path: Option<&Path>) {
+    if emulator.is_recording() {
+        match emulator.stop_recording() {
+            Ok(frames) => println!("Recorded {} frames", frames),
+            Err(err) => eprintln!("Recording failed: {}", err),
+        }
+    }
+    if let (Some(path), Some(log)) = (cdl_path, emulator.code_data_log()) {
+        match log.save(path) {
+            Ok(()) => println!(
+                "Code/data log: {} of {} PRG-ROM bytes code, {} data",
+                log.prg_count(cdl::PRG_CODE),
+                log.prg.len(),
+                log.prg_count(cdl::PRG_DATA)
+            ),
+            Err(err) => eprintln!("Failed to write code/data log: {}", err),
+        }
+    }
+    emulator.cpu.bus.flush_save().expect("Failed to write save file");
+}
+
+#[cfg(feature = "lua")]
+type Script = ScriptHost;
+
+#[cfg(feature = "lua")]
+fn load_script(path: &str) -> Script {
+    ScriptHost::from_file(path).unwrap_or_else(|err| panic!("Failed to load script: {}", err))
+}
+
+#[cfg(feature = "lua")]
+fn run_script(script: &mut Option<Script>, cpu: &mut CPU) {
+    if let Some(host) = script {
+        if let Err(err) = host.hook(cpu) {
+            // a broken script stops, the game keeps running
+            eprintln!("Script error: {}", err);
+            *script = None;
+        }
+    }
+}
+
+#[cfg(feature = "lua")]
+fn script_overlay(script: &Option<Script>) -> Vec<OverlayText> {
+    script.as_ref().map_or_else(Vec::new, |host| host.overlay())
+}
+
+#[cfg(not(feature = "lua"))]
+type Script = ();
+
+#[cfg(not(feature = "lua"))]
+fn load_script(_path: &str) -> Script {
+    panic!("pabnes was built without the 'lua' feature");
+}
+
+#[cfg(not(feature = "lua"))]
+fn run_script(_script: &mut Option<Script>, _cpu: &mut CPU) {}
 
-    let mut window: PistonWindow =
-        WindowSettings::new("Hello Piston!", [640, 480])
-        .exit_on_esc(true).build().unwrap();
-    while let Some(event) = window.next() {
-        window.draw_2d(&event, |context, graphics, _device| {
-            clear([1.0; 4], graphics);
-            rectangle([1.0, 0.0, 0.0, 1.0], // red
-                      [0.0, 0.0, 100.0, 100.0],
-                      context.transform,
-                      graphics);
-        });
-    }
-}
\ No newline at end of file
+#[cfg(not(feature = "lua"))]
+fn script_overlay(_script: &Option<Script>) -> Vec<OverlayText> {
+    vec![]
+}
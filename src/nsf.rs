@@ -0,0 +1,334 @@
+use crate::cartridge::Cartridge;
+use crate::region::Region;
+
+const NSF_TAG: &[u8; 5] = b"NESM\x1A";
+const NSFE_TAG: &[u8; 4] = b"NSFE";
+const HEADER_SIZE: usize = 0x80;
+const BANK_SIZE: usize = 0x1000;
+// Play rates used when a file leaves them out, in microseconds
+const NTSC_PLAY_RATE: u16 = 16_639;
+const PAL_PLAY_RATE: u16 = 19_997;
+
+bitflags! {
+    // Extra sound chips the rip was made for
+    pub struct ExpansionAudio: u8 {
+        const VRC6 = 0b0000_0001;
+        const VRC7 = 0b0000_0010;
+        const FDS = 0b0000_0100;
+        const MMC5 = 0b0000_1000;
+        const N163 = 0b0001_0000;
+        const SUNSOFT_5B = 0b0010_0000;
+    }
+}
+
+impl ExpansionAudio {
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            (ExpansionAudio::VRC6, "VRC6"),
+            (ExpansionAudio::VRC7, "VRC7"),
+            (ExpansionAudio::FDS, "FDS"),
+            (ExpansionAudio::MMC5, "MMC5"),
+            (ExpansionAudio::N163, "Namco 163"),
+            (ExpansionAudio::SUNSOFT_5B, "Sunsoft 5B"),
+        ]
+        .iter()
+        .filter(|(flag, _)| self.contains(*flag))
+        .map(|(_, name)| *name)
+        .collect()
+    }
+}
+
+// A music rip in NSF or NSFe format: the game's sound driver and data, plus
+// where to call it to start a song (INIT) and to advance it (PLAY).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Nsf {
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub songs: u8,
+    // 1-based
+    pub start_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    // initial 4K banks for $8000-$FFFF, None for rips that do not bankswitch
+    pub banks: Option<[u8; 8]>,
+    // microseconds between PLAY calls
+    pub ntsc_rate: u16,
+    pub pal_rate: u16,
+    pub region: Region,
+    pub expansion: ExpansionAudio,
+    // NSFe only, may be shorter than `songs`
+    pub track_titles: Vec<String>,
+    pub data: Vec<u8>,
+}
+
+impl Nsf {
+    pub fn is_nsf(raw: &[u8]) -> bool {
+        raw.starts_with(NSF_TAG) || raw.starts_with(NSFE_TAG)
+    }
+
+    pub fn new(raw: &[u8]) -> Result<Nsf, String> {
+        if raw.starts_with(NSFE_TAG) {
+            Nsf::parse_nsfe(raw)
+        } else if raw.starts_with(NSF_TAG) {
+            Nsf::parse_nsf(raw)
+        } else {
+            Err("File is not in NSF format".to_string())
+        }
+    }
+
+    fn parse_nsf(raw: &[u8]) -> Result<Nsf, String> {
+        if raw.len() <= HEADER_SIZE {
+            return Err("NSF file is truncated".to_string());
+        }
+        let u16_at = |pos: usize| u16::from_le_bytes([raw[pos], raw[pos + 1]]);
+        let banks = read_banks(&raw[0x70..0x78]);
+        // NSF2 stores the data length so metadata can follow it
+        let data_len = u32::from_le_bytes([raw[0x7D], raw[0x7E], raw[0x7F], 0]) as usize;
+        let data_end = if raw[5] >= 2 && data_len > 0 {
+            (HEADER_SIZE + data_len).min(raw.len())
+        } else {
+            raw.len()
+        };
+        let nsf = Nsf {
+            title: read_string(&raw[0x0E..0x2E]),
+            artist: read_string(&raw[0x2E..0x4E]),
+            copyright: read_string(&raw[0x4E..0x6E]),
+            songs: raw[6],
+            start_song: raw[7],
+            load_addr: u16_at(0x08),
+            init_addr: u16_at(0x0A),
+            play_addr: u16_at(0x0C),
+            banks,
+            ntsc_rate: u16_at(0x6E),
+            pal_rate: u16_at(0x78),
+            region: region_from_flags(raw[0x7A]),
+            expansion: ExpansionAudio::from_bits_truncate(raw[0x7B]),
+            track_titles: vec![],
+            data: raw[HEADER_SIZE..data_end].to_vec(),
+        };
+        nsf.validate()
+    }
+
+    // A list of chunks: 4-byte length, 4-byte id, data. Chunks whose id starts
+    // with an uppercase letter must be understood, the rest may be skipped.
+    fn parse_nsfe(raw: &[u8]) -> Result<Nsf, String> {
+        let mut nsf = Nsf {
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            songs: 1,
+            start_song: 1,
+            load_addr: 0,
+            init_addr: 0,
+            play_addr: 0,
+            banks: None,
+            ntsc_rate: NTSC_PLAY_RATE,
+            pal_rate: PAL_PLAY_RATE,
+            region: Region::Ntsc,
+            expansion: ExpansionAudio::empty(),
+            track_titles: vec![],
+            data: vec![],
+        };
+        let mut has_info = false;
+        let mut pos = NSFE_TAG.len();
+        loop {
+            if raw.len() < pos + 8 {
+                return Err("NSFe file is truncated".to_string());
+            }
+            let len = u32::from_le_bytes([raw[pos], raw[pos + 1], raw[pos + 2], raw[pos + 3]]) as usize;
+            let id = &raw[pos + 4..pos + 8];
+            let chunk = raw
+                .get(pos + 8..(pos + 8).saturating_add(len))
+                .ok_or_else(|| "NSFe file is truncated".to_string())?;
+            pos += 8 + len;
+            match id {
+                b"INFO" => {
+                    if chunk.len() < 8 {
+                        return Err("NSFe INFO chunk is too short".to_string());
+                    }
+                    nsf.load_addr = u16::from_le_bytes([chunk[0], chunk[1]]);
+                    nsf.init_addr = u16::from_le_bytes([chunk[2], chunk[3]]);
+                    nsf.play_addr = u16::from_le_bytes([chunk[4], chunk[5]]);
+                    nsf.region = region_from_flags(chunk[6]);
+                    nsf.expansion = ExpansionAudio::from_bits_truncate(chunk[7]);
+                    nsf.songs = chunk.get(8).copied().unwrap_or(1);
+                    // 0-based in NSFe
+                    nsf.start_song = chunk.get(9).copied().unwrap_or(0) + 1;
+                    has_info = true;
+                }
+                b"DATA" => nsf.data = chunk.to_vec(),
+                b"BANK" => {
+                    let mut banks = [0; 8];
+                    banks[..chunk.len().min(8)].copy_from_slice(&chunk[..chunk.len().min(8)]);
+                    nsf.banks = read_banks(&banks);
+                }
+                b"RATE" => {
+                    let rate = |i: usize| chunk.get(i..i + 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+                    nsf.ntsc_rate = rate(0).unwrap_or(NTSC_PLAY_RATE);
+                    nsf.pal_rate = rate(2).unwrap_or(PAL_PLAY_RATE);
+                }
+                b"auth" => {
+                    let mut fields = chunk.split(|&byte| byte == 0).map(read_string);
+                    nsf.title = fields.next().unwrap_or_default();
+                    nsf.artist = fields.next().unwrap_or_default();
+                    nsf.copyright = fields.next().unwrap_or_default();
+                }
+                b"tlbl" => {
+                    let titles = chunk.strip_suffix(&[0]).unwrap_or(chunk);
+                    nsf.track_titles = titles.split(|&byte| byte == 0).map(read_string).collect();
+                }
+                b"NEND" => break,
+                _ if id[0].is_ascii_uppercase() => {
+                    return Err(format!("NSFe chunk {} is not supported", String::from_utf8_lossy(id)));
+                }
+                _ => {}
+            }
+        }
+        if !has_info {
+            return Err("NSFe file has no INFO chunk".to_string());
+        }
+        nsf.validate()
+    }
+
+    fn validate(self) -> Result<Nsf, String> {
+        if self.data.is_empty() {
+            return Err("NSF file has no data".to_string());
+        }
+        if self.songs == 0 {
+            return Err("NSF file has no songs".to_string());
+        }
+        if self.banks.is_none() && self.load_addr < 0x8000 {
+            return Err(format!("NSF load address ${:04X} is below $8000", self.load_addr));
+        }
+        Ok(self)
+    }
+
+    // Microseconds between PLAY calls for `region`
+    pub fn play_rate(&self, region: Region) -> u16 {
+        match region {
+            Region::Ntsc if self.ntsc_rate > 0 => self.ntsc_rate,
+            Region::Ntsc => NTSC_PLAY_RATE,
+            _ if self.pal_rate > 0 => self.pal_rate,
+            _ => PAL_PLAY_RATE,
+        }
+    }
+
+    // NSFe track title, if the file has one
+    pub fn track_title(&self, track: u8) -> Option<&str> {
+        let title = self.track_titles.get(track.checked_sub(1)? as usize)?;
+        if title.is_empty() {
+            None
+        } else {
+            Some(title)
+        }
+    }
+
+    // The data laid out in 4K banks. Rips that do not bankswitch get their
+    // data placed at the load address with the banks fixed to 0-7.
+    pub fn cartridge(&self) -> Cartridge {
+        let (padding, banks) = match self.banks {
+            Some(banks) => (self.load_addr as usize % BANK_SIZE, banks),
+            None => ((self.load_addr - 0x8000) as usize, [0, 1, 2, 3, 4, 5, 6, 7]),
+        };
+        let mut image = vec![0; padding];
+        image.extend_from_slice(&self.data);
+        image.resize(image.len().div_ceil(BANK_SIZE) * BANK_SIZE, 0);
+        Cartridge::nsf(image, banks, self.region)
+    }
+}
+
+fn read_banks(bytes: &[u8]) -> Option<[u8; 8]> {
+    let mut banks = [0; 8];
+    banks.copy_from_slice(bytes);
+    if banks.iter().any(|&bank| bank != 0) {
+        Some(banks)
+    } else {
+        None
+    }
+}
+
+fn read_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+// Bit 0 is PAL, bit 1 says the rip works on both, which is played as NTSC
+fn region_from_flags(flags: u8) -> Region {
+    if flags & 0b11 == 0b01 {
+        Region::Pal
+    } else {
+        Region::Ntsc
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // INIT stores A+1 at $00 and sets up pulse 1, PLAY counts calls at $01
+    pub fn test_nsf() -> Vec<u8> {
+        let mut raw = NSF_TAG.to_vec();
+        raw.extend_from_slice(&[1, 3, 2, 0x00, 0x80, 0x00, 0x80, 0x20, 0x80]);
+        raw.resize(HEADER_SIZE, 0);
+        raw[0x0E..0x13].copy_from_slice(b"Title");
+        raw[0x6E..0x70].copy_from_slice(&NTSC_PLAY_RATE.to_le_bytes());
+        raw[0x7B] = ExpansionAudio::VRC6.bits();
+        let mut code = vec![0; 0x30];
+        // $8000: TAX; INX; STX $00; LDA #$01; STA $4015; LDA #$BF; STA $4000;
+        //        LDA #$FD; STA $4002; LDA #$08; STA $4003; RTS
+        code[..27].copy_from_slice(&[
+            0xAA, 0xE8, 0x86, 0x00, 0xA9, 0x01, 0x8D, 0x15, 0x40, 0xA9, 0xBF, 0x8D, 0x00, 0x40, 0xA9, 0xFD, 0x8D,
+            0x02, 0x40, 0xA9, 0x08, 0x8D, 0x03, 0x40, 0x60, 0, 0,
+        ]);
+        // $8020: INC $01; RTS
+        code[0x20..0x23].copy_from_slice(&[0xE6, 0x01, 0x60]);
+        raw.extend(code);
+        raw
+    }
+
+    #[test]
+    fn test_parse_nsf() {
+        let nsf = Nsf::new(&test_nsf()).unwrap();
+        assert_eq!(nsf.title, "Title");
+        assert_eq!((nsf.songs, nsf.start_song), (3, 2));
+        assert_eq!((nsf.init_addr, nsf.play_addr), (0x8000, 0x8020));
+        assert_eq!(nsf.banks, None);
+        assert_eq!(nsf.expansion.names(), vec!["VRC6"]);
+        assert_eq!(nsf.cartridge().read_prg_rom(0x8020), 0xE6);
+    }
+
+    #[test]
+    fn test_parse_nsfe() {
+        let chunk = |id: &[u8], data: &[u8]| {
+            let mut chunk = (data.len() as u32).to_le_bytes().to_vec();
+            chunk.extend_from_slice(id);
+            chunk.extend_from_slice(data);
+            chunk
+        };
+        let mut raw = NSFE_TAG.to_vec();
+        raw.extend(chunk(b"INFO", &[0x00, 0x90, 0x00, 0x90, 0x03, 0x90, 0x01, 0x00, 4, 1]));
+        raw.extend(chunk(b"BANK", &[0, 1]));
+        raw.extend(chunk(b"DATA", &[0xEA; 0x1800]));
+        raw.extend(chunk(b"auth", b"Song\0Someone\0\0"));
+        raw.extend(chunk(b"tlbl", b"Intro\0\0Boss\0"));
+        raw.extend(chunk(b"NEND", &[]));
+        let nsf = Nsf::new(&raw).unwrap();
+        assert_eq!((nsf.songs, nsf.start_song, nsf.region), (4, 2, Region::Pal));
+        assert_eq!(nsf.artist, "Someone");
+        assert_eq!(nsf.track_title(1), Some("Intro"));
+        assert_eq!(nsf.track_title(2), None);
+        assert_eq!(nsf.track_title(3), Some("Boss"));
+
+        // banks past the end of the data read as zeros
+        let mut cartridge = nsf.cartridge();
+        cartridge.write_bank(0x5FF9, 5);
+        assert_eq!(cartridge.read_prg_rom(0x9000), 0x00);
+        cartridge.write_bank(0x5FF9, 1);
+        assert_eq!(cartridge.read_prg_rom(0x9000), 0xEA);
+
+        raw[4 + 8 + 10 + 4] = b'X';
+        assert!(Nsf::new(&raw).is_err());
+    }
+}
@@ -13,11 +13,11 @@ pub struct OpCode {
 impl OpCode {
     fn new(code: u8, mnemonic: &'static str, len: u8, cycles: u8, mode: AddressingMode) -> OpCode {
         OpCode {
-            code: code,
-            mnemonic: mnemonic,
-            len: len,
-            cycles: cycles,
-            mode: mode,
+            code,
+            mnemonic,
+            len,
+            cycles,
+            mode,
         }
     }
 }
@@ -228,4 +228,67 @@ lazy_static! {
 
         map
     };
+
+    // Added or changed by the 65C02
+    pub static ref CMOS_OPS_CODES: Vec<OpCode> = {
+        let mut ops = vec![
+            OpCode::new(0x80, "BRA", 2, 3 /*(+1 if page crossed)*/, AddressingMode::NoneAddressing),
+            OpCode::new(0x6C, "JMP", 3, 6, AddressingMode::NoneAddressing), //AddressingMode:Indirect, without the page bug
+            OpCode::new(0x7C, "JMP", 3, 6, AddressingMode::NoneAddressing), //AddressingMode:Indirect,X
+
+            OpCode::new(0xDA, "PHX", 1, 3, AddressingMode::NoneAddressing),
+            OpCode::new(0xFA, "PLX", 1, 4, AddressingMode::NoneAddressing),
+            OpCode::new(0x5A, "PHY", 1, 3, AddressingMode::NoneAddressing),
+            OpCode::new(0x7A, "PLY", 1, 4, AddressingMode::NoneAddressing),
+
+            OpCode::new(0x1A, "INC", 1, 2, AddressingMode::NoneAddressing),
+            OpCode::new(0x3A, "DEC", 1, 2, AddressingMode::NoneAddressing),
+
+            OpCode::new(0x64, "STZ", 2, 3, AddressingMode::ZeroPage),
+            OpCode::new(0x74, "STZ", 2, 4, AddressingMode::ZeroPageX),
+            OpCode::new(0x9C, "STZ", 3, 4, AddressingMode::Absolute),
+            OpCode::new(0x9E, "STZ", 3, 5, AddressingMode::AbsoluteX),
+
+            OpCode::new(0x04, "TSB", 2, 5, AddressingMode::ZeroPage),
+            OpCode::new(0x0C, "TSB", 3, 6, AddressingMode::Absolute),
+            OpCode::new(0x14, "TRB", 2, 5, AddressingMode::ZeroPage),
+            OpCode::new(0x1C, "TRB", 3, 6, AddressingMode::Absolute),
+
+            OpCode::new(0x89, "BIT", 2, 2, AddressingMode::Immediate),
+            OpCode::new(0x34, "BIT", 2, 4, AddressingMode::ZeroPageX),
+            OpCode::new(0x3C, "BIT", 3, 4/*+1 if page crossed*/, AddressingMode::AbsoluteX),
+
+            OpCode::new(0x12, "ORA", 2, 5, AddressingMode::ZeroPageIndirect),
+            OpCode::new(0x32, "AND", 2, 5, AddressingMode::ZeroPageIndirect),
+            OpCode::new(0x52, "EOR", 2, 5, AddressingMode::ZeroPageIndirect),
+            OpCode::new(0x72, "ADC", 2, 5, AddressingMode::ZeroPageIndirect),
+            OpCode::new(0x92, "STA", 2, 5, AddressingMode::ZeroPageIndirect),
+            OpCode::new(0xB2, "LDA", 2, 5, AddressingMode::ZeroPageIndirect),
+            OpCode::new(0xD2, "CMP", 2, 5, AddressingMode::ZeroPageIndirect),
+            OpCode::new(0xF2, "SBC", 2, 5, AddressingMode::ZeroPageIndirect),
+        ];
+
+        /* Bit ops, one opcode per bit */
+        const RMB: [&str; 8] = ["RMB0", "RMB1", "RMB2", "RMB3", "RMB4", "RMB5", "RMB6", "RMB7"];
+        const SMB: [&str; 8] = ["SMB0", "SMB1", "SMB2", "SMB3", "SMB4", "SMB5", "SMB6", "SMB7"];
+        const BBR: [&str; 8] = ["BBR0", "BBR1", "BBR2", "BBR3", "BBR4", "BBR5", "BBR6", "BBR7"];
+        const BBS: [&str; 8] = ["BBS0", "BBS1", "BBS2", "BBS3", "BBS4", "BBS5", "BBS6", "BBS7"];
+        for bit in 0..8 {
+            ops.push(OpCode::new(0x07 + 0x10 * bit as u8, RMB[bit], 2, 5, AddressingMode::ZeroPage));
+            ops.push(OpCode::new(0x87 + 0x10 * bit as u8, SMB[bit], 2, 5, AddressingMode::ZeroPage));
+            ops.push(OpCode::new(0x0F + 0x10 * bit as u8, BBR[bit], 3, 5 /*(+1 if branch succeeds)*/, AddressingMode::ZeroPage));
+            ops.push(OpCode::new(0x8F + 0x10 * bit as u8, BBS[bit], 3, 5 /*(+1 if branch succeeds)*/, AddressingMode::ZeroPage));
+        }
+        ops
+    };
+
+    pub static ref CMOS_OPCODES_MAP: HashMap<u8, &'static OpCode> = {
+        let mut map = OPCODES_MAP.clone();
+
+        for op_code in &*CMOS_OPS_CODES {
+            map.insert(op_code.code, op_code);
+        }
+
+        map
+    };
 }
\ No newline at end of file
@@ -0,0 +1,667 @@
+pub mod registers;
+
+use crate::cartridge::Mirroring;
+use crate::cdl;
+use crate::region::Region;
+use crate::render::frame::Frame;
+use crate::savestate::{StateReader, StateWriter};
+use registers::{ControlRegister, MaskRegister, StatusRegister};
+
+const DOTS_PER_SCANLINE: usize = 341;
+const VISIBLE_SCANLINES: u16 = 240;
+const CHR_RAM_SIZE: usize = 0x2000;
+
+// Renders a whole scanline as soon as the PPU enters it, using the registers
+// as they are at that point. Mid-frame scroll splits work as long as the game
+// changes them during hblank, which is what games do anyway.
+pub struct NesPPU {
+    pub chr_rom: Vec<u8>,
+    chr_ram: bool,
+    // the CHR-ROM half of the code/data log, see Bus::set_code_data_log
+    pub chr_log: Option<Vec<u8>>,
+    pub mirroring: Mirroring,
+    pub palette_table: [u8; 32],
+    // 4K so four-screen cartridges get their extra nametables
+    pub vram: [u8; 0x1000],
+    pub oam_addr: u8,
+    pub oam_data: [u8; 256],
+
+    pub ctrl: ControlRegister,
+    pub mask: MaskRegister,
+    pub status: StatusRegister,
+
+    // Loopy registers: current/temporary VRAM address, fine x scroll, write toggle
+    v: u16,
+    t: u16,
+    fine_x: u8,
+    w: bool,
+    internal_data_buf: u8,
+
+    region: Region,
+    scanline: u16,
+    cycle: usize,
+    frame_count: u64,
+    nmi_interrupt: bool,
+    sprite_zero_hit_at: Option<usize>,
+
+    // 256x240 palette indices, with the emphasis bits from PPUMASK above bit 6
+    pub screen: Vec<u16>,
+}
+
+impl NesPPU {
+    pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        // no CHR-ROM on the cartridge means it has 8K of CHR-RAM instead
+        let chr_ram = chr_rom.is_empty();
+        NesPPU {
+            chr_rom: if chr_ram { vec![0; CHR_RAM_SIZE] } else { chr_rom },
+            chr_ram,
+            chr_log: None,
+            mirroring,
+            palette_table: [0; 32],
+            vram: [0; 0x1000],
+            oam_addr: 0,
+            oam_data: [0; 256],
+            ctrl: ControlRegister::new(),
+            mask: MaskRegister::new(),
+            status: StatusRegister::new(),
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            w: false,
+            internal_data_buf: 0,
+            region: Region::Ntsc,
+            scanline: 0,
+            cycle: 0,
+            frame_count: 0,
+            nmi_interrupt: false,
+            sprite_zero_hit_at: None,
+            screen: vec![0; Frame::WIDTH * Frame::HEIGHT],
+        }
+    }
+
+    pub fn new_empty_rom() -> Self {
+        NesPPU::new(vec![], Mirroring::Horizontal)
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        if self.scanline >= region.scanlines_per_frame() {
+            self.scanline = 0;
+        }
+    }
+
+    fn pre_render_scanline(&self) -> u16 {
+        self.region.scanlines_per_frame() - 1
+    }
+
+    // Advances by `dots` PPU cycles, returns true if a frame finished on the way
+    pub fn tick(&mut self, dots: usize) -> bool {
+        let mut frame_done = false;
+        self.cycle += dots;
+        loop {
+            if let Some(x) = self.sprite_zero_hit_at {
+                if self.cycle > x {
+                    self.status.insert(StatusRegister::SPRITE_ZERO_HIT);
+                    self.sprite_zero_hit_at = None;
+                }
+            }
+            if self.cycle < DOTS_PER_SCANLINE {
+                return frame_done;
+            }
+            self.cycle -= DOTS_PER_SCANLINE;
+            frame_done |= self.next_scanline();
+        }
+    }
+
+    fn next_scanline(&mut self) -> bool {
+        if self.rendering_enabled() {
+            if self.scanline < VISIBLE_SCANLINES {
+                self.increment_y();
+                self.copy_horizontal();
+            } else if self.scanline == self.pre_render_scanline() {
+                self.copy_horizontal();
+                self.copy_vertical();
+            }
+        }
+
+        self.scanline += 1;
+        if self.scanline == self.region.vblank_scanline() {
+            self.status.insert(StatusRegister::VBLANK_STARTED);
+            self.frame_count += 1;
+            if self.ctrl.generate_vblank_nmi() {
+                self.nmi_interrupt = true;
+            }
+            return true;
+        } else if self.scanline == self.pre_render_scanline() {
+            self.status.remove(StatusRegister::VBLANK_STARTED);
+            self.status.remove(StatusRegister::SPRITE_ZERO_HIT);
+            self.status.remove(StatusRegister::SPRITE_OVERFLOW);
+        } else if self.scanline == self.region.scanlines_per_frame() {
+            self.scanline = 0;
+        }
+        if self.scanline < VISIBLE_SCANLINES {
+            self.render_scanline();
+        }
+        false
+    }
+
+    pub fn poll_nmi_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.nmi_interrupt)
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.mask.show_background() || self.mask.show_sprites()
+    }
+
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    fn copy_horizontal(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+
+    fn copy_vertical(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+
+    fn render_scanline(&mut self) {
+        let y = self.scanline as usize;
+        let background = self.background_line();
+        let sprites = self.sprite_line(y);
+
+        let grayscale_mask = if self.mask.is_grayscale() { 0x30 } else { 0x3F };
+        let emphasis = (self.mask.emphasis() as u16) << 6;
+        for x in 0..Frame::WIDTH {
+            let bg = background[x];
+            let color = match sprites[x] {
+                Some(sprite) => {
+                    if sprite.zero && bg & 0b11 != 0 && x != 255 && self.sprite_zero_hit_at.is_none() {
+                        self.sprite_zero_hit_at = Some(x);
+                    }
+                    if bg & 0b11 == 0 || !sprite.behind_background {
+                        sprite.color
+                    } else {
+                        bg
+                    }
+                }
+                None => bg,
+            };
+            let index = self.palette_table[palette_mirror(color as u16)] & grayscale_mask;
+            self.screen[y * Frame::WIDTH + x] = index as u16 | emphasis;
+        }
+    }
+
+    // Palette RAM offsets (0-15) for one line of background
+    fn background_line(&mut self) -> [u8; Frame::WIDTH] {
+        let mut line = [0; Frame::WIDTH];
+        if !self.mask.show_background() {
+            return line;
+        }
+
+        let mut v = self.v;
+        let fine_y = (v >> 12) & 0b111;
+        for tile in 0..33 {
+            let tile_id = self.vram[self.mirror_vram_addr(0x2000 | (v & 0x0FFF))] as u16;
+            let attr_addr = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+            let attr = self.vram[self.mirror_vram_addr(attr_addr)];
+            let shift = ((v >> 4) & 0b100) | (v & 0b10);
+            let palette = (attr >> shift) & 0b11;
+
+            let pattern_addr = self.ctrl.bknd_pattern_addr() + tile_id * 16 + fine_y;
+            let (lo, hi) = self.pattern_row(pattern_addr);
+            for bit in 0..8 {
+                let x = tile * 8 + bit as isize - self.fine_x as isize;
+                if !(0..Frame::WIDTH as isize).contains(&x) {
+                    continue;
+                }
+                let pixel = ((lo >> (7 - bit)) & 1) | (((hi >> (7 - bit)) & 1) << 1);
+                if pixel != 0 {
+                    line[x as usize] = palette << 2 | pixel;
+                }
+            }
+
+            // coarse x, wrapping into the neighbouring nametable
+            if v & 0x001F == 31 {
+                v &= !0x001F;
+                v ^= 0x0400;
+            } else {
+                v += 1;
+            }
+        }
+
+        if !self.mask.leftmost_8pxl_background() {
+            line[..8].fill(0);
+        }
+        line
+    }
+
+    // The two bitplanes of a row of a tile, as fetched for rendering
+    fn pattern_row(&mut self, addr: u16) -> (u8, u8) {
+        let addr = addr as usize;
+        if let Some(log) = &mut self.chr_log {
+            for offset in [addr, addr + 8] {
+                if let Some(flags) = log.get_mut(offset) {
+                    *flags |= cdl::CHR_RENDERED;
+                }
+            }
+        }
+        (self.chr_rom[addr], self.chr_rom[addr + 8])
+    }
+
+    fn sprite_line(&mut self, y: usize) -> [Option<SpritePixel>; Frame::WIDTH] {
+        let mut line = [None; Frame::WIDTH];
+        if !self.mask.show_sprites() {
+            return line;
+        }
+
+        let height = self.ctrl.sprite_size();
+        let mut found = 0;
+        for i in 0..64 {
+            let sprite = &self.oam_data[i * 4..i * 4 + 4];
+            // sprites are drawn one line below their OAM y coordinate
+            let top = sprite[0] as usize + 1;
+            if y < top || y >= top + height {
+                continue;
+            }
+            found += 1;
+            if found > 8 {
+                self.status.insert(StatusRegister::SPRITE_OVERFLOW);
+                break;
+            }
+
+            let (tile, attr, left) = (sprite[1] as u16, sprite[2], sprite[3] as usize);
+            let flip_vertical = attr & 0b1000_0000 != 0;
+            let flip_horizontal = attr & 0b0100_0000 != 0;
+            let mut row = (y - top) as u16;
+            if flip_vertical {
+                row = height as u16 - 1 - row;
+            }
+            let pattern_addr = if height == 16 {
+                let bank = (tile & 1) * 0x1000;
+                let tile = (tile & 0xFE) + row / 8;
+                bank + tile * 16 + row % 8
+            } else {
+                self.ctrl.sprt_pattern_addr() + tile * 16 + row
+            };
+            let (lo, hi) = self.pattern_row(pattern_addr);
+
+            for col in 0..8 {
+                let x = left + col;
+                if x >= Frame::WIDTH {
+                    break;
+                }
+                let bit = if flip_horizontal { col } else { 7 - col };
+                let pixel = ((lo >> bit) & 1) | (((hi >> bit) & 1) << 1);
+                // lower OAM index wins, even if it ends up behind the background
+                if pixel == 0 || line[x].is_some() || (x < 8 && !self.mask.leftmost_8pxl_sprite()) {
+                    continue;
+                }
+                line[x] = Some(SpritePixel {
+                    color: 0x10 | (attr & 0b11) << 2 | pixel,
+                    behind_background: attr & 0b0010_0000 != 0,
+                    zero: i == 0,
+                });
+            }
+        }
+        line
+    }
+
+    // Horizontal:
+    //   [ A ] [ a ]
+    //   [ B ] [ b ]
+    // Vertical:
+    //   [ A ] [ B ]
+    //   [ a ] [ b ]
+    fn mirror_vram_addr(&self, addr: u16) -> usize {
+        let vram_index = (addr & 0x0FFF) as usize;
+        let name_table = vram_index / 0x400;
+        match (&self.mirroring, name_table) {
+            (Mirroring::Vertical, 2) | (Mirroring::Vertical, 3) => vram_index - 0x800,
+            (Mirroring::Horizontal, 1) | (Mirroring::Horizontal, 2) => vram_index - 0x400,
+            (Mirroring::Horizontal, 3) => vram_index - 0x800,
+            _ => vram_index,
+        }
+    }
+
+    fn increment_vram_addr(&mut self) {
+        self.v = self.v.wrapping_add(self.ctrl.vram_addr_increment()) & 0x3FFF;
+    }
+
+    pub fn write_to_ctrl(&mut self, value: u8) {
+        let before_nmi_status = self.ctrl.generate_vblank_nmi();
+        self.ctrl.update(value);
+        self.t = (self.t & !0x0C00) | ((value as u16 & 0b11) << 10);
+        if !before_nmi_status && self.ctrl.generate_vblank_nmi() && self.status.is_in_vblank() {
+            self.nmi_interrupt = true;
+        }
+    }
+
+    pub fn write_to_mask(&mut self, value: u8) {
+        self.mask.update(value);
+    }
+
+    pub fn read_status(&mut self) -> u8 {
+        let data = self.status.snapshot();
+        self.status.remove(StatusRegister::VBLANK_STARTED);
+        self.w = false;
+        data
+    }
+
+    // Status without acknowledging vblank or resetting the write latch
+    pub fn peek_status(&self) -> u8 {
+        self.status.snapshot()
+    }
+
+    pub fn write_to_oam_addr(&mut self, value: u8) {
+        self.oam_addr = value;
+    }
+
+    pub fn write_to_oam_data(&mut self, value: u8) {
+        self.oam_data[self.oam_addr as usize] = value;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    pub fn read_oam_data(&self) -> u8 {
+        self.oam_data[self.oam_addr as usize]
+    }
+
+    pub fn write_oam_dma(&mut self, data: &[u8; 256]) {
+        for x in data.iter() {
+            self.write_to_oam_data(*x);
+        }
+    }
+
+    pub fn write_to_scroll(&mut self, value: u8) {
+        if !self.w {
+            self.t = (self.t & !0x001F) | (value as u16 >> 3);
+            self.fine_x = value & 0b111;
+        } else {
+            self.t = (self.t & !0x73E0) | ((value as u16 & 0b111) << 12) | ((value as u16 >> 3) << 5);
+        }
+        self.w = !self.w;
+    }
+
+    pub fn write_to_ppu_addr(&mut self, value: u8) {
+        if !self.w {
+            self.t = (self.t & 0x00FF) | ((value as u16 & 0x3F) << 8);
+        } else {
+            self.t = (self.t & 0xFF00) | value as u16;
+            self.v = self.t;
+        }
+        self.w = !self.w;
+    }
+
+    pub fn write_to_data(&mut self, value: u8) {
+        let addr = self.v & 0x3FFF;
+        match addr {
+            0..=0x1FFF => {
+                if self.chr_ram {
+                    self.chr_rom[addr as usize] = value;
+                }
+            }
+            0x2000..=0x3EFF => self.vram[self.mirror_vram_addr(addr)] = value,
+            _ => self.palette_table[palette_mirror(addr)] = value,
+        }
+        self.increment_vram_addr();
+    }
+
+    pub fn read_data(&mut self) -> u8 {
+        let addr = self.v & 0x3FFF;
+        self.increment_vram_addr();
+
+        match addr {
+            0..=0x1FFF => {
+                let result = self.internal_data_buf;
+                self.internal_data_buf = self.chr_rom[addr as usize];
+                if let Some(flags) = self.chr_log.as_mut().and_then(|log| log.get_mut(addr as usize)) {
+                    *flags |= cdl::CHR_READ;
+                }
+                result
+            }
+            0x2000..=0x3EFF => {
+                let result = self.internal_data_buf;
+                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr)];
+                result
+            }
+            // palette reads skip the buffer, which gets the nametable byte underneath
+            _ => {
+                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr)];
+                self.palette_table[palette_mirror(addr)]
+            }
+        }
+    }
+
+    // What read_data would return, without moving on or refilling the buffer
+    pub fn peek_data(&self) -> u8 {
+        match self.v & 0x3FFF {
+            0..=0x3EFF => self.internal_data_buf,
+            addr => self.palette_table[palette_mirror(addr)],
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(if self.chr_ram { &self.chr_rom } else { &[] });
+        w.write_bytes(&self.palette_table);
+        w.write_bytes(&self.vram);
+        w.write_u8(self.oam_addr);
+        w.write_bytes(&self.oam_data);
+        w.write_u8(self.ctrl.bits());
+        w.write_u8(self.mask.bits());
+        w.write_u8(self.status.bits());
+        w.write_u16(self.v);
+        w.write_u16(self.t);
+        w.write_u8(self.fine_x);
+        w.write_bool(self.w);
+        w.write_u8(self.internal_data_buf);
+        w.write_u16(self.scanline);
+        w.write_u64(self.cycle as u64);
+        w.write_u64(self.frame_count);
+        w.write_bool(self.nmi_interrupt);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        if self.chr_ram {
+            r.read_bytes_into(&mut self.chr_rom)?;
+        } else {
+            r.read_bytes_into(&mut [])?;
+        }
+        r.read_bytes_into(&mut self.palette_table)?;
+        r.read_bytes_into(&mut self.vram)?;
+        self.oam_addr = r.read_u8()?;
+        r.read_bytes_into(&mut self.oam_data)?;
+        self.ctrl = ControlRegister::from_bits_truncate(r.read_u8()?);
+        self.mask = MaskRegister::from_bits_truncate(r.read_u8()?);
+        self.status = StatusRegister::from_bits_truncate(r.read_u8()?);
+        self.v = r.read_u16()?;
+        self.t = r.read_u16()?;
+        self.fine_x = r.read_u8()?;
+        self.w = r.read_bool()?;
+        self.internal_data_buf = r.read_u8()?;
+        self.scanline = r.read_u16()?;
+        self.cycle = r.read_u64()? as usize;
+        self.frame_count = r.read_u64()?;
+        self.nmi_interrupt = r.read_bool()?;
+        self.sprite_zero_hit_at = None;
+        Ok(())
+    }
+}
+
+impl Default for NesPPU {
+    fn default() -> Self {
+        NesPPU::new_empty_rom()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct SpritePixel {
+    color: u8,
+    behind_background: bool,
+    zero: bool,
+}
+
+// $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
+fn palette_mirror(addr: u16) -> usize {
+    let index = (addr & 0x1F) as usize;
+    match index {
+        0x10 | 0x14 | 0x18 | 0x1C => index - 0x10,
+        _ => index,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ppu_vram_writes() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66);
+
+        assert_eq!(ppu.vram[0x0305], 0x66);
+    }
+
+    #[test]
+    fn test_ppu_vram_reads_are_buffered() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0);
+        ppu.vram[0x0305] = 0x66;
+
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_ppu_addr(0x05);
+
+        ppu.read_data(); //load into buffer
+        assert_eq!(ppu.v, 0x2306);
+        assert_eq!(ppu.read_data(), 0x66);
+    }
+
+    #[test]
+    fn test_vram_horizontal_mirror() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ppu_addr(0x24);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66); //write to a
+
+        ppu.write_to_ppu_addr(0x28);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x77); //write to B
+
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data(); //load into buffer
+        assert_eq!(ppu.read_data(), 0x66); //read from A
+
+        ppu.write_to_ppu_addr(0x2C);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data(); //load into buffer
+        assert_eq!(ppu.read_data(), 0x77); //read from b
+    }
+
+    #[test]
+    fn test_status_read_resets_latch_and_vblank() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.vram[0x0305] = 0x66;
+
+        ppu.write_to_ppu_addr(0x21);
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data(); //load into buffer
+        assert_ne!(ppu.read_data(), 0x66);
+
+        ppu.read_status();
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data(); //load into buffer
+        assert_eq!(ppu.read_data(), 0x66);
+
+        ppu.status.insert(StatusRegister::VBLANK_STARTED);
+        assert_eq!(ppu.read_status() >> 7, 1);
+        assert_eq!(ppu.status.snapshot() >> 7, 0);
+    }
+
+    #[test]
+    fn test_vblank_nmi_once_per_frame() {
+        let mut ppu = NesPPU::new_empty_rom();
+        ppu.write_to_ctrl(0b1000_0000);
+
+        assert!(!ppu.tick(DOTS_PER_SCANLINE * 240));
+        assert!(!ppu.poll_nmi_interrupt());
+        assert!(ppu.tick(DOTS_PER_SCANLINE));
+        assert!(ppu.status.is_in_vblank());
+        assert!(ppu.poll_nmi_interrupt());
+        assert!(!ppu.poll_nmi_interrupt());
+        assert_eq!(ppu.frame_count(), 1);
+
+        ppu.tick(DOTS_PER_SCANLINE * 20);
+        assert!(!ppu.status.is_in_vblank());
+    }
+
+    #[test]
+    fn test_region_frame_layout() {
+        // PAL has 70 lines of vblank, Dendy starts vblank 50 lines late
+        for (region, vblank_lines) in [(Region::Pal, 70), (Region::Dendy, 20)] {
+            let mut ppu = NesPPU::new_empty_rom();
+            ppu.set_region(region);
+            assert!(!ppu.tick(DOTS_PER_SCANLINE * (region.vblank_scanline() as usize - 1)));
+            assert!(ppu.tick(DOTS_PER_SCANLINE));
+            ppu.tick(DOTS_PER_SCANLINE * (vblank_lines - 1));
+            assert!(ppu.status.is_in_vblank());
+            ppu.tick(DOTS_PER_SCANLINE);
+            assert!(!ppu.status.is_in_vblank());
+            ppu.tick(DOTS_PER_SCANLINE);
+            assert_eq!(ppu.scanline(), 0);
+        }
+    }
+
+    #[test]
+    fn test_renders_background_and_sprite_zero_hit() {
+        let mut chr = vec![0; 0x2000];
+        // tile 1 is solid color 1
+        chr[16..24].fill(0xFF);
+        let mut ppu = NesPPU::new(chr, Mirroring::Vertical);
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[1] = 0x30;
+        ppu.palette_table[0x11] = 0x16;
+        ppu.vram[0] = 1; // top-left tile
+        ppu.oam_data[..4].copy_from_slice(&[3, 1, 0, 2]);
+        ppu.write_to_mask(0b0001_1110);
+
+        // pre-render line loads the scroll, then line 0 is drawn on entry
+        ppu.tick(DOTS_PER_SCANLINE * 262);
+        assert_eq!(ppu.screen[0], 0x30);
+        assert_eq!(ppu.screen[8], 0x0F);
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+
+        ppu.tick(DOTS_PER_SCANLINE * 4 + 3);
+        assert_eq!(ppu.screen[4 * 256 + 2], 0x16);
+        assert!(ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+    }
+}
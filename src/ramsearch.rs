@@ -0,0 +1,222 @@
+use crate::cpu::Mem;
+
+// 2 KiB internal work RAM and the cartridge PRG-RAM window
+pub const SEARCH_REGIONS: [(u16, u16); 2] = [(0x0000, 0x07FF), (0x6000, 0x7FFF)];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueSize {
+    Byte,
+    Word,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareTo {
+    Previous,
+    Value(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate {
+    pub addr: u16,
+    pub previous: i32,
+}
+
+pub struct RamSearch {
+    pub size: ValueSize,
+    pub signed: bool,
+    candidates: Vec<Candidate>,
+}
+
+impl RamSearch {
+    pub fn new() -> RamSearch {
+        RamSearch {
+            size: ValueSize::Byte,
+            signed: false,
+            candidates: vec![],
+        }
+    }
+
+    pub fn candidates(&self) -> &[Candidate] {
+        &self.candidates
+    }
+
+    pub fn reset<M: Mem>(&mut self, mem: &mut M) {
+        let word = self.size == ValueSize::Word;
+        self.candidates = SEARCH_REGIONS
+            .iter()
+            .flat_map(|&(start, end)| {
+                // words must fit inside the region, the last byte has no high half
+                let last = if word { end - 1 } else { end };
+                start..=last
+            })
+            .map(|addr| Candidate {
+                addr,
+                previous: self.read_value(mem, addr),
+            })
+            .collect();
+    }
+
+    pub fn filter<M: Mem>(&mut self, mem: &mut M, comparison: Comparison, compare_to: CompareTo) {
+        let size = self.size;
+        let signed = self.signed;
+        self.candidates.retain_mut(|candidate| {
+            let current = read_value(mem, candidate.addr, size, signed);
+            let other = match compare_to {
+                CompareTo::Previous => candidate.previous,
+                CompareTo::Value(value) => wrap_value(value, size, signed),
+            };
+            candidate.previous = current;
+            compare(current, other, comparison)
+        });
+    }
+
+    pub fn equal<M: Mem>(&mut self, mem: &mut M) {
+        self.filter(mem, Comparison::Equal, CompareTo::Previous);
+    }
+
+    pub fn changed<M: Mem>(&mut self, mem: &mut M) {
+        self.filter(mem, Comparison::NotEqual, CompareTo::Previous);
+    }
+
+    pub fn increased<M: Mem>(&mut self, mem: &mut M) {
+        self.filter(mem, Comparison::Greater, CompareTo::Previous);
+    }
+
+    pub fn decreased<M: Mem>(&mut self, mem: &mut M) {
+        self.filter(mem, Comparison::Less, CompareTo::Previous);
+    }
+
+    pub fn specific<M: Mem>(&mut self, mem: &mut M, value: i32) {
+        self.filter(mem, Comparison::Equal, CompareTo::Value(value));
+    }
+
+    pub fn read_value<M: Mem>(&self, mem: &mut M, addr: u16) -> i32 {
+        read_value(mem, addr, self.size, self.signed)
+    }
+}
+
+impl Default for RamSearch {
+    fn default() -> RamSearch {
+        RamSearch::new()
+    }
+}
+
+fn read_value<M: Mem>(mem: &mut M, addr: u16, size: ValueSize, signed: bool) -> i32 {
+    match (size, signed) {
+        (ValueSize::Byte, false) => mem.mem_read(addr) as i32,
+        (ValueSize::Byte, true) => mem.mem_read(addr) as i8 as i32,
+        (ValueSize::Word, false) => mem.mem_read_u16(addr) as i32,
+        (ValueSize::Word, true) => mem.mem_read_u16(addr) as i16 as i32,
+    }
+}
+
+// Reads a value given as its bits, like $FF, the way memory is read, so
+// that $FF and -1 both find a signed byte holding -1
+fn wrap_value(value: i32, size: ValueSize, signed: bool) -> i32 {
+    match (size, signed) {
+        (ValueSize::Byte, false) => value as u8 as i32,
+        (ValueSize::Byte, true) => value as u8 as i8 as i32,
+        (ValueSize::Word, false) => value as u16 as i32,
+        (ValueSize::Word, true) => value as u16 as i16 as i32,
+    }
+}
+
+fn compare(current: i32, other: i32, comparison: Comparison) -> bool {
+    match comparison {
+        Comparison::Equal => current == other,
+        Comparison::NotEqual => current != other,
+        Comparison::Less => current < other,
+        Comparison::Greater => current > other,
+        Comparison::LessOrEqual => current <= other,
+        Comparison::GreaterOrEqual => current >= other,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn test_reset_covers_work_ram_and_prg_ram() {
+        let mut cpu = CPU::new();
+        let mut search = RamSearch::new();
+
+        search.reset(&mut cpu);
+
+        assert_eq!(search.candidates().len(), 0x0800 + 0x2000);
+        assert_eq!(search.candidates()[0].addr, 0x0000);
+        assert_eq!(search.candidates().last().unwrap().addr, 0x7FFF);
+    }
+
+    #[test]
+    fn test_changed_increased_decreased() {
+        let mut cpu = CPU::new();
+        let mut search = RamSearch::new();
+        cpu.mem_write(0x10, 5);
+        cpu.mem_write(0x6010, 5);
+        search.reset(&mut cpu);
+
+        cpu.mem_write(0x10, 6);
+        cpu.mem_write(0x6010, 4);
+        search.changed(&mut cpu);
+        assert_eq!(search.candidates().len(), 2);
+
+        cpu.mem_write(0x10, 7);
+        cpu.mem_write(0x6010, 3);
+        search.increased(&mut cpu);
+        assert_eq!(search.candidates(), &[Candidate { addr: 0x10, previous: 7 }]);
+
+        cpu.mem_write(0x10, 2);
+        search.decreased(&mut cpu);
+        assert_eq!(search.candidates().len(), 1);
+
+        search.equal(&mut cpu);
+        search.specific(&mut cpu, 2);
+        assert_eq!(search.candidates().len(), 1);
+    }
+
+    #[test]
+    fn test_signed_search() {
+        let mut cpu = CPU::new();
+        let mut search = RamSearch::new();
+        search.signed = true;
+        cpu.mem_write(0x10, 0xFF);
+        cpu.mem_write(0x11, 0x01);
+
+        // $FF is -1, below 1 rather than above it
+        search.reset(&mut cpu);
+        search.filter(&mut cpu, Comparison::Less, CompareTo::Value(1));
+        assert!(search.candidates().contains(&Candidate { addr: 0x10, previous: -1 }));
+        assert!(!search.candidates().iter().any(|c| c.addr == 0x11));
+        search.filter(&mut cpu, Comparison::Less, CompareTo::Value(0));
+        assert_eq!(search.candidates(), &[Candidate { addr: 0x10, previous: -1 }]);
+        search.specific(&mut cpu, 0xFF);
+        assert_eq!(search.candidates().len(), 1);
+
+        let mut cpu = CPU::new();
+        let mut search = RamSearch::new();
+        search.size = ValueSize::Word;
+        search.signed = true;
+        cpu.mem_write_u16(0x0200, 0xFFFE);
+
+        search.reset(&mut cpu);
+        assert_eq!(search.candidates().last().unwrap().addr, 0x7FFE);
+
+        search.filter(&mut cpu, Comparison::Less, CompareTo::Value(0));
+        assert_eq!(search.candidates(), &[
+            Candidate { addr: 0x01FF, previous: -512 },
+            Candidate { addr: 0x0200, previous: -2 },
+        ]);
+    }
+}
@@ -0,0 +1,227 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::audio::wav::WavWriter;
+use crate::render::filter::{Filter, Image};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VideoFormat {
+    // one numbered PNG per frame in a directory
+    PngSequence,
+    // YUV4MPEG2, 4:4:4, which ffmpeg and most players read directly
+    Y4m,
+    // bare RGB24 frames back to back
+    Raw,
+    // sound only
+    None,
+}
+
+// What to record and where. The sound always goes to a WAV file next to the video.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recording {
+    pub video_path: PathBuf,
+    pub format: VideoFormat,
+    pub audio_path: PathBuf,
+    pub filter: Filter,
+}
+
+impl Recording {
+    // The format follows the extension: .y4m, .rgb or .raw, .wav for sound
+    // only, and anything else is a directory for PNGs
+    pub fn new<P: AsRef<Path>>(path: P) -> Recording {
+        let path = path.as_ref();
+        let extension = path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_ascii_lowercase());
+        let format = match extension.as_deref() {
+            Some("y4m") => VideoFormat::Y4m,
+            Some("rgb") | Some("raw") => VideoFormat::Raw,
+            Some("wav") => VideoFormat::None,
+            _ => VideoFormat::PngSequence,
+        };
+        let audio_path = match format {
+            VideoFormat::None => path.to_path_buf(),
+            VideoFormat::PngSequence => path.join("audio.wav"),
+            _ => path.with_extension("wav"),
+        };
+        Recording {
+            video_path: path.to_path_buf(),
+            format,
+            audio_path,
+            filter: Filter::None,
+        }
+    }
+}
+
+enum VideoWriter {
+    Png(PathBuf),
+    Stream(BufWriter<File>),
+    None,
+}
+
+// An open recording. Every emulated frame is written as one video frame
+// with the sound made during it, so the output is frame exact however fast
+// the emulator runs.
+pub struct Recorder {
+    recording: Recording,
+    video: VideoWriter,
+    audio: WavWriter<BufWriter<File>>,
+    frame_rate: f64,
+    frames: u64,
+    samples: u64,
+}
+
+impl Recorder {
+    pub fn start(recording: Recording, frame_rate: f64, sample_rate: u32) -> Result<Recorder, String> {
+        let error = |path: &Path, err: &dyn std::fmt::Display| format!("{}: {}", path.display(), err);
+        let path = &recording.video_path;
+        let video = match recording.format {
+            VideoFormat::PngSequence => {
+                fs::create_dir_all(path).map_err(|err| error(path, &err))?;
+                VideoWriter::Png(path.clone())
+            }
+            VideoFormat::Y4m | VideoFormat::Raw => {
+                VideoWriter::Stream(BufWriter::new(File::create(path).map_err(|err| error(path, &err))?))
+            }
+            VideoFormat::None => VideoWriter::None,
+        };
+        let audio = WavWriter::create(&recording.audio_path, sample_rate, 1)
+            .map_err(|err| error(&recording.audio_path, &err))?;
+        Ok(Recorder {
+            recording,
+            video,
+            audio,
+            frame_rate,
+            frames: 0,
+            samples: 0,
+        })
+    }
+
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn record_frame(&mut self, image: &Image, samples: &[f32]) -> Result<(), String> {
+        let path = &self.recording.video_path;
+        let error = |err: &dyn std::fmt::Display| format!("{}: {}", path.display(), err);
+        match &mut self.video {
+            VideoWriter::Png(dir) => image.save_png(dir.join(format!("{:06}.png", self.frames)))?,
+            VideoWriter::Stream(out) if self.recording.format == VideoFormat::Y4m => {
+                if self.frames == 0 {
+                    // frame rate as a fraction, e.g. 60.0988 is 600988:10000
+                    let header = format!(
+                        "YUV4MPEG2 W{} H{} F{}:10000 Ip A1:1 C444\n",
+                        image.width,
+                        image.height,
+                        (self.frame_rate * 10000.0).round() as u64
+                    );
+                    out.write_all(header.as_bytes()).map_err(|err| error(&err))?;
+                }
+                out.write_all(b"FRAME\n").map_err(|err| error(&err))?;
+                out.write_all(&y4m_planes(image)).map_err(|err| error(&err))?;
+            }
+            VideoWriter::Stream(out) => out.write_all(&image.data).map_err(|err| error(&err))?,
+            VideoWriter::None => {}
+        }
+        self.frames += 1;
+
+        // Without sound from the emulator the WAV still has to last as long
+        // as the video, so it gets padded with silence
+        let path = &self.recording.audio_path;
+        let error = |err: &dyn std::fmt::Display| format!("{}: {}", path.display(), err);
+        self.audio.write_samples(samples).map_err(|err| error(&err))?;
+        self.samples += samples.len() as u64;
+        let due = (self.frames as f64 * self.audio.sample_rate() as f64 / self.frame_rate) as u64;
+        if self.samples < due {
+            let silence = vec![0.0; (due - self.samples) as usize];
+            self.audio.write_samples(&silence).map_err(|err| error(&err))?;
+            self.samples = due;
+        }
+        Ok(())
+    }
+
+    // Flushes everything and returns how many frames were recorded
+    pub fn finish(self) -> Result<u64, String> {
+        let Recorder { recording, video, audio, frames, .. } = self;
+        if let VideoWriter::Stream(mut out) = video {
+            out.flush().map_err(|err| format!("{}: {}", recording.video_path.display(), err))?;
+        }
+        audio.finish().map_err(|err| format!("{}: {}", recording.audio_path.display(), err))?;
+        Ok(frames)
+    }
+}
+
+// Full resolution Y, Cb and Cr planes, BT.601 in the studio range Y4M
+// readers assume: Y from 16 to 235, Cb and Cr from 16 to 240
+fn y4m_planes(image: &Image) -> Vec<u8> {
+    let pixels = image.width * image.height;
+    let mut planes = vec![0; pixels * 3];
+    for (i, rgb) in image.data.chunks_exact(3).enumerate() {
+        let (r, g, b) = (rgb[0] as f32 / 255.0, rgb[1] as f32 / 255.0, rgb[2] as f32 / 255.0);
+        planes[i] = (16.0 + 65.481 * r + 128.553 * g + 24.966 * b).round() as u8;
+        planes[pixels + i] = (128.0 - 37.797 * r - 74.203 * g + 112.0 * b).round() as u8;
+        planes[pixels * 2 + i] = (128.0 + 112.0 * r - 93.786 * g - 18.214 * b).round() as u8;
+    }
+    planes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pabnes_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_formats_from_path() {
+        assert_eq!(Recording::new("run.y4m").format, VideoFormat::Y4m);
+        assert_eq!(Recording::new("run.y4m").audio_path, PathBuf::from("run.wav"));
+        assert_eq!(Recording::new("run.RGB").format, VideoFormat::Raw);
+        assert_eq!(Recording::new("run.wav").format, VideoFormat::None);
+        assert_eq!(Recording::new("frames").audio_path, PathBuf::from("frames/audio.wav"));
+    }
+
+    #[test]
+    fn test_y4m_with_padded_audio() {
+        let path = temp_path("test.y4m");
+        let mut recorder = Recorder::start(Recording::new(&path), 60.0, 44_100).unwrap();
+        let mut image = Image::new(4, 2);
+        image.data[..3].fill(255);
+        recorder.record_frame(&image, &[]).unwrap();
+        recorder.record_frame(&image, &[0.5; 1000]).unwrap();
+        assert_eq!(recorder.finish(), Ok(2));
+
+        let video = fs::read(&path).unwrap();
+        let header = "YUV4MPEG2 W4 H2 F600000:10000 Ip A1:1 C444\n";
+        assert!(video.starts_with(header.as_bytes()));
+        assert_eq!(video.len(), header.len() + 2 * (6 + 4 * 2 * 3));
+        // studio range: white is Y 235, black Y 16, both with Cb and Cr 128
+        let planes = &video[header.len() + 6..][..24];
+        assert_eq!(&planes[..9], &[235, 16, 16, 16, 16, 16, 16, 16, 128]);
+        assert!(planes[8..].iter().all(|&chroma| chroma == 128));
+
+        // 735 samples of silence, then 1000 that already cover the second frame
+        let audio_path = path.with_extension("wav");
+        assert_eq!(fs::metadata(&audio_path).unwrap().len(), 44 + (735 + 1000) * 2);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&audio_path).unwrap();
+    }
+
+    #[test]
+    fn test_png_sequence() {
+        let dir = temp_path("frames");
+        let _ = fs::remove_dir_all(&dir);
+        let mut recorder = Recorder::start(Recording::new(&dir), 60.0, 44_100).unwrap();
+        for _ in 0..3 {
+            recorder.record_frame(&Image::new(2, 2), &[]).unwrap();
+        }
+        recorder.finish().unwrap();
+        assert!(dir.join("000002.png").exists());
+        assert!(dir.join("audio.wav").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
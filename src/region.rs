@@ -0,0 +1,158 @@
+use crate::clock::{NTSC_FRAME_RATE, PAL_FRAME_RATE};
+
+// The console's TV system, which sets how fast the CPU runs, how the PPU is
+// clocked against it and how long a frame is.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    // Famiclone sold in Russia: a PAL-speed PPU with NTSC-like CPU timing
+    Dendy,
+}
+
+// APU tables, in CPU cycles
+const NTSC_FRAME_COUNTER_STEPS: [u32; 5] = [7457, 14913, 22371, 29829, 37281];
+const PAL_FRAME_COUNTER_STEPS: [u32; 5] = [8313, 16627, 24939, 33253, 41565];
+const NTSC_NOISE_PERIODS: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+const PAL_NOISE_PERIODS: [u16; 16] = [4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778];
+const NTSC_DMC_PERIODS: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+const PAL_DMC_PERIODS: [u16; 16] = [398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50];
+
+impl Region {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Region::Ntsc => "NTSC",
+            Region::Pal => "PAL",
+            Region::Dendy => "Dendy",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Region, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "ntsc" => Ok(Region::Ntsc),
+            "pal" => Ok(Region::Pal),
+            "dendy" => Ok(Region::Dendy),
+            _ => Err(format!("Unknown region '{}'", name)),
+        }
+    }
+
+    // No-Intro and GoodNES names tag PAL releases, e.g. "Game (Europe).nes" or "Game (E).nes".
+    // Only a fallback for headers that do not say: there is no database of checksums.
+    pub fn from_file_name(name: &str) -> Option<Region> {
+        let tags = ["(e)", "(europe)", "(pal)", "(australia)", "(germany)", "(france)", "(spain)", "(italy)"];
+        let name = name.to_ascii_lowercase();
+        if name.contains("(dendy)") {
+            Some(Region::Dendy)
+        } else if tags.iter().any(|tag| name.contains(tag)) {
+            Some(Region::Pal)
+        } else {
+            None
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => NTSC_FRAME_RATE,
+            // same frame length and nearly the same clock as PAL
+            Region::Pal | Region::Dendy => PAL_FRAME_RATE,
+        }
+    }
+
+    pub fn cpu_clock_rate(&self) -> u32 {
+        match self {
+            Region::Ntsc => 1_789_773,
+            Region::Pal => 1_662_607,
+            Region::Dendy => 1_773_448,
+        }
+    }
+
+    // PPU dots per CPU cycle as a fraction: 3 on NTSC and Dendy, 3.2 on PAL
+    pub fn ppu_dots_per_cpu_cycle(&self) -> (usize, usize) {
+        match self {
+            Region::Ntsc | Region::Dendy => (3, 1),
+            Region::Pal => (16, 5),
+        }
+    }
+
+    pub fn scanlines_per_frame(&self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    // First scanline of vblank, where the NMI fires. Dendy puts its extra
+    // lines before vblank so that games written for NTSC keep working.
+    pub fn vblank_scanline(&self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    pub fn frame_counter_steps(&self) -> &'static [u32; 5] {
+        match self {
+            Region::Ntsc | Region::Dendy => &NTSC_FRAME_COUNTER_STEPS,
+            Region::Pal => &PAL_FRAME_COUNTER_STEPS,
+        }
+    }
+
+    pub fn noise_periods(&self) -> &'static [u16; 16] {
+        match self {
+            Region::Ntsc | Region::Dendy => &NTSC_NOISE_PERIODS,
+            Region::Pal => &PAL_NOISE_PERIODS,
+        }
+    }
+
+    pub fn dmc_periods(&self) -> &'static [u16; 16] {
+        match self {
+            Region::Ntsc | Region::Dendy => &NTSC_DMC_PERIODS,
+            Region::Pal => &PAL_DMC_PERIODS,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Region::Ntsc => 0,
+            Region::Pal => 1,
+            Region::Dendy => 2,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Result<Region, String> {
+        match value {
+            0 => Ok(Region::Ntsc),
+            1 => Ok(Region::Pal),
+            2 => Ok(Region::Dendy),
+            _ => Err(format!("Unknown region {}", value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_region_names() {
+        assert_eq!(Region::parse("PAL"), Ok(Region::Pal));
+        assert!(Region::parse("secam").is_err());
+        assert_eq!(Region::from_file_name("Game (Europe).nes"), Some(Region::Pal));
+        assert_eq!(Region::from_file_name("Game (E) [!].nes"), Some(Region::Pal));
+        assert_eq!(Region::from_file_name("Game (USA).nes"), None);
+        assert_eq!(Region::from_u8(Region::Dendy.to_u8()), Ok(Region::Dendy));
+    }
+
+    #[test]
+    fn test_frame_rate_matches_timing() {
+        for region in [Region::Ntsc, Region::Pal, Region::Dendy] {
+            let (dots, cycles) = region.ppu_dots_per_cpu_cycle();
+            let dots_per_second = region.cpu_clock_rate() as f64 * dots as f64 / cycles as f64;
+            // NTSC skips a dot every other frame
+            let dots_per_frame = region.scanlines_per_frame() as f64 * 341.0
+                - if region == Region::Ntsc { 0.5 } else { 0.0 };
+            assert!((dots_per_second / dots_per_frame - region.frame_rate()).abs() < 0.001, "{:?}", region);
+        }
+    }
+}
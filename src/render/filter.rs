@@ -0,0 +1,493 @@
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use super::frame::Frame;
+use super::palette::{ntsc_signal, NtscSettings};
+
+// Scanlines keep this much of the line's brightness
+const SCANLINE_LEVEL: u32 = 128;
+// Shadow mask of the CRT filter: the other two channels of each column are
+// dimmed to this level, and the last row of every pixel to SCANLINE_LEVEL
+const MASK_LEVEL: u32 = 160;
+// The NES puts out 8 signal samples per pixel, 12 per color cycle
+const SAMPLES_PER_PIXEL: usize = 8;
+
+// An RGB picture of any size, what the filters turn a Frame into
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Image {
+        Image {
+            width,
+            height,
+            data: vec![0; width * height * 3],
+        }
+    }
+
+    pub fn from_frame(frame: &Frame) -> Image {
+        Image {
+            width: Frame::WIDTH,
+            height: Frame::HEIGHT,
+            data: frame.data.clone(),
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * self.width + x) * 3;
+        (self.data[base], self.data[base + 1], self.data[base + 2])
+    }
+
+    fn set(&mut self, x: usize, y: usize, rgb: u32) {
+        let base = (y * self.width + x) * 3;
+        self.data[base..base + 3].copy_from_slice(&rgb.to_be_bytes()[1..]);
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let error = |err: &dyn std::fmt::Display| format!("{}: {}", path.display(), err);
+        let file = File::create(path).map_err(|err| error(&err))?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        // without this the encoder picks its fastest mode, which hardly
+        // compresses patterned frames at all
+        encoder.set_compression(png::Compression::Default);
+        let mut writer = encoder.write_header().map_err(|err| error(&err))?;
+        writer.write_image_data(&self.data).map_err(|err| error(&err))
+    }
+
+    // Reads back a PNG in the format save_png writes
+    pub fn load_png<P: AsRef<Path>>(path: P) -> Result<Image, String> {
+        let path = path.as_ref();
+        let error = |err: &dyn std::fmt::Display| format!("{}: {}", path.display(), err);
+        let file = File::open(path).map_err(|err| error(&err))?;
+        let mut reader = png::Decoder::new(file).read_info().map_err(|err| error(&err))?;
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).map_err(|err| error(&err))?;
+        if info.color_type != png::ColorType::Rgb || info.bit_depth != png::BitDepth::Eight {
+            return Err(error(&"not an 8 bit RGB image"));
+        }
+        data.truncate(info.buffer_size());
+        Ok(Image {
+            width: info.width as usize,
+            height: info.height as usize,
+            data,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Default)]
+pub enum Filter {
+    #[default]
+    None,
+    // every other line darkened, at 2x
+    Scanlines,
+    // scanlines plus an RGB shadow mask, at 3x
+    Crt,
+    Scale2x,
+    Scale3x,
+    // hq2x-style edge blending, but not the real hq2x rule table
+    Smooth2x,
+    Xbr,
+    // decodes the composite signal the PPU would have sent, color fringes
+    // and all, at 2x
+    Ntsc(NtscSettings),
+}
+
+pub const FILTER_NAMES: [&str; 8] = ["none", "scanlines", "crt", "scale2x", "scale3x", "smooth2x", "xbr", "ntsc"];
+
+impl Filter {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Filter::None => "none",
+            Filter::Scanlines => "scanlines",
+            Filter::Crt => "crt",
+            Filter::Scale2x => "scale2x",
+            Filter::Scale3x => "scale3x",
+            Filter::Smooth2x => "smooth2x",
+            Filter::Xbr => "xbr",
+            Filter::Ntsc(_) => "ntsc",
+        }
+    }
+
+    // A name from FILTER_NAMES, or "ntsc:" with decoder settings like the
+    // generated palette takes
+    pub fn parse(spec: &str) -> Result<Filter, String> {
+        match spec {
+            "none" => Ok(Filter::None),
+            "scanlines" => Ok(Filter::Scanlines),
+            "crt" => Ok(Filter::Crt),
+            "scale2x" => Ok(Filter::Scale2x),
+            "scale3x" => Ok(Filter::Scale3x),
+            "smooth2x" => Ok(Filter::Smooth2x),
+            "xbr" => Ok(Filter::Xbr),
+            "ntsc" => Ok(Filter::Ntsc(NtscSettings::new())),
+            _ => match spec.strip_prefix("ntsc:") {
+                Some(settings) => Ok(Filter::Ntsc(NtscSettings::parse(settings)?)),
+                None => Err(format!("Unknown filter '{}', expected one of {}", spec, FILTER_NAMES.join(", "))),
+            },
+        }
+    }
+
+    // The one after this in FILTER_NAMES, for cycling through them with a hotkey
+    pub fn next(&self) -> Filter {
+        let index = FILTER_NAMES.iter().position(|name| *name == self.name()).unwrap_or(0);
+        Filter::parse(FILTER_NAMES[(index + 1) % FILTER_NAMES.len()]).expect("filter names parse")
+    }
+
+    // `screen` is the PPU's output the frame was rendered from, which the
+    // NTSC filter works on instead of the RGB colors
+    pub fn apply(&self, screen: &[u16], frame: &Frame, frame_count: u64) -> Image {
+        let source = Source::new(frame);
+        match self {
+            Filter::None => Image::from_frame(frame),
+            Filter::Scanlines => scanlines(&source),
+            Filter::Crt => crt(&source),
+            Filter::Scale2x => scale2x(&source),
+            Filter::Scale3x => scale3x(&source),
+            Filter::Smooth2x => smooth2x(&source),
+            Filter::Xbr => xbr(&source),
+            Filter::Ntsc(settings) => ntsc(screen, settings, frame_count),
+        }
+    }
+}
+
+// The frame as packed 0xRRGGBB, with reads past the edges clamped
+struct Source {
+    pixels: Vec<u32>,
+}
+
+impl Source {
+    fn new(frame: &Frame) -> Source {
+        let pixels = frame
+            .data
+            .chunks_exact(3)
+            .map(|rgb| u32::from_be_bytes([0, rgb[0], rgb[1], rgb[2]]))
+            .collect();
+        Source { pixels }
+    }
+
+    fn get(&self, x: usize, y: usize, dx: isize, dy: isize) -> u32 {
+        let x = (x as isize + dx).clamp(0, Frame::WIDTH as isize - 1) as usize;
+        let y = (y as isize + dy).clamp(0, Frame::HEIGHT as isize - 1) as usize;
+        self.pixels[y * Frame::WIDTH + x]
+    }
+}
+
+fn channels(rgb: u32) -> [u32; 3] {
+    [(rgb >> 16) & 0xFF, (rgb >> 8) & 0xFF, rgb & 0xFF]
+}
+
+fn pack(channels: [u32; 3]) -> u32 {
+    (channels[0].min(255) << 16) | (channels[1].min(255) << 8) | channels[2].min(255)
+}
+
+fn scale(rgb: u32, levels: [u32; 3]) -> u32 {
+    let c = channels(rgb);
+    pack([c[0] * levels[0] / 255, c[1] * levels[1] / 255, c[2] * levels[2] / 255])
+}
+
+// Weighted average of colors, weights summing to anything
+fn blend(colors: &[(u32, u32)]) -> u32 {
+    let total: u32 = colors.iter().map(|(_, weight)| weight).sum();
+    let mut sum = [0; 3];
+    for (color, weight) in colors {
+        for (sum, channel) in sum.iter_mut().zip(channels(*color).iter()) {
+            *sum += channel * weight;
+        }
+    }
+    pack([sum[0] / total, sum[1] / total, sum[2] / total])
+}
+
+fn yuv(rgb: u32) -> [i32; 3] {
+    let [r, g, b] = channels(rgb);
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    [
+        (299 * r + 587 * g + 114 * b) / 1000,
+        (-169 * r - 331 * g + 500 * b) / 1000 + 128,
+        (500 * r - 419 * g - 81 * b) / 1000 + 128,
+    ]
+}
+
+fn scanlines(source: &Source) -> Image {
+    let mut image = Image::new(Frame::WIDTH * 2, Frame::HEIGHT * 2);
+    for y in 0..Frame::HEIGHT {
+        for x in 0..Frame::WIDTH {
+            let color = source.get(x, y, 0, 0);
+            let dark = scale(color, [SCANLINE_LEVEL; 3]);
+            for dx in 0..2 {
+                image.set(x * 2 + dx, y * 2, color);
+                image.set(x * 2 + dx, y * 2 + 1, dark);
+            }
+        }
+    }
+    image
+}
+
+fn crt(source: &Source) -> Image {
+    let mut image = Image::new(Frame::WIDTH * 3, Frame::HEIGHT * 3);
+    for y in 0..Frame::HEIGHT {
+        for x in 0..Frame::WIDTH {
+            let color = source.get(x, y, 0, 0);
+            for column in 0..3 {
+                let mut levels = [MASK_LEVEL; 3];
+                levels[column] = 255;
+                let masked = scale(color, levels);
+                image.set(x * 3 + column, y * 3, masked);
+                image.set(x * 3 + column, y * 3 + 1, masked);
+                image.set(x * 3 + column, y * 3 + 2, scale(masked, [SCANLINE_LEVEL; 3]));
+            }
+        }
+    }
+    image
+}
+
+// https://www.scale2x.it/algorithm
+fn scale2x(source: &Source) -> Image {
+    let mut image = Image::new(Frame::WIDTH * 2, Frame::HEIGHT * 2);
+    for y in 0..Frame::HEIGHT {
+        for x in 0..Frame::WIDTH {
+            let p = |dx, dy| source.get(x, y, dx, dy);
+            let (b, d, e, f, h) = (p(0, -1), p(-1, 0), p(0, 0), p(1, 0), p(0, 1));
+            let out = if b != h && d != f {
+                [
+                    if d == b { d } else { e },
+                    if b == f { f } else { e },
+                    if d == h { d } else { e },
+                    if h == f { f } else { e },
+                ]
+            } else {
+                [e; 4]
+            };
+            for (i, color) in out.iter().enumerate() {
+                image.set(x * 2 + i % 2, y * 2 + i / 2, *color);
+            }
+        }
+    }
+    image
+}
+
+fn scale3x(source: &Source) -> Image {
+    let mut image = Image::new(Frame::WIDTH * 3, Frame::HEIGHT * 3);
+    for y in 0..Frame::HEIGHT {
+        for x in 0..Frame::WIDTH {
+            let p = |dx, dy| source.get(x, y, dx, dy);
+            let (a, b, c) = (p(-1, -1), p(0, -1), p(1, -1));
+            let (d, e, f) = (p(-1, 0), p(0, 0), p(1, 0));
+            let (g, h, i) = (p(-1, 1), p(0, 1), p(1, 1));
+            let out = if b != h && d != f {
+                [
+                    if d == b { d } else { e },
+                    if (d == b && e != c) || (b == f && e != a) { b } else { e },
+                    if b == f { f } else { e },
+                    if (d == b && e != g) || (d == h && e != a) { d } else { e },
+                    e,
+                    if (b == f && e != i) || (h == f && e != c) { f } else { e },
+                    if d == h { d } else { e },
+                    if (d == h && e != i) || (h == f && e != g) { h } else { e },
+                    if h == f { f } else { e },
+                ]
+            } else {
+                [e; 9]
+            };
+            for (n, color) in out.iter().enumerate() {
+                image.set(x * 3 + n % 3, y * 3 + n / 3, *color);
+            }
+        }
+    }
+    image
+}
+
+// Colors treated as the same: close in luma and both chroma channels, with
+// hq2x's thresholds
+fn similar(a: u32, b: u32) -> bool {
+    let (a, b) = (yuv(a), yuv(b));
+    (a[0] - b[0]).abs() <= 48 && (a[1] - b[1]).abs() <= 7 && (a[2] - b[2]).abs() <= 6
+}
+
+// Borrows hq2x's similarity test but not its 256-entry rule table, just
+// two rules per corner: an edge running past the corner gets rounded off,
+// a lone diagonal neighbor gets blended in
+fn smooth2x(source: &Source) -> Image {
+    let mut image = Image::new(Frame::WIDTH * 2, Frame::HEIGHT * 2);
+    for y in 0..Frame::HEIGHT {
+        for x in 0..Frame::WIDTH {
+            let e = source.get(x, y, 0, 0);
+            for (corner, (dx, dy)) in [(-1, -1), (1, -1), (-1, 1), (1, 1)].iter().enumerate() {
+                let side = source.get(x, y, *dx, 0);
+                let vertical = source.get(x, y, 0, *dy);
+                let diagonal = source.get(x, y, *dx, *dy);
+                let color = if similar(side, vertical) && !similar(e, side) {
+                    blend(&[(e, 2), (side, 1), (vertical, 1)])
+                } else if !similar(e, diagonal) && (similar(e, side) || similar(e, vertical)) {
+                    blend(&[(e, 3), (diagonal, 1)])
+                } else {
+                    e
+                };
+                image.set(x * 2 + corner % 2, y * 2 + corner / 2, color);
+            }
+        }
+    }
+    image
+}
+
+fn distance(a: u32, b: u32) -> u32 {
+    let (a, b) = (yuv(a), yuv(b));
+    (48 * (a[0] - b[0]).abs() + 7 * (a[1] - b[1]).abs() + 6 * (a[2] - b[2]).abs()) as u32
+}
+
+// Hyllian's xBR at 2x. Each corner weighs the color differences along the
+// two diagonals through it and, if an edge runs across the corner, blends in
+// the neighbor on the other side of it.
+fn xbr(source: &Source) -> Image {
+    let mut image = Image::new(Frame::WIDTH * 2, Frame::HEIGHT * 2);
+    for y in 0..Frame::HEIGHT {
+        for x in 0..Frame::WIDTH {
+            let e = source.get(x, y, 0, 0);
+            for (corner, (sx, sy)) in [(-1, -1), (1, -1), (-1, 1), (1, 1)].iter().enumerate() {
+                // neighbors as seen from the bottom right corner, mirrored into this one
+                let p = |dx: isize, dy: isize| source.get(x, y, dx * sx, dy * sy);
+                let (b, c, d, f) = (p(0, -1), p(1, -1), p(-1, 0), p(1, 0));
+                let (g, h, i) = (p(-1, 1), p(0, 1), p(1, 1));
+                let (f4, i4, h5, i5) = (p(2, 0), p(2, 1), p(0, 2), p(1, 2));
+
+                let across = distance(e, c) + distance(e, g) + distance(i, f4) + distance(i, h5) + 4 * distance(h, f);
+                let along = distance(h, d) + distance(h, i5) + distance(f, i4) + distance(f, b) + 4 * distance(e, i);
+                let color = if across < along {
+                    let neighbor = if distance(e, f) <= distance(e, h) { f } else { h };
+                    blend(&[(e, 1), (neighbor, 1)])
+                } else {
+                    e
+                };
+                image.set(x * 2 + corner % 2, y * 2 + corner / 2, color);
+            }
+        }
+    }
+    image
+}
+
+// Rebuilds each scanline's composite signal, 8 samples per pixel, and
+// decodes it at two points per pixel with a 12 sample (one color cycle) window
+fn ntsc(screen: &[u16], settings: &NtscSettings, frame_count: u64) -> Image {
+    let mut levels = vec![[0.0; 12]; 512];
+    for (pixel, phases) in levels.iter_mut().enumerate() {
+        for (phase, level) in phases.iter_mut().enumerate() {
+            *level = ntsc_signal(pixel as u16, phase as u16);
+        }
+    }
+    let hue = settings.hue_offset();
+    let carrier: Vec<(f64, f64)> = (0..12)
+        .map(|phase| {
+            let angle = PI * (phase as f64 + hue) / 6.0;
+            (angle.cos(), angle.sin())
+        })
+        .collect();
+
+    let samples = Frame::WIDTH * SAMPLES_PER_PIXEL;
+    let mut image = Image::new(Frame::WIDTH * 2, Frame::HEIGHT * 2);
+    let mut signal = vec![0.0; samples];
+    for y in 0..Frame::HEIGHT {
+        // 341 dots of 8 samples move each line 4 samples along the color
+        // cycle, and the short odd frames shift every other frame by 4 more
+        let line_phase = (y * 4 + (frame_count as usize % 2) * 4) % 12;
+        for (s, level) in signal.iter_mut().enumerate() {
+            let pixel = screen[y * Frame::WIDTH + s / SAMPLES_PER_PIXEL] & 0x1FF;
+            *level = levels[pixel as usize][(s + line_phase) % 12];
+        }
+
+        for x in 0..Frame::WIDTH * 2 {
+            let center = x * SAMPLES_PER_PIXEL / 2;
+            let (mut luma, mut i, mut q) = (0.0, 0.0, 0.0);
+            for s in center as isize - 6..center as isize + 6 {
+                // off the edges of the line the signal sits at black
+                let level = if s >= 0 && (s as usize) < samples { signal[s as usize] } else { 0.0 };
+                let (cos, sin) = carrier[(s + line_phase as isize).rem_euclid(12) as usize];
+                luma += level;
+                i += level * cos;
+                q += level * sin;
+            }
+            let (r, g, b) = settings.yiq_to_rgb(luma / 12.0, i / 12.0, q / 12.0);
+            let rgb = u32::from_be_bytes([0, r, g, b]);
+            image.set(x, y * 2, rgb);
+            image.set(x, y * 2 + 1, rgb);
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::render::palette::Palette;
+
+    // a white diagonal staircase on black
+    fn staircase() -> Frame {
+        let mut frame = Frame::new();
+        for i in 0..Frame::HEIGHT {
+            frame.set_pixel(i, i, (255, 255, 255));
+        }
+        frame
+    }
+
+    #[test]
+    fn test_filter_sizes_and_names() {
+        let frame = Frame::new();
+        let screen = vec![0; Frame::WIDTH * Frame::HEIGHT];
+        let mut filter = Filter::None;
+        for _ in 0..FILTER_NAMES.len() {
+            let image = filter.apply(&screen, &frame, 0);
+            let factor = match filter {
+                Filter::None => 1,
+                Filter::Crt | Filter::Scale3x => 3,
+                _ => 2,
+            };
+            assert_eq!((image.width, image.height), (256 * factor, 240 * factor), "{}", filter.name());
+            assert_eq!(image.data.len(), image.width * image.height * 3);
+            assert_eq!(Filter::parse(filter.name()).unwrap().name(), filter.name());
+            filter = filter.next();
+        }
+        assert_eq!(filter, Filter::None);
+        assert!(Filter::parse("ntsc:saturation=2").is_ok());
+        assert!(Filter::parse("bilinear").is_err());
+    }
+
+    #[test]
+    fn test_scalers_smooth_diagonals() {
+        let frame = staircase();
+        let screen = vec![0; Frame::WIDTH * Frame::HEIGHT];
+
+        let image = Filter::Scale2x.apply(&screen, &frame, 0);
+        // the corners between steps get filled in
+        assert_eq!(image.pixel(22, 21), (255, 255, 255));
+        assert_eq!(image.pixel(21, 22), (255, 255, 255));
+        assert_eq!(image.pixel(23, 21), (0, 0, 0));
+
+        for filter in [Filter::Smooth2x, Filter::Xbr] {
+            let image = filter.apply(&screen, &frame, 0);
+            let (r, _, _) = image.pixel(21, 20);
+            assert!(r > 0 && r < 255, "{} leaves a hard step", filter.name());
+            // flat areas stay untouched
+            assert_eq!(image.pixel(100, 20), (0, 0, 0));
+        }
+    }
+
+    #[test]
+    fn test_ntsc_flat_colors_match_palette() {
+        let settings = NtscSettings::new();
+        let palette = Palette::generate(&settings);
+        let screen = vec![0x16; Frame::WIDTH * Frame::HEIGHT];
+        let image = Filter::Ntsc(settings).apply(&screen, &Frame::new(), 0);
+        let (r, g, b) = image.pixel(200, 100);
+        let (pr, pg, pb) = palette.color(0x16);
+        for (a, b) in [(r, pr), (g, pg), (b, pb)] {
+            assert!((a as i32 - b as i32).abs() <= 1);
+        }
+    }
+}
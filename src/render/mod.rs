@@ -0,0 +1,16 @@
+pub mod filter;
+pub mod frame;
+pub mod palette;
+pub mod text;
+
+use crate::ppu::NesPPU;
+use frame::Frame;
+use palette::Palette;
+
+// Turns the PPU's palette indices and emphasis bits into RGB
+pub fn render(ppu: &NesPPU, palette: &Palette, frame: &mut Frame) {
+    for (pixel, rgb) in ppu.screen.iter().zip(frame.data.chunks_exact_mut(3)) {
+        let color = palette.color(*pixel);
+        rgb.copy_from_slice(&[color.0, color.1, color.2]);
+    }
+}
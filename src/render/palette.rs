@@ -0,0 +1,277 @@
+use std::f64::consts::PI;
+use std::fs;
+use std::path::Path;
+
+#[rustfmt::skip]
+pub static SYSTEM_PALLETE: [(u8, u8, u8); 64] = [
+    (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96), (0xA1, 0x00, 0x5E),
+    (0xC7, 0x00, 0x28), (0xBA, 0x06, 0x00), (0x8C, 0x17, 0x00), (0x5C, 0x2F, 0x00), (0x10, 0x45, 0x00),
+    (0x05, 0x4A, 0x00), (0x00, 0x47, 0x2E), (0x00, 0x41, 0x66), (0x00, 0x00, 0x00), (0x05, 0x05, 0x05),
+    (0x05, 0x05, 0x05), (0xC7, 0xC7, 0xC7), (0x00, 0x77, 0xFF), (0x21, 0x55, 0xFF), (0x82, 0x37, 0xFA),
+    (0xEB, 0x2F, 0xB5), (0xFF, 0x29, 0x50), (0xFF, 0x22, 0x00), (0xD6, 0x32, 0x00), (0xC4, 0x62, 0x00),
+    (0x35, 0x80, 0x00), (0x05, 0x8F, 0x00), (0x00, 0x8A, 0x55), (0x00, 0x99, 0xCC), (0x21, 0x21, 0x21),
+    (0x09, 0x09, 0x09), (0x09, 0x09, 0x09), (0xFF, 0xFF, 0xFF), (0x0F, 0xD7, 0xFF), (0x69, 0xA2, 0xFF),
+    (0xD4, 0x80, 0xFF), (0xFF, 0x45, 0xF3), (0xFF, 0x61, 0x8B), (0xFF, 0x88, 0x33), (0xFF, 0x9C, 0x12),
+    (0xFA, 0xBC, 0x20), (0x9F, 0xE3, 0x0E), (0x2B, 0xF0, 0x35), (0x0C, 0xF0, 0xA4), (0x05, 0xFB, 0xFF),
+    (0x5E, 0x5E, 0x5E), (0x0D, 0x0D, 0x0D), (0x0D, 0x0D, 0x0D), (0xFF, 0xFF, 0xFF), (0xA6, 0xFC, 0xFF),
+    (0xB3, 0xEC, 0xFF), (0xDA, 0xAB, 0xEB), (0xFF, 0xA8, 0xF9), (0xFF, 0xAB, 0xB3), (0xFF, 0xD2, 0xB0),
+    (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
+    (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11),
+];
+
+pub const BUILTIN_PALETTES: [&str; 2] = ["default", "ntsc"];
+
+// Colors per emphasis combination, and the combinations
+const COLORS: usize = 64;
+const ENTRIES: usize = COLORS * 8;
+// How much emphasis dims the other two channels when a .pal file only has
+// the 64 base colors
+const EMPHASIS_ATTENUATION: f64 = 0.746;
+
+// Maps the PPU's 9-bit pixels (color index | emphasis << 6) to RGB
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    colors: Vec<(u8, u8, u8)>,
+}
+
+impl Palette {
+    pub fn new() -> Palette {
+        Palette::from_base_colors(&SYSTEM_PALLETE)
+    }
+
+    pub fn builtin(name: &str) -> Option<Palette> {
+        match name {
+            "default" => Some(Palette::new()),
+            "ntsc" => Some(Palette::generate(&NtscSettings::new())),
+            _ => None,
+        }
+    }
+
+    // A built-in name, "ntsc:" followed by settings like "hue=-5,saturation=1.2",
+    // or the path of a .pal file
+    pub fn from_spec(spec: &str) -> Result<Palette, String> {
+        if let Some(palette) = Palette::builtin(spec) {
+            return Ok(palette);
+        }
+        if let Some(settings) = spec.strip_prefix("ntsc:") {
+            return Ok(Palette::generate(&NtscSettings::parse(settings)?));
+        }
+        Palette::load(spec)
+    }
+
+    // Fills in the emphasis variants by dimming the channels that are not emphasized
+    pub fn from_base_colors(base: &[(u8, u8, u8); COLORS]) -> Palette {
+        let mut colors = Vec::with_capacity(ENTRIES);
+        for emphasis in 0..8u8 {
+            for (index, &(r, g, b)) in base.iter().enumerate() {
+                // $xE and $xF are black whatever the emphasis
+                if emphasis == 0 || index & 0x0E == 0x0E {
+                    colors.push((r, g, b));
+                    continue;
+                }
+                let dim = |value: u8, bit: u8| {
+                    if emphasis & bit != 0 {
+                        value
+                    } else {
+                        (value as f64 * EMPHASIS_ATTENUATION).round() as u8
+                    }
+                };
+                colors.push((dim(r, 1), dim(g, 2), dim(b, 4)));
+            }
+        }
+        Palette { colors }
+    }
+
+    // .pal files are 64 or 512 RGB triplets, the latter with every emphasis variant
+    pub fn from_pal(data: &[u8]) -> Result<Palette, String> {
+        let colors: Vec<(u8, u8, u8)> = data.chunks_exact(3).map(|rgb| (rgb[0], rgb[1], rgb[2])).collect();
+        match (data.len() % 3, colors.len()) {
+            (0, COLORS) => {
+                let mut base = [(0, 0, 0); COLORS];
+                base.copy_from_slice(&colors);
+                Ok(Palette::from_base_colors(&base))
+            }
+            (0, ENTRIES) => Ok(Palette { colors }),
+            _ => Err(format!("A .pal file has 64 or 512 colors, this one is {} bytes", data.len())),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Palette, String> {
+        let path = path.as_ref();
+        let data = fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        Palette::from_pal(&data).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    // Decodes what a TV would make of the composite signal the PPU puts out
+    // for each pixel, after https://www.nesdev.org/wiki/NTSC_video
+    pub fn generate(settings: &NtscSettings) -> Palette {
+        let hue = settings.hue_offset();
+        let colors = (0..ENTRIES as u16)
+            .map(|pixel| {
+                let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+                for phase in 0..12 {
+                    let level = ntsc_signal(pixel, phase);
+                    let angle = PI * (phase as f64 + hue) / 6.0;
+                    y += level;
+                    i += level * angle.cos();
+                    q += level * angle.sin();
+                }
+                settings.yiq_to_rgb(y / 12.0, i / 12.0, q / 12.0)
+            })
+            .collect();
+        Palette { colors }
+    }
+
+    pub fn color(&self, pixel: u16) -> (u8, u8, u8) {
+        self.colors[pixel as usize % ENTRIES]
+    }
+
+    // The full 512 color .pal format
+    pub fn to_pal(&self) -> Vec<u8> {
+        self.colors.iter().flat_map(|&(r, g, b)| [r, g, b]).collect()
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::new()
+    }
+}
+
+// Knobs of the generated palette, the way a TV's menu would have them
+#[derive(Clone, Debug, PartialEq)]
+pub struct NtscSettings {
+    // degrees
+    pub hue: f64,
+    pub saturation: f64,
+    pub contrast: f64,
+    pub brightness: f64,
+    pub gamma: f64,
+}
+
+impl NtscSettings {
+    pub fn new() -> NtscSettings {
+        NtscSettings {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 2.2,
+        }
+    }
+
+    // "hue=-5,saturation=1.2", anything left out keeps its default
+    pub fn parse(source: &str) -> Result<NtscSettings, String> {
+        let mut settings = NtscSettings::new();
+        for pair in source.split(',').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').ok_or_else(|| format!("Expected key=value, got '{}'", pair))?;
+            let value: f64 = value.trim().parse().map_err(|_| format!("Invalid number in '{}'", pair))?;
+            match key.trim() {
+                "hue" => settings.hue = value,
+                "saturation" => settings.saturation = value,
+                "contrast" => settings.contrast = value,
+                "brightness" => settings.brightness = value,
+                "gamma" if value > 0.0 => settings.gamma = value,
+                "gamma" => return Err("gamma must be above 0".to_string()),
+                key => return Err(format!("Unknown palette setting '{}'", key)),
+            }
+        }
+        Ok(settings)
+    }
+
+    // Phase of the decoder's color reference, in twelfths of a color cycle
+    pub(crate) fn hue_offset(&self) -> f64 {
+        NTSC_HUE_OFFSET + self.hue / 30.0
+    }
+
+    pub(crate) fn yiq_to_rgb(&self, y: f64, i: f64, q: f64) -> (u8, u8, u8) {
+        let y = y * self.contrast + self.brightness;
+        let i = i * self.saturation * self.contrast;
+        let q = q * self.saturation * self.contrast;
+
+        let channel = |value: f64| (value.clamp(0.0, 1.0).powf(2.2 / self.gamma) * 255.0).round() as u8;
+        (
+            channel(y + 0.946882 * i + 0.623557 * q),
+            channel(y - 0.274788 * i - 0.635691 * q),
+            channel(y - 1.108545 * i + 1.709007 * q),
+        )
+    }
+}
+
+impl Default for NtscSettings {
+    fn default() -> NtscSettings {
+        NtscSettings::new()
+    }
+}
+
+// Signal voltages relative to sync: four low and four high levels of the
+// color square wave
+const SIGNAL_LEVELS: [f64; 8] = [0.350, 0.518, 0.962, 1.550, 1.094, 1.506, 1.962, 1.962];
+const BLACK: f64 = 0.518;
+const WHITE: f64 = 1.962;
+const SIGNAL_ATTENUATION: f64 = 0.746;
+// Lines the decoded hues up with the colorburst
+const NTSC_HUE_OFFSET: f64 = 3.9;
+
+// Signal level of `pixel` at one of the 12 phases of the color subcarrier,
+// scaled so black is 0 and white is 1
+pub(crate) fn ntsc_signal(pixel: u16, phase: u16) -> f64 {
+    let color = pixel & 0x0F;
+    let emphasis = pixel >> 6;
+    // $xE and $xF are forced to the black level
+    let level = if color > 13 { 1 } else { ((pixel >> 4) & 3) as usize };
+
+    let mut low = SIGNAL_LEVELS[level];
+    let mut high = SIGNAL_LEVELS[4 + level];
+    if color == 0 {
+        low = high;
+    }
+    if color > 12 {
+        high = low;
+    }
+
+    let in_phase = |color: u16| (color + phase) % 12 < 6;
+    let mut signal = if in_phase(color) { high } else { low };
+    if (emphasis & 1 != 0 && in_phase(0)) || (emphasis & 2 != 0 && in_phase(4)) || (emphasis & 4 != 0 && in_phase(8)) {
+        signal *= SIGNAL_ATTENUATION;
+    }
+    (signal - BLACK) / (WHITE - BLACK)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pal_files() {
+        let base: Vec<u8> = (0..64 * 3).map(|i| i as u8).collect();
+        let palette = Palette::from_pal(&base).unwrap();
+        assert_eq!(palette.color(0x01), (3, 4, 5));
+        // red emphasis dims green and blue
+        assert_eq!(palette.color(0x01 | 1 << 6), (3, 3, 4));
+
+        let full = palette.to_pal();
+        assert_eq!(full.len(), 512 * 3);
+        assert_eq!(Palette::from_pal(&full).unwrap(), palette);
+        assert!(Palette::from_pal(&full[..100]).is_err());
+    }
+
+    #[test]
+    fn test_generated_palette() {
+        let palette = Palette::generate(&NtscSettings::new());
+        assert_eq!(palette.color(0x0F), (0, 0, 0));
+        assert_eq!(palette.color(0x30), (255, 255, 255));
+        let (r, g, b) = palette.color(0x16);
+        assert!(r > g && r > b, "$16 should be red");
+        let (r, g, b) = palette.color(0x2A);
+        assert!(g > r && g > b, "$2A should be green");
+        let (r, g, b) = palette.color(0x12);
+        assert!(b > r && b > g, "$12 should be blue");
+
+        let gray = NtscSettings::parse("saturation=0").unwrap();
+        let (r, g, b) = Palette::generate(&gray).color(0x16);
+        assert!(r == g && g == b);
+        assert!(NtscSettings::parse("tint=3").is_err());
+        assert!(Palette::from_spec("ntsc:hue=10").is_ok());
+    }
+}
@@ -0,0 +1,200 @@
+// On-screen text, drawn over the picture after filtering so it stays sharp.
+// Coordinates are NES pixels, scaled along with the filter's output.
+use super::filter::Image;
+use super::frame::Frame;
+
+const GLYPH_HEIGHT: i32 = 8;
+// 5 pixel glyphs plus a column of spacing
+const ADVANCE: i32 = 6;
+const TEXT_COLOR: [u8; 3] = [0xFF, 0xFF, 0xFF];
+const OUTLINE_COLOR: [u8; 3] = [0x00, 0x00, 0x00];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverlayText {
+    pub x: i32,
+    pub y: i32,
+    pub text: String,
+    // how many more frames it stays up
+    pub frames: u32,
+}
+
+// Printable ASCII from ' ' to '~', a row per byte with bit 4 the leftmost column
+const FONT: [[u8; 8]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // space
+    [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04, 0x00], // !
+    [0x0A, 0x0A, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // "
+    [0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A, 0x00], // #
+    [0x04, 0x0F, 0x14, 0x0E, 0x05, 0x1E, 0x04, 0x00], // $
+    [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03, 0x00], // %
+    [0x0C, 0x12, 0x14, 0x08, 0x15, 0x12, 0x0D, 0x00], // &
+    [0x04, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '
+    [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02, 0x00], // (
+    [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08, 0x00], // )
+    [0x00, 0x04, 0x15, 0x0E, 0x15, 0x04, 0x00, 0x00], // *
+    [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00, 0x00], // +
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x04, 0x08], // ,
+    [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00, 0x00], // -
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00], // .
+    [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00, 0x00], // /
+    [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E, 0x00], // 0
+    [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E, 0x00], // 1
+    [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F, 0x00], // 2
+    [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E, 0x00], // 3
+    [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02, 0x00], // 4
+    [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E, 0x00], // 5
+    [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E, 0x00], // 6
+    [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08, 0x00], // 7
+    [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E, 0x00], // 8
+    [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C, 0x00], // 9
+    [0x00, 0x00, 0x04, 0x00, 0x00, 0x04, 0x00, 0x00], // :
+    [0x00, 0x00, 0x04, 0x00, 0x00, 0x04, 0x04, 0x08], // ;
+    [0x02, 0x04, 0x08, 0x10, 0x08, 0x04, 0x02, 0x00], // <
+    [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00, 0x00], // =
+    [0x08, 0x04, 0x02, 0x01, 0x02, 0x04, 0x08, 0x00], // >
+    [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04, 0x00], // ?
+    [0x0E, 0x11, 0x01, 0x0D, 0x15, 0x15, 0x0E, 0x00], // @
+    [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11, 0x00], // A
+    [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E, 0x00], // B
+    [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E, 0x00], // C
+    [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C, 0x00], // D
+    [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F, 0x00], // E
+    [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10, 0x00], // F
+    [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F, 0x00], // G
+    [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11, 0x00], // H
+    [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E, 0x00], // I
+    [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C, 0x00], // J
+    [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11, 0x00], // K
+    [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F, 0x00], // L
+    [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11, 0x00], // M
+    [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11, 0x00], // N
+    [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E, 0x00], // O
+    [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10, 0x00], // P
+    [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D, 0x00], // Q
+    [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11, 0x00], // R
+    [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E, 0x00], // S
+    [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04, 0x00], // T
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E, 0x00], // U
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04, 0x00], // V
+    [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A, 0x00], // W
+    [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11, 0x00], // X
+    [0x11, 0x11, 0x0A, 0x04, 0x04, 0x04, 0x04, 0x00], // Y
+    [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F, 0x00], // Z
+    [0x0E, 0x08, 0x08, 0x08, 0x08, 0x08, 0x0E, 0x00], // [
+    [0x00, 0x10, 0x08, 0x04, 0x02, 0x01, 0x00, 0x00], // \
+    [0x0E, 0x02, 0x02, 0x02, 0x02, 0x02, 0x0E, 0x00], // ]
+    [0x04, 0x0A, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00], // ^
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F, 0x00], // _
+    [0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // `
+    [0x00, 0x00, 0x0E, 0x01, 0x0F, 0x11, 0x0F, 0x00], // a
+    [0x10, 0x10, 0x16, 0x19, 0x11, 0x11, 0x1E, 0x00], // b
+    [0x00, 0x00, 0x0E, 0x10, 0x10, 0x11, 0x0E, 0x00], // c
+    [0x01, 0x01, 0x0D, 0x13, 0x11, 0x11, 0x0F, 0x00], // d
+    [0x00, 0x00, 0x0E, 0x11, 0x1F, 0x10, 0x0E, 0x00], // e
+    [0x06, 0x09, 0x08, 0x1C, 0x08, 0x08, 0x08, 0x00], // f
+    [0x00, 0x00, 0x0F, 0x11, 0x11, 0x0F, 0x01, 0x0E], // g
+    [0x10, 0x10, 0x16, 0x19, 0x11, 0x11, 0x11, 0x00], // h
+    [0x04, 0x00, 0x0C, 0x04, 0x04, 0x04, 0x0E, 0x00], // i
+    [0x02, 0x00, 0x06, 0x02, 0x02, 0x02, 0x12, 0x0C], // j
+    [0x10, 0x10, 0x12, 0x14, 0x18, 0x14, 0x12, 0x00], // k
+    [0x0C, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E, 0x00], // l
+    [0x00, 0x00, 0x1A, 0x15, 0x15, 0x11, 0x11, 0x00], // m
+    [0x00, 0x00, 0x16, 0x19, 0x11, 0x11, 0x11, 0x00], // n
+    [0x00, 0x00, 0x0E, 0x11, 0x11, 0x11, 0x0E, 0x00], // o
+    [0x00, 0x00, 0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10], // p
+    [0x00, 0x00, 0x0F, 0x11, 0x11, 0x0F, 0x01, 0x01], // q
+    [0x00, 0x00, 0x16, 0x19, 0x10, 0x10, 0x10, 0x00], // r
+    [0x00, 0x00, 0x0F, 0x10, 0x0E, 0x01, 0x1E, 0x00], // s
+    [0x08, 0x08, 0x1C, 0x08, 0x08, 0x09, 0x06, 0x00], // t
+    [0x00, 0x00, 0x11, 0x11, 0x11, 0x13, 0x0D, 0x00], // u
+    [0x00, 0x00, 0x11, 0x11, 0x11, 0x0A, 0x04, 0x00], // v
+    [0x00, 0x00, 0x11, 0x11, 0x15, 0x15, 0x0A, 0x00], // w
+    [0x00, 0x00, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x00], // x
+    [0x00, 0x00, 0x11, 0x11, 0x11, 0x0F, 0x01, 0x0E], // y
+    [0x00, 0x00, 0x1F, 0x02, 0x04, 0x08, 0x1F, 0x00], // z
+    [0x02, 0x04, 0x04, 0x08, 0x04, 0x04, 0x02, 0x00], // {
+    [0x04, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04, 0x00], // |
+    [0x08, 0x04, 0x04, 0x02, 0x04, 0x04, 0x08, 0x00], // }
+    [0x00, 0x00, 0x08, 0x15, 0x02, 0x00, 0x00, 0x00], // ~
+];
+
+fn glyph(ch: char) -> &'static [u8; 8] {
+    match ch {
+        ' '..='~' => &FONT[ch as usize - 0x20],
+        _ => &FONT[(b'?' - 0x20) as usize],
+    }
+}
+
+// White with a black outline, so it reads on any background. Text running
+// off the picture is clipped, a '\n' starts a new line.
+pub fn draw_text(image: &mut Image, x: i32, y: i32, text: &str) {
+    for (line_number, line) in text.split('\n').enumerate() {
+        let top = y + line_number as i32 * (GLYPH_HEIGHT + 1);
+        for (column, ch) in line.chars().enumerate() {
+            let left = x + column as i32 * ADVANCE;
+            let rows = glyph(ch);
+            for (color, outline) in [(OUTLINE_COLOR, true), (TEXT_COLOR, false)] {
+                for (row, bits) in rows.iter().enumerate() {
+                    for bit in 0..5 {
+                        if bits & (0x10 >> bit) == 0 {
+                            continue;
+                        }
+                        let (px, py) = (left + bit, top + row as i32);
+                        if outline {
+                            for (dx, dy) in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
+                                fill(image, px + dx, py + dy, color);
+                            }
+                        } else {
+                            fill(image, px, py, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn draw_overlay(image: &mut Image, overlay: &[OverlayText]) {
+    for text in overlay {
+        draw_text(image, text.x, text.y, &text.text);
+    }
+}
+
+// Sets the block of image pixels NES pixel (x, y) ended up as
+fn fill(image: &mut Image, x: i32, y: i32, color: [u8; 3]) {
+    if x < 0 || y < 0 || x >= Frame::WIDTH as i32 || y >= Frame::HEIGHT as i32 {
+        return;
+    }
+    let scale_x = (image.width / Frame::WIDTH).max(1);
+    let scale_y = (image.height / Frame::HEIGHT).max(1);
+    for image_y in y as usize * scale_y..(y as usize + 1) * scale_y {
+        for image_x in x as usize * scale_x..(x as usize + 1) * scale_x {
+            let base = (image_y * image.width + image_x) * 3;
+            image.data[base..base + 3].copy_from_slice(&color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_draws_scaled_outlined_and_clipped() {
+        let mut image = Image::new(Frame::WIDTH * 2, Frame::HEIGHT * 2);
+        image.data.iter_mut().for_each(|byte| *byte = 0x40);
+
+        draw_text(&mut image, 10, 20, "I\n|");
+        draw_text(&mut image, 250, 236, "W\u{e9}");
+
+        // the top bar of 'I' runs from x 11 to 13 on row 20, at 2x
+        assert_eq!(image.pixel(22, 40), (0xFF, 0xFF, 0xFF));
+        assert_eq!(image.pixel(27, 41), (0xFF, 0xFF, 0xFF));
+        assert_eq!(image.pixel(20, 40), (0x00, 0x00, 0x00));
+        assert_eq!(image.pixel(22, 38), (0x00, 0x00, 0x00));
+        assert_eq!(image.pixel(18, 40), (0x40, 0x40, 0x40));
+        // the second line starts 9 rows down
+        assert_eq!(image.pixel(24, 58), (0xFF, 0xFF, 0xFF));
+        assert_eq!(image.pixel(24, 56), (0x00, 0x00, 0x00));
+        assert_eq!(glyph('\u{e9}'), glyph('?'));
+    }
+}
@@ -0,0 +1,172 @@
+use crate::cpu::{CpuFlags, CPU};
+
+const MAGIC: &[u8; 6] = b"PABNES";
+const VERSION: u8 = 5;
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: vec![] }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Length-prefixed, so readers can check it matches what they expect
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u64(bytes.len() as u64);
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> StateWriter {
+        StateWriter::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < len {
+            return Err("Save state is truncated".to_string());
+        }
+        let bytes = &self.data[self.pos..(self.pos + len)];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, String> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn read_bytes_into(&mut self, out: &mut [u8]) -> Result<(), String> {
+        let len = self.read_u64()? as usize;
+        if len != out.len() {
+            return Err(format!("Save state block has {} bytes, expected {}", len, out.len()));
+        }
+        out.copy_from_slice(self.take(len)?);
+        Ok(())
+    }
+}
+
+pub fn save(cpu: &CPU) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    w.data.extend_from_slice(MAGIC);
+    w.write_u8(VERSION);
+
+    w.write_u8(cpu.reg_a);
+    w.write_u8(cpu.reg_x);
+    w.write_u8(cpu.reg_y);
+    w.write_u8(cpu.status.bits());
+    w.write_u16(cpu.pc);
+    w.write_u8(cpu.sp);
+    cpu.bus.save_state(&mut w);
+
+    w.finish()
+}
+
+// A state that fails to load half way leaves `cpu` exactly as it was
+pub fn load(cpu: &mut CPU, data: &[u8]) -> Result<(), String> {
+    let backup = save(cpu);
+    load_unchecked(cpu, data).inspect_err(|_| {
+        load_unchecked(cpu, &backup).expect("Failed to restore state after a bad load");
+    })
+}
+
+fn load_unchecked(cpu: &mut CPU, data: &[u8]) -> Result<(), String> {
+    let mut r = StateReader::new(data);
+    if r.take(MAGIC.len())? != MAGIC {
+        return Err("Not a pabnes save state".to_string());
+    }
+    let version = r.read_u8()?;
+    if version != VERSION {
+        return Err(format!("Unsupported save state version {}", version));
+    }
+
+    cpu.reg_a = r.read_u8()?;
+    cpu.reg_x = r.read_u8()?;
+    cpu.reg_y = r.read_u8()?;
+    cpu.status = CpuFlags::from_bits_truncate(r.read_u8()?);
+    cpu.pc = r.read_u16()?;
+    cpu.sp = r.read_u8()?;
+    cpu.bus.load_state(&mut r)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_round_trip() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xA9, 0x05, 0x85, 0x10, 0x00]);
+        cpu.reset();
+        cpu.run().unwrap();
+        let state = save(&cpu);
+
+        let mut other = CPU::new();
+        load(&mut other, &state).unwrap();
+
+        assert_eq!(other.reg_a, 5);
+        assert_eq!(other.pc, cpu.pc);
+        assert_eq!(other.mem_read(0x10), 5);
+        assert_eq!(other.bus.cycles, cpu.bus.cycles);
+    }
+
+    #[test]
+    fn test_rejects_bad_state() {
+        let mut cpu = CPU::new();
+        cpu.reg_a = 0x42;
+        let state = save(&cpu);
+        cpu.reg_a = 0x43;
+
+        assert!(load(&mut cpu, b"garbage").is_err());
+        assert!(load(&mut cpu, &state[..state.len() - 1]).is_err());
+        assert_eq!(cpu.reg_a, 0x43);
+    }
+}
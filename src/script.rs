@@ -0,0 +1,460 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use mlua::{AnyUserData, Function, Lua, Table, Value, Variadic};
+use mlua::thread::ThreadStatus;
+
+use crate::cpu::{CpuFlags, Mem, CPU};
+use crate::joypad::{Joypad, JoypadButton};
+pub use crate::render::text::OverlayText;
+use crate::savestate;
+
+// Registry slot holding the CPU while the host is inside a callback
+const CPU_KEY: &str = "pabnes_cpu";
+const MESSAGE_FRAMES: u32 = 180;
+
+const BUTTON_NAMES: [(&str, JoypadButton); 8] = [
+    ("A", JoypadButton::BUTTON_A),
+    ("B", JoypadButton::BUTTON_B),
+    ("select", JoypadButton::SELECT),
+    ("start", JoypadButton::START),
+    ("up", JoypadButton::UP),
+    ("down", JoypadButton::DOWN),
+    ("left", JoypadButton::LEFT),
+    ("right", JoypadButton::RIGHT),
+];
+
+#[derive(Default)]
+struct Hooks {
+    before_frame: Option<Function>,
+    after_frame: Option<Function>,
+    instruction: Option<Function>,
+    execute: HashMap<u16, Function>,
+}
+
+struct StateSlot(Option<Vec<u8>>);
+
+pub struct ScriptHost {
+    lua: Lua,
+    main: mlua::Thread,
+    hooks: Rc<RefCell<Hooks>>,
+    overlay: Rc<RefCell<Vec<OverlayText>>>,
+    last_frame: Option<u64>,
+}
+
+impl ScriptHost {
+    pub fn new(source: &str, name: &str) -> Result<ScriptHost, String> {
+        let lua = Lua::new();
+        let hooks = Rc::new(RefCell::new(Hooks::default()));
+        let overlay = Rc::new(RefCell::new(vec![]));
+
+        register_api(&lua, &hooks, &overlay).map_err(|err| err.to_string())?;
+        let chunk = lua.load(source).set_name(name).into_function().map_err(|err| err.to_string())?;
+        let main = lua.create_thread(chunk).map_err(|err| err.to_string())?;
+
+        Ok(ScriptHost {
+            lua,
+            main,
+            hooks,
+            overlay,
+            last_frame: None,
+        })
+    }
+
+    pub fn from_file(path: &str) -> Result<ScriptHost, String> {
+        let source = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        ScriptHost::new(&source, path)
+    }
+
+    pub fn overlay(&self) -> Vec<OverlayText> {
+        self.overlay.borrow().clone()
+    }
+
+    // Call before every instruction, e.g. from run_with_callback
+    pub fn hook(&mut self, cpu: &mut CPU) -> Result<(), String> {
+        let frame = cpu.bus.frame_count();
+        let new_frame = self.last_frame != Some(frame);
+        let (instruction, execute) = {
+            let hooks = self.hooks.borrow();
+            (hooks.instruction.clone(), hooks.execute.get(&cpu.pc).cloned())
+        };
+        if !new_frame && instruction.is_none() && execute.is_none() {
+            return Ok(());
+        }
+
+        let pc = cpu.pc;
+        let first_frame = self.last_frame.is_none();
+        self.last_frame = Some(frame);
+
+        let lua = &self.lua;
+        let main = &self.main;
+        let hooks = &self.hooks;
+        let overlay = &self.overlay;
+        lua.scope(|scope| {
+            let cpu = scope.create_any_userdata_ref_mut(cpu)?;
+            lua.set_named_registry_value(CPU_KEY, &cpu)?;
+
+            if new_frame {
+                overlay.borrow_mut().retain_mut(|text| {
+                    text.frames -= 1;
+                    text.frames > 0
+                });
+
+                if !first_frame {
+                    let after_frame = hooks.borrow().after_frame.clone();
+                    if let Some(after_frame) = after_frame {
+                        after_frame.call::<()>(())?;
+                    }
+                }
+                if main.status() == ThreadStatus::Resumable {
+                    main.resume::<()>(())?;
+                }
+                let before_frame = hooks.borrow().before_frame.clone();
+                if let Some(before_frame) = before_frame {
+                    before_frame.call::<()>(())?;
+                }
+            }
+
+            if let Some(instruction) = instruction {
+                instruction.call::<()>(pc)?;
+            }
+            if let Some(execute) = execute {
+                execute.call::<()>(pc)?;
+            }
+
+            lua.unset_named_registry_value(CPU_KEY)
+        })
+        .map_err(|err| err.to_string())
+    }
+}
+
+fn with_cpu<R>(lua: &Lua, f: impl FnOnce(&mut CPU) -> mlua::Result<R>) -> mlua::Result<R> {
+    let cpu: Option<AnyUserData> = lua.named_registry_value(CPU_KEY)?;
+    let cpu = cpu.ok_or_else(|| mlua::Error::runtime("emulator state is only available from inside a callback"))?;
+    cpu.borrow_mut_scoped::<CPU, _>(f)?
+}
+
+fn joypad(cpu: &mut CPU, player: u8) -> mlua::Result<&mut Joypad> {
+    match player {
+        1 => Ok(&mut cpu.bus.joypad1),
+        2 => Ok(&mut cpu.bus.joypad2),
+        _ => Err(mlua::Error::runtime(format!("invalid player {}", player))),
+    }
+}
+
+fn register_api(lua: &Lua, hooks: &Rc<RefCell<Hooks>>, overlay: &Rc<RefCell<Vec<OverlayText>>>) -> mlua::Result<()> {
+    let globals = lua.globals();
+
+    /* memory.* */
+    let memory = lua.create_table()?;
+    memory.set("readbyte", lua.create_function(|lua, addr: u16| with_cpu(lua, |cpu| Ok(cpu.bus.peek(addr))))?)?;
+    memory.set(
+        "readbytesigned",
+        lua.create_function(|lua, addr: u16| with_cpu(lua, |cpu| Ok(cpu.bus.peek(addr) as i8)))?,
+    )?;
+    memory.set(
+        "readword",
+        lua.create_function(|lua, (lo, hi): (u16, Option<u16>)| {
+            with_cpu(lua, |cpu| {
+                let hi = hi.unwrap_or_else(|| lo.wrapping_add(1));
+                Ok((cpu.bus.peek(hi) as u16) << 8 | cpu.bus.peek(lo) as u16)
+            })
+        })?,
+    )?;
+    memory.set(
+        "writebyte",
+        lua.create_function(|lua, (addr, data): (u16, i64)| {
+            with_cpu(lua, |cpu| {
+                cpu.mem_write(addr, data as u8);
+                Ok(())
+            })
+        })?,
+    )?;
+    memory.set(
+        "getregister",
+        lua.create_function(|lua, name: String| {
+            with_cpu(lua, |cpu| match name.to_lowercase().as_str() {
+                "a" => Ok(cpu.reg_a as u16),
+                "x" => Ok(cpu.reg_x as u16),
+                "y" => Ok(cpu.reg_y as u16),
+                "s" => Ok(cpu.sp as u16),
+                "p" => Ok(cpu.status.bits() as u16),
+                "pc" => Ok(cpu.pc),
+                _ => Err(mlua::Error::runtime(format!("unknown register '{}'", name))),
+            })
+        })?,
+    )?;
+    memory.set(
+        "setregister",
+        lua.create_function(|lua, (name, value): (String, i64)| {
+            with_cpu(lua, |cpu| {
+                match name.to_lowercase().as_str() {
+                    "a" => cpu.reg_a = value as u8,
+                    "x" => cpu.reg_x = value as u8,
+                    "y" => cpu.reg_y = value as u8,
+                    "s" => cpu.sp = value as u8,
+                    "p" => cpu.status = CpuFlags::from_bits_truncate(value as u8),
+                    "pc" => cpu.pc = value as u16,
+                    _ => return Err(mlua::Error::runtime(format!("unknown register '{}'", name))),
+                }
+                Ok(())
+            })
+        })?,
+    )?;
+    let execute_hooks = hooks.clone();
+    memory.set(
+        "registerexecute",
+        lua.create_function(move |_, (addr, args): (u16, Variadic<Value>)| {
+            // FCEUX allows an optional size between the address and the callback
+            let callback = match args.last() {
+                Some(Value::Function(callback)) => Some(callback.clone()),
+                _ => None,
+            };
+            let size = match (args.len(), args.first()) {
+                (2, Some(Value::Integer(size))) => *size as u16,
+                _ => 1,
+            };
+            let mut hooks = execute_hooks.borrow_mut();
+            for addr in addr..addr.saturating_add(size) {
+                match &callback {
+                    Some(callback) => hooks.execute.insert(addr, callback.clone()),
+                    None => hooks.execute.remove(&addr),
+                };
+            }
+            Ok(())
+        })?,
+    )?;
+    globals.set("memory", memory)?;
+
+    /* emu.* */
+    let emu = lua.create_table()?;
+    emu.set("framecount", lua.create_function(|lua, ()| with_cpu(lua, |cpu| Ok(cpu.bus.frame_count())))?)?;
+    emu.set(
+        "softreset",
+        lua.create_function(|lua, ()| {
+            with_cpu(lua, |cpu| {
+                cpu.reset();
+                Ok(())
+            })
+        })?,
+    )?;
+    let before_hooks = hooks.clone();
+    emu.set(
+        "registerbefore",
+        lua.create_function(move |_, callback: Option<Function>| {
+            before_hooks.borrow_mut().before_frame = callback;
+            Ok(())
+        })?,
+    )?;
+    let after_hooks = hooks.clone();
+    emu.set(
+        "registerafter",
+        lua.create_function(move |_, callback: Option<Function>| {
+            after_hooks.borrow_mut().after_frame = callback;
+            Ok(())
+        })?,
+    )?;
+    // Not in FCEUX: runs before every single instruction, which is slow
+    let instruction_hooks = hooks.clone();
+    emu.set(
+        "registerinstruction",
+        lua.create_function(move |_, callback: Option<Function>| {
+            instruction_hooks.borrow_mut().instruction = callback;
+            Ok(())
+        })?,
+    )?;
+    emu.set(
+        "print",
+        lua.create_function(|_, values: Variadic<Value>| {
+            let values = values.iter().map(|value| value.to_string()).collect::<mlua::Result<Vec<_>>>()?;
+            println!("{}", values.join("\t"));
+            Ok(())
+        })?,
+    )?;
+    let message_overlay = overlay.clone();
+    emu.set(
+        "message",
+        lua.create_function(move |_, text: String| {
+            message_overlay.borrow_mut().push(OverlayText {
+                x: 8,
+                y: 216,
+                text,
+                frames: MESSAGE_FRAMES,
+            });
+            Ok(())
+        })?,
+    )?;
+    globals.set("emu", emu)?;
+    lua.load("function emu.frameadvance() coroutine.yield() end").exec()?;
+
+    /* joypad.* */
+    let joypad_table = lua.create_table()?;
+    joypad_table.set(
+        "set",
+        lua.create_function(|lua, (player, buttons): (u8, Table)| {
+            with_cpu(lua, |cpu| {
+                let joypad = joypad(cpu, player)?;
+                for (name, button) in BUTTON_NAMES.iter() {
+                    if let Some(pressed) = buttons.get::<Option<bool>>(*name)? {
+                        joypad.set_button_pressed_status(*button, pressed);
+                    }
+                }
+                Ok(())
+            })
+        })?,
+    )?;
+    let get = lua.create_function(|lua, player: u8| {
+        with_cpu(lua, |cpu| {
+            let status = joypad(cpu, player)?.button_status;
+            let buttons = lua.create_table()?;
+            for (name, button) in BUTTON_NAMES.iter() {
+                buttons.set(*name, status.contains(*button))?;
+            }
+            Ok(buttons)
+        })
+    })?;
+    joypad_table.set("get", get.clone())?;
+    joypad_table.set("read", get)?;
+    globals.set("joypad", joypad_table)?;
+
+    /* savestate.* */
+    let savestate_table = lua.create_table()?;
+    savestate_table.set("create", lua.create_function(|lua, ()| lua.create_any_userdata(StateSlot(None)))?)?;
+    savestate_table.set(
+        "save",
+        lua.create_function(|lua, slot: AnyUserData| {
+            with_cpu(lua, |cpu| {
+                slot.borrow_mut::<StateSlot>()?.0 = Some(savestate::save(cpu));
+                Ok(())
+            })
+        })?,
+    )?;
+    savestate_table.set(
+        "load",
+        lua.create_function(|lua, slot: AnyUserData| {
+            with_cpu(lua, |cpu| {
+                let slot = slot.borrow::<StateSlot>()?;
+                let data = slot.0.as_ref().ok_or_else(|| mlua::Error::runtime("save state is empty"))?;
+                savestate::load(cpu, data).map_err(mlua::Error::runtime)
+            })
+        })?,
+    )?;
+    globals.set("savestate", savestate_table)?;
+
+    /* gui.* */
+    let gui = lua.create_table()?;
+    let text_overlay = overlay.clone();
+    let text = lua.create_function(move |_, (x, y, text): (i32, i32, String)| {
+        text_overlay.borrow_mut().push(OverlayText { x, y, text, frames: 1 });
+        Ok(())
+    })?;
+    gui.set("text", text.clone())?;
+    gui.set("drawtext", text)?;
+    globals.set("gui", gui)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // LDA $00; BEQ -4; BRK - spins until $00 becomes non-zero
+    fn looping_cpu() -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xA5, 0x00, 0xF0, 0xFC, 0x00]);
+        cpu.reset();
+        cpu
+    }
+
+    fn run_until<F>(host: &mut ScriptHost, cpu: &mut CPU, mut done: F)
+    where F: FnMut(&CPU) -> bool,
+    {
+        cpu.run_with_callback(|cpu| {
+            host.hook(cpu).unwrap();
+            if done(cpu) {
+                cpu.mem_write(0x00, 1);
+            }
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_memory_and_frameadvance() {
+        let mut cpu = looping_cpu();
+        let mut host = ScriptHost::new(
+            r#"
+            memory.writebyte(0x10, 1)
+            while true do
+                emu.frameadvance()
+                memory.writebyte(0x10, memory.readbyte(0x10) + 1)
+                memory.setregister("x", emu.framecount())
+            end
+            "#,
+            "test",
+        )
+        .unwrap();
+
+        run_until(&mut host, &mut cpu, |cpu| cpu.bus.frame_count() == 3);
+
+        assert_eq!(cpu.mem_read(0x10), 4);
+        assert_eq!(cpu.reg_x, 3);
+    }
+
+    #[test]
+    fn test_execute_hook_and_joypad() {
+        let mut cpu = looping_cpu();
+        let mut host = ScriptHost::new(
+            r#"
+            hits = 0
+            memory.registerexecute(0x8002, function() hits = hits + 1 end)
+            joypad.set(1, {A = true, start = true})
+            gui.text(10, 20, "hello")
+            "#,
+            "test",
+        )
+        .unwrap();
+
+        let mut steps = 0;
+        run_until(&mut host, &mut cpu, |_| {
+            steps += 1;
+            steps == 10
+        });
+
+        // five passes while $00 is zero plus the final one that falls through to BRK
+        let hits: u32 = host.lua.globals().get("hits").unwrap();
+        assert_eq!(hits, 6);
+        assert_eq!(cpu.bus.joypad1.button_status, JoypadButton::BUTTON_A | JoypadButton::START);
+        assert_eq!(host.overlay()[0].text, "hello");
+    }
+
+    #[test]
+    fn test_savestate_from_script() {
+        let mut cpu = looping_cpu();
+        let mut host = ScriptHost::new(
+            r#"
+            local state = savestate.create()
+            memory.writebyte(0x20, 7)
+            savestate.save(state)
+            memory.writebyte(0x20, 9)
+            savestate.load(state)
+            "#,
+            "test",
+        )
+        .unwrap();
+
+        host.hook(&mut cpu).unwrap();
+
+        assert_eq!(cpu.mem_read(0x20), 7);
+    }
+
+    #[test]
+    fn test_errors_are_reported() {
+        let mut cpu = looping_cpu();
+        let mut host = ScriptHost::new("memory.getregister('q')", "test").unwrap();
+
+        assert!(host.hook(&mut cpu).unwrap_err().contains("unknown register"));
+        assert!(ScriptHost::new("this is not lua", "test").is_err());
+    }
+}
@@ -0,0 +1,127 @@
+// Accuracy test ROMs by blargg and others report through PRG RAM: $6000 is
+// the status, $6001-$6003 hold DE B0 61 once the data is valid, and the
+// text they print starts at $6004, zero terminated.
+use crate::cpu::Mem;
+use crate::emulator::Emulator;
+
+const STATUS: u16 = 0x6000;
+const SIGNATURE_ADDR: u16 = 0x6001;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const TEXT: u16 = 0x6004;
+const TEXT_END: u16 = 0x7FFF;
+const RUNNING: u8 = 0x80;
+const NEEDS_RESET: u8 = 0x81;
+// the ROMs want at least 100 ms between asking for a reset and getting it
+const RESET_DELAY_FRAMES: u64 = 10;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TestStatus {
+    // no signature yet
+    NotStarted,
+    Running,
+    NeedsReset,
+    Done(u8),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TestResult {
+    // 0 is a pass, anything else the number of the failing test
+    pub code: u8,
+    pub text: String,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.code == 0
+    }
+}
+
+pub fn status(emulator: &mut Emulator) -> TestStatus {
+    let cpu = &mut emulator.cpu;
+    if cpu.bus.cartridge.is_none() {
+        return TestStatus::NotStarted;
+    }
+    let signature: Vec<u8> = (0..3).map(|i| cpu.mem_read(SIGNATURE_ADDR + i)).collect();
+    if signature != SIGNATURE {
+        return TestStatus::NotStarted;
+    }
+    match cpu.mem_read(STATUS) {
+        RUNNING => TestStatus::Running,
+        NEEDS_RESET => TestStatus::NeedsReset,
+        code => TestStatus::Done(code),
+    }
+}
+
+pub fn text(emulator: &mut Emulator) -> String {
+    let mut bytes = Vec::new();
+    for addr in TEXT..=TEXT_END {
+        match emulator.cpu.mem_read(addr) {
+            0 => break,
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// Runs a loaded test ROM until it reports a result, resetting it when it
+// asks to. Fails if the CPU halts or faults, or there is no result after max_frames.
+pub fn run(emulator: &mut Emulator, max_frames: u64) -> Result<TestResult, String> {
+    let mut reset_requested = None;
+    for frame in 0..max_frames {
+        if !emulator.step_frame().map_err(|err| err.to_string())? {
+            return Err(format!("CPU halted at ${:04X}", emulator.cpu.pc));
+        }
+        // nothing plays them, and they would pile up until the result
+        emulator.take_audio_samples();
+        match status(emulator) {
+            TestStatus::Done(code) => return Ok(TestResult { code, text: text(emulator) }),
+            TestStatus::NeedsReset => {
+                let requested = *reset_requested.get_or_insert(frame);
+                if frame - requested >= RESET_DELAY_FRAMES {
+                    emulator.reset();
+                    reset_requested = None;
+                }
+            }
+            _ => reset_requested = None,
+        }
+    }
+    let text = text(emulator);
+    Err(format!("No result after {} frames: {}", max_frames, text.trim()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::nrom;
+
+    // NROM that writes the status bytes in $6000-$6004 and loops
+    fn status_rom(status: u8, text: u8) -> Vec<u8> {
+        let mut code = Vec::new();
+        for (i, data) in [status, 0xDE, 0xB0, 0x61, text].iter().enumerate() {
+            // LDA #data; STA $6000+i
+            code.extend([0xA9, *data, 0x8D, i as u8, 0x60]);
+        }
+        // JMP to itself
+        let hang = 0x8000 + code.len() as u16;
+        code.extend([0x4C, hang as u8, (hang >> 8) as u8]);
+        nrom(&code, &[])
+    }
+
+    #[test]
+    fn test_reports_result() {
+        let mut emu = Emulator::new();
+        emu.load_rom(&status_rom(0x02, b'F')).unwrap();
+        assert_eq!(status(&mut emu), TestStatus::NotStarted);
+
+        let result = run(&mut emu, 5).unwrap();
+        assert_eq!(result, TestResult { code: 2, text: "F".to_string() });
+        assert!(!result.passed());
+
+        let mut emu = Emulator::new();
+        emu.load_rom(&status_rom(RUNNING, 0)).unwrap();
+        assert!(run(&mut emu, 5).unwrap_err().starts_with("No result after 5 frames"));
+        assert_eq!(status(&mut emu), TestStatus::Running);
+        // the sound of every frame was dropped as it ran
+        assert!(emu.take_audio_samples().is_empty());
+    }
+}
@@ -0,0 +1,324 @@
+// Klaus Dormann's 6502 functional test and Bruce Clark's decimal mode test.
+// Both signal the result by looping on a JMP or branch to itself: reaching
+// the success trap means every test passed, any other trap is a failure.
+use pabnes::cpu::CpuVariant;
+use pabnes::{Mem, CPU};
+
+const FUNCTIONAL_TEST: &[u8] = include_bytes!("roms/6502_functional_test.bin");
+const FUNCTIONAL_START: u16 = 0x0400;
+const FUNCTIONAL_SUCCESS: u16 = 0x331C;
+// the number of the test running, kept in data RAM by the functional test
+const FUNCTIONAL_TEST_CASE: u16 = 0x0200;
+
+const DECIMAL_START: u16 = 0x0200;
+const DECIMAL_SUCCESS: u16 = 0x0233;
+// zero page variables of the decimal test, see the listing below
+const DECIMAL_N1: u16 = 0x00;
+const DECIMAL_N2: u16 = 0x01;
+
+const MAX_STEPS: u64 = 200_000_000;
+
+// Runs until the program loops on itself and returns the trap address
+fn run_until_trap(cpu: &mut CPU) -> u16 {
+    for _ in 0..MAX_STEPS {
+        let pc = cpu.pc;
+        if cpu.step().unwrap().halted || cpu.pc == pc {
+            return pc;
+        }
+    }
+    panic!("no trap after {} instructions, pc at ${:04X}", MAX_STEPS, cpu.pc);
+}
+
+fn run_functional_test(variant: CpuVariant) {
+    let mut cpu = CPU::new();
+    cpu.variant = variant;
+    cpu.bus.load(0x0000, FUNCTIONAL_TEST);
+    cpu.pc = FUNCTIONAL_START;
+
+    let trap = run_until_trap(&mut cpu);
+    if trap != FUNCTIONAL_SUCCESS {
+        panic!(
+            "{} trapped at ${:04X} in test ${:02X}",
+            variant.name(),
+            trap,
+            cpu.mem_read(FUNCTIONAL_TEST_CASE)
+        );
+    }
+}
+
+// Fails with the trap address and the N1, N2 and carry being tested
+fn run_decimal_test(variant: CpuVariant) -> Result<(), String> {
+    let mut cpu = CPU::new();
+    cpu.variant = variant;
+    cpu.bus.load(DECIMAL_START, DECIMAL_TEST);
+    cpu.pc = DECIMAL_START;
+
+    let trap = run_until_trap(&mut cpu);
+    if trap != DECIMAL_SUCCESS {
+        return Err(format!(
+            "{} trapped at ${:04X}: ${:02X} and ${:02X} with carry {}",
+            variant.name(),
+            trap,
+            cpu.mem_read(DECIMAL_N1),
+            cpu.mem_read(DECIMAL_N2),
+            cpu.reg_y
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_functional_nmos() {
+    run_functional_test(CpuVariant::Nmos6502);
+}
+
+#[test]
+fn test_decimal_nmos() {
+    run_decimal_test(CpuVariant::Nmos6502).unwrap();
+}
+
+#[test]
+fn test_decimal_65c02() {
+    run_decimal_test(CpuVariant::Cmos65C02).unwrap();
+}
+
+#[test]
+fn test_decimal_2a03_fails() {
+    // the test has to notice the 2A03 ignoring the D flag
+    assert!(run_decimal_test(CpuVariant::Ricoh2A03).is_err());
+}
+
+// Bruce Clark's test from the 6502.org decimal mode tutorial, checking ADC and
+// SBC against a binary computation of the result for every pair of operands
+// and both carry values. It predicts the flags the NMOS 6502 or the 65C02
+// produce, chosen by how the CPU running it sets Z on $99 + 1.
+//
+// Zero page: N1 $00, N2 $01, N1L $02, N1H $03, N2L $04, N2H $05-$06, DA $07,
+// DNVZC $08, HA $09, HNVZC $0A, AR $0B, NF $0C, VF $0D, ZF $0E, CF $0F,
+// ERROR $10, ADDVEC $11-$12, SUBVEC $13-$14
+#[rustfmt::skip]
+const DECIMAL_TEST: &[u8] = &[
+    // pick the predictions for the CPU running the test
+    0xF8,              // START: SED
+    0x38,              // SEC
+    0xA9, 0x99,        // LDA #$99
+    0x69, 0x00,        // ADC #$00
+    0xD8,              // CLD
+    // $99 + 1 is 00 on both, but only the 65C02 sets Z
+    0xF0, 0x13,        // BEQ CMOS
+    0xA9, 0x49,        // LDA #<A6502
+    0x85, 0x11,        // STA ADDVEC
+    0xA9, 0x03,        // LDA #>A6502
+    0x85, 0x12,        // STA ADDVEC+1
+    0xA9, 0x52,        // LDA #<S6502
+    0x85, 0x13,        // STA SUBVEC
+    0xA9, 0x03,        // LDA #>S6502
+    0x85, 0x14,        // STA SUBVEC+1
+    0x4C, 0x2C, 0x02,  // JMP RUN
+    0xA9, 0x60,        // CMOS: LDA #<A65C02
+    0x85, 0x11,        // STA ADDVEC
+    0xA9, 0x03,        // LDA #>A65C02
+    0x85, 0x12,        // STA ADDVEC+1
+    0xA9, 0x69,        // LDA #<S65C02
+    0x85, 0x13,        // STA SUBVEC
+    0xA9, 0x03,        // LDA #>S65C02
+    0x85, 0x14,        // STA SUBVEC+1
+    0x20, 0x3F, 0x02,  // RUN: JSR TEST
+    0xA5, 0x10,        // LDA ERROR
+    0xD0, 0x03,        // BNE FAIL
+    0x4C, 0x33, 0x02,  // PASS: JMP PASS
+    0x4C, 0x36, 0x02,  // FAIL: JMP FAIL
+    0x6C, 0x11, 0x00,  // PREDADD: JMP (ADDVEC)
+    0x6C, 0x13, 0x00,  // PREDSUB: JMP (SUBVEC)
+
+    // loop through both carry flag values
+    0xA0, 0x01,        // TEST: LDY #1
+    0x84, 0x10,        // STY ERROR
+    0xA9, 0x00,        // LDA #0
+    0x85, 0x00,        // STA N1
+    0x85, 0x01,        // STA N2
+    0xA5, 0x01,        // LOOP1: LDA N2
+    0x29, 0x0F,        // AND #$0F
+    0x85, 0x04,        // STA N2L
+    0xA5, 0x01,        // LDA N2
+    0x29, 0xF0,        // AND #$F0
+    0x85, 0x05,        // STA N2H
+    0x09, 0x0F,        // ORA #$0F
+    0x85, 0x06,        // STA N2H+1
+    0xA5, 0x00,        // LOOP2: LDA N1
+    0x29, 0x0F,        // AND #$0F
+    0x85, 0x02,        // STA N1L
+    0xA5, 0x00,        // LDA N1
+    0x29, 0xF0,        // AND #$F0
+    0x85, 0x03,        // STA N1H
+    0x20, 0x8B, 0x02,  // JSR ADD
+    0x20, 0x39, 0x02,  // JSR PREDADD
+    0x20, 0x24, 0x03,  // JSR COMPARE
+    0xD0, 0x1A,        // BNE DONE
+    0x20, 0xCF, 0x02,  // JSR SUB
+    0x20, 0x3C, 0x02,  // JSR PREDSUB
+    0x20, 0x24, 0x03,  // JSR COMPARE
+    0xD0, 0x0F,        // BNE DONE
+    0xE6, 0x00,        // INC N1
+    0xD0, 0xDA,        // BNE LOOP2
+    0xE6, 0x01,        // INC N2
+    0xD0, 0xC6,        // BNE LOOP1
+    0x88,              // DEY
+    0x10, 0xC3,        // BPL LOOP1
+    0xA9, 0x00,        // LDA #0
+    0x85, 0x10,        // STA ERROR
+    0x60,              // DONE: RTS
+
+    // N1 + N2 in decimal and binary mode, and the predicted result
+    0xF8,              // ADD: SED
+    0xC0, 0x01,        // CPY #1
+    0xA5, 0x00,        // LDA N1
+    0x65, 0x01,        // ADC N2
+    0x85, 0x07,        // STA DA
+    0x08,              // PHP
+    0x68,              // PLA
+    0x85, 0x08,        // STA DNVZC
+    0xD8,              // CLD
+    0xC0, 0x01,        // CPY #1
+    0xA5, 0x00,        // LDA N1
+    0x65, 0x01,        // ADC N2
+    0x85, 0x09,        // STA HA
+    0x08,              // PHP
+    0x68,              // PLA
+    0x85, 0x0A,        // STA HNVZC
+    0xC0, 0x01,        // CPY #1
+    0xA5, 0x02,        // LDA N1L
+    0x65, 0x04,        // ADC N2L
+    0xC9, 0x0A,        // CMP #$0A
+    0xA2, 0x00,        // LDX #0
+    0x90, 0x06,        // BCC A1
+    0xE8,              // INX
+    0x69, 0x05,        // ADC #5
+    0x29, 0x0F,        // AND #$0F
+    0x38,              // SEC
+    0x05, 0x03,        // A1: ORA N1H
+    0x75, 0x05,        // ADC N2H,X
+    0x08,              // PHP
+    0xB0, 0x04,        // BCS A2
+    0xC9, 0xA0,        // CMP #$A0
+    0x90, 0x03,        // BCC A3
+    0x69, 0x5F,        // A2: ADC #$5F
+    0x38,              // SEC
+    0x85, 0x0B,        // A3: STA AR
+    0x08,              // PHP
+    0x68,              // PLA
+    0x85, 0x0F,        // STA CF
+    0x68,              // PLA
+    0x85, 0x0D,        // STA VF
+    0x60,              // RTS
+
+    // N1 - N2 in decimal and binary mode
+    0xF8,              // SUB: SED
+    0xC0, 0x01,        // CPY #1
+    0xA5, 0x00,        // LDA N1
+    0xE5, 0x01,        // SBC N2
+    0x85, 0x07,        // STA DA
+    0x08,              // PHP
+    0x68,              // PLA
+    0x85, 0x08,        // STA DNVZC
+    0xD8,              // CLD
+    0xC0, 0x01,        // CPY #1
+    0xA5, 0x00,        // LDA N1
+    0xE5, 0x01,        // SBC N2
+    0x85, 0x09,        // STA HA
+    0x08,              // PHP
+    0x68,              // PLA
+    0x85, 0x0A,        // STA HNVZC
+    0x60,              // RTS
+
+    // predicted SBC result of the 6502
+    0xC0, 0x01,        // SUB1: CPY #1
+    0xA5, 0x02,        // LDA N1L
+    0xE5, 0x04,        // SBC N2L
+    0xA2, 0x00,        // LDX #0
+    0xB0, 0x06,        // BCS S11
+    0xE8,              // INX
+    0xE9, 0x05,        // SBC #5
+    0x29, 0x0F,        // AND #$0F
+    0x18,              // CLC
+    0x05, 0x03,        // S11: ORA N1H
+    0xF5, 0x05,        // SBC N2H,X
+    0xB0, 0x02,        // BCS S12
+    0xE9, 0x5F,        // SBC #$5F
+    0x85, 0x0B,        // S12: STA AR
+    0x60,              // RTS
+
+    // predicted SBC result of the 65C02
+    0xC0, 0x01,        // SUB2: CPY #1
+    0xA5, 0x02,        // LDA N1L
+    0xE5, 0x04,        // SBC N2L
+    0xA2, 0x00,        // LDX #0
+    0xB0, 0x04,        // BCS S21
+    0xE8,              // INX
+    0x29, 0x0F,        // AND #$0F
+    0x18,              // CLC
+    0x05, 0x03,        // S21: ORA N1H
+    0xF5, 0x05,        // SBC N2H,X
+    0xB0, 0x02,        // BCS S22
+    0xE9, 0x5F,        // SBC #$5F
+    0xE0, 0x00,        // S22: CPX #0
+    0xF0, 0x02,        // BEQ S23
+    0xE9, 0x06,        // SBC #6
+    0x85, 0x0B,        // S23: STA AR
+    0x60,              // RTS
+
+    // Z set when A, N, V, Z and C match the predictions
+    0xA5, 0x07,        // COMPARE: LDA DA
+    0xC5, 0x0B,        // CMP AR
+    0xD0, 0x1E,        // BNE C1
+    0xA5, 0x08,        // LDA DNVZC
+    0x45, 0x0C,        // EOR NF
+    0x29, 0x80,        // AND #$80
+    0xD0, 0x16,        // BNE C1
+    0xA5, 0x08,        // LDA DNVZC
+    0x45, 0x0D,        // EOR VF
+    0x29, 0x40,        // AND #$40
+    0xD0, 0x0E,        // BNE C1
+    0xA5, 0x08,        // LDA DNVZC
+    0x45, 0x0E,        // EOR ZF
+    0x29, 0x02,        // AND #2
+    0xD0, 0x06,        // BNE C1
+    0xA5, 0x08,        // LDA DNVZC
+    0x45, 0x0F,        // EOR CF
+    0x29, 0x01,        // AND #1
+    0x60,              // C1: RTS
+
+    // flag predictions of the 6502
+    0xA5, 0x0D,        // A6502: LDA VF
+    0x85, 0x0C,        // STA NF
+    0xA5, 0x0A,        // LDA HNVZC
+    0x85, 0x0E,        // STA ZF
+    0x60,              // RTS
+
+    0x20, 0xEA, 0x02,  // S6502: JSR SUB1
+    0xA5, 0x0A,        // LDA HNVZC
+    0x85, 0x0C,        // STA NF
+    0x85, 0x0D,        // STA VF
+    0x85, 0x0E,        // STA ZF
+    0x85, 0x0F,        // STA CF
+    0x60,              // RTS
+
+    // flag predictions of the 65C02
+    0xA5, 0x0B,        // A65C02: LDA AR
+    0x08,              // PHP
+    0x68,              // PLA
+    0x85, 0x0C,        // STA NF
+    0x85, 0x0E,        // STA ZF
+    0x60,              // RTS
+
+    0x20, 0x05, 0x03,  // S65C02: JSR SUB2
+    0xA5, 0x0B,        // LDA AR
+    0x08,              // PHP
+    0x68,              // PLA
+    0x85, 0x0C,        // STA NF
+    0x85, 0x0E,        // STA ZF
+    0xA5, 0x0A,        // LDA HNVZC
+    0x85, 0x0D,        // STA VF
+    0x85, 0x0F,        // STA CF
+    0x60,              // RTS
+];
@@ -0,0 +1,198 @@
+// Sources of the NROM images in tests/roms that were written for this
+// repository, as annotated listings like DECIMAL_TEST in klaus_dormann.rs.
+// The test checks that the images were built from them; after changing a
+// listing, run it with PABNES_UPDATE_ROMS=1 to rebuild them.
+use std::fs;
+use std::path::Path;
+
+use pabnes::cartridge::nrom;
+
+// A 16K PRG-ROM bank with `code` at $8000 and the NMI, reset and IRQ vectors
+fn prg(code: &[u8], vectors: [u16; 3]) -> Vec<u8> {
+    let mut prg = code.to_vec();
+    prg.resize(0x4000, 0);
+    for (i, vector) in vectors.iter().enumerate() {
+        prg[0x3FFA + i * 2..0x3FFC + i * 2].copy_from_slice(&vector.to_le_bytes());
+    }
+    prg
+}
+
+// status/passes.nes: reports a pass through the $6000 protocol of
+// pabnes::testrom and stops
+#[rustfmt::skip]
+const PASSES: &[u8] = &[
+    0xA9, 0x80,        // START: LDA #$80
+    0x8D, 0x00, 0x60,  // STA $6000         running
+    0xA9, 0xDE,        // LDA #$DE
+    0x8D, 0x01, 0x60,  // STA $6001         signature
+    0xA9, 0xB0,        // LDA #$B0
+    0x8D, 0x02, 0x60,  // STA $6002
+    0xA9, 0x61,        // LDA #$61
+    0x8D, 0x03, 0x60,  // STA $6003
+    0xA2, 0x00,        // LDX #0
+    0xBD, 0x29, 0x80,  // COPY: LDA TEXT,X
+    0x9D, 0x04, 0x60,  // STA $6004,X
+    0xF0, 0x03,        // BEQ DONE
+    0xE8,              // INX
+    0xD0, 0xF5,        // BNE COPY
+    0xA9, 0x00,        // DONE: LDA #0
+    0x8D, 0x00, 0x60,  // STA $6000         passed
+    0x4C, 0x26, 0x80,  // HANG: JMP HANG
+    // TEXT, at $8029
+];
+
+// status/reset.nes: asks for a reset with $81 the first time, leaving a
+// mark at $6010 in PRG-RAM, which survives the reset, and passes after it
+#[rustfmt::skip]
+const RESET: &[u8] = &[
+    0xAD, 0x10, 0x60,  // START: LDA $6010
+    0xC9, 0xA5,        // CMP #$A5
+    0xF0, 0x21,        // BEQ AGAIN
+    0xA9, 0xA5,        // LDA #$A5
+    0x8D, 0x10, 0x60,  // STA $6010
+    0xA9, 0x80,        // LDA #$80
+    0x8D, 0x00, 0x60,  // STA $6000         running
+    0xA9, 0xDE,        // LDA #$DE
+    0x8D, 0x01, 0x60,  // STA $6001         signature
+    0xA9, 0xB0,        // LDA #$B0
+    0x8D, 0x02, 0x60,  // STA $6002
+    0xA9, 0x61,        // LDA #$61
+    0x8D, 0x03, 0x60,  // STA $6003
+    0xA9, 0x81,        // LDA #$81
+    0x8D, 0x00, 0x60,  // STA $6000         needs a reset
+    0x4C, 0x25, 0x80,  // WAIT: JMP WAIT
+    0xA9, 0x80,        // AGAIN: LDA #$80
+    0x8D, 0x00, 0x60,  // STA $6000
+    0xA9, 0xDE,        // LDA #$DE
+    0x8D, 0x01, 0x60,  // STA $6001
+    0xA9, 0xB0,        // LDA #$B0
+    0x8D, 0x02, 0x60,  // STA $6002
+    0xA9, 0x61,        // LDA #$61
+    0x8D, 0x03, 0x60,  // STA $6003
+    0xA2, 0x00,        // LDX #0
+    0xBD, 0x51, 0x80,  // COPY: LDA TEXT,X
+    0x9D, 0x04, 0x60,  // STA $6004,X
+    0xF0, 0x03,        // BEQ DONE
+    0xE8,              // INX
+    0xD0, 0xF5,        // BNE COPY
+    0xA9, 0x00,        // DONE: LDA #0
+    0x8D, 0x00, 0x60,  // STA $6000         passed
+    0x4C, 0x4E, 0x80,  // HANG: JMP HANG
+    // TEXT, at $8051
+];
+
+// screenshot/tiles.nes: fills the first nametable with columns of tiles 0-3
+// and turns the backdrop red while A is held on controller 1
+#[rustfmt::skip]
+const TILES: &[u8] = &[
+    0x78,              // RESET: SEI
+    0xA2, 0xFF,        // LDX #$FF
+    0x9A,              // TXS
+    0xA9, 0x00,        // LDA #0
+    0x8D, 0x00, 0x20,  // STA $2000         NMI off
+    0x8D, 0x01, 0x20,  // STA $2001         rendering off
+    0xA9, 0x3F,        // LDA #$3F
+    0x8D, 0x06, 0x20,  // STA $2006
+    0xA9, 0x00,        // LDA #$00
+    0x8D, 0x06, 0x20,  // STA $2006         PPU address $3F00
+    0xA2, 0x00,        // LDX #0
+    0xBD, 0x8E, 0x80,  // PALETTE: LDA COLORS,X
+    0x8D, 0x07, 0x20,  // STA $2007
+    0xE8,              // INX
+    0xE0, 0x20,        // CPX #32
+    0xD0, 0xF5,        // BNE PALETTE
+    0xA9, 0x20,        // LDA #$20
+    0x8D, 0x06, 0x20,  // STA $2006
+    0xA9, 0x00,        // LDA #$00
+    0x8D, 0x06, 0x20,  // STA $2006         PPU address $2000
+    0xA0, 0x04,        // LDY #4            4 pages, attributes included
+    0xA2, 0x00,        // LDX #0
+    0x8A,              // FILL: TXA
+    0x29, 0x03,        // AND #3            tile = column & 3
+    0x8D, 0x07, 0x20,  // STA $2007
+    0xE8,              // INX
+    0xD0, 0xF7,        // BNE FILL
+    0x88,              // DEY
+    0xD0, 0xF4,        // BNE FILL
+    0xA9, 0x00,        // LDA #0
+    0x8D, 0x05, 0x20,  // STA $2005
+    0x8D, 0x05, 0x20,  // STA $2005         no scrolling
+    0xA9, 0x80,        // LDA #$80
+    0x8D, 0x00, 0x20,  // STA $2000         NMI on
+    0xA9, 0x0A,        // LDA #$0A
+    0x8D, 0x01, 0x20,  // STA $2001         background on, left column too
+    0x4C, 0x4F, 0x80,  // MAIN: JMP MAIN
+    0x48,              // NMI: PHA
+    0xA9, 0x01,        // LDA #1
+    0x8D, 0x16, 0x40,  // STA $4016
+    0xA9, 0x00,        // LDA #0
+    0x8D, 0x16, 0x40,  // STA $4016         latch the buttons
+    0xAD, 0x16, 0x40,  // LDA $4016         A
+    0x29, 0x01,        // AND #1
+    0xF0, 0x04,        // BEQ RELEASED
+    0xA9, 0x16,        // LDA #$16          red
+    0xD0, 0x02,        // BNE SET
+    0xA9, 0x0F,        // RELEASED: LDA #$0F  black
+    0x48,              // SET: PHA
+    0xA9, 0x3F,        // LDA #$3F
+    0x8D, 0x06, 0x20,  // STA $2006
+    0xA9, 0x00,        // LDA #$00
+    0x8D, 0x06, 0x20,  // STA $2006
+    0x68,              // PLA
+    0x8D, 0x07, 0x20,  // STA $2007         backdrop
+    0xA9, 0x00,        // LDA #0
+    0x8D, 0x06, 0x20,  // STA $2006
+    0x8D, 0x06, 0x20,  // STA $2006
+    0x8D, 0x05, 0x20,  // STA $2005
+    0x8D, 0x05, 0x20,  // STA $2005
+    0xA9, 0x80,        // LDA #$80
+    0x8D, 0x00, 0x20,  // STA $2000
+    0x68,              // PLA
+    0x40,              // RTI
+    // COLORS, at $808E: the same four palettes for background and sprites
+    0x0F, 0x21, 0x2A, 0x30, 0x0F, 0x16, 0x27, 0x18, 0x0F, 0x11, 0x1A, 0x28, 0x0F, 0x04, 0x14, 0x24,
+    0x0F, 0x21, 0x2A, 0x30, 0x0F, 0x16, 0x27, 0x18, 0x0F, 0x11, 0x1A, 0x28, 0x0F, 0x04, 0x14, 0x24,
+];
+
+// Pattern table 0, both bit planes of each tile
+#[rustfmt::skip]
+const TILES_CHR: &[u8] = &[
+    // 0: blank
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // 1: solid color 1
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // 2: checkerboard of colors 1 and 2
+    0xAA, 0x55, 0xAA, 0x55, 0xAA, 0x55, 0xAA, 0x55, 0x55, 0xAA, 0x55, 0xAA, 0x55, 0xAA, 0x55, 0xAA,
+    // 3: stripes of colors 3 and 1 on the left half, 2 and 0 on the right
+    0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xFF, 0xFF, 0x00, 0x00, 0xFF, 0xFF, 0x00, 0x00,
+];
+
+fn roms() -> Vec<(&'static str, Vec<u8>)> {
+    let passes = [PASSES, b"status_protocol\n\nPassed\n\0"].concat();
+    let reset = [RESET, b"reset_request\n\nPassed\n\0"].concat();
+    vec![
+        ("status/passes.nes", nrom(&prg(&passes, [0x8000, 0x8000, 0x0000]), &[])),
+        ("status/reset.nes", nrom(&prg(&reset, [0x8000, 0x8000, 0x0000]), &[])),
+        ("screenshot/tiles.nes", nrom(&prg(TILES, [0x8052, 0x8000, 0x8000]), TILES_CHR)),
+    ]
+}
+
+#[test]
+fn test_roms_match_sources() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms");
+    let update = std::env::var_os("PABNES_UPDATE_ROMS").is_some();
+    let mut stale = Vec::new();
+    for (name, image) in roms() {
+        let path = dir.join(name);
+        if update {
+            fs::write(&path, &image).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        } else if fs::read(&path).ok() != Some(image) {
+            stale.push(name);
+        }
+    }
+    assert!(
+        stale.is_empty(),
+        "{} not built from tests/rom_sources.rs, rerun with PABNES_UPDATE_ROMS=1",
+        stale.join(", ")
+    );
+}
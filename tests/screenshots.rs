@@ -0,0 +1,141 @@
+// Screenshot regression tests: each case runs a ROM for a number of frames
+// with scripted controller input and compares a hash of the last frame with
+// tests/screenshots/hashes.txt. Run with PABNES_UPDATE_SCREENSHOTS=1 to
+// store new hashes, along with PNGs of the frames next to them. On a
+// mismatch the frame and a diff against the stored PNG, with the changed
+// pixels in red, are written to the directory printed in the failure.
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use pabnes::joypad::JoypadButton;
+use pabnes::render::filter::Image;
+use pabnes::Emulator;
+
+struct Case {
+    name: &'static str,
+    rom: &'static str,
+    frames: u64,
+    // from each frame on, controller 1 holds these buttons
+    input: &'static [(u64, JoypadButton)],
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "tiles",
+        rom: "tiles.nes",
+        frames: 10,
+        input: &[],
+    },
+    Case {
+        name: "tiles_a_held",
+        rom: "tiles.nes",
+        frames: 10,
+        input: &[(5, JoypadButton::BUTTON_A)],
+    },
+    Case {
+        name: "tiles_a_released",
+        rom: "tiles.nes",
+        frames: 10,
+        input: &[(2, JoypadButton::BUTTON_A), (6, JoypadButton::empty())],
+    },
+];
+
+// FNV-1a, stable across Rust versions unlike DefaultHasher
+fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+fn run(case: &Case) -> Image {
+    let raw = fs::read(manifest_dir().join("tests/roms/screenshot").join(case.rom)).unwrap();
+    let mut emulator = Emulator::new();
+    emulator.load_rom(&raw).unwrap();
+    for frame in 0..case.frames {
+        if let Some(&(_, buttons)) = case.input.iter().rev().find(|(from, _)| *from <= frame) {
+            emulator.set_input(1, buttons);
+        }
+        assert_eq!(emulator.step_frame(), Ok(true), "{}: CPU halted in frame {}", case.name, frame);
+    }
+    let mut image = Image::new(256, 240);
+    image.data.copy_from_slice(emulator.frame_buffer());
+    image
+}
+
+fn manifest_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn read_hashes(path: &Path) -> BTreeMap<String, u64> {
+    let text = fs::read_to_string(path).unwrap_or_default();
+    text.lines()
+        .filter_map(|line| {
+            let (name, hash) = line.split_once(' ')?;
+            Some((name.to_string(), u64::from_str_radix(hash.trim(), 16).ok()?))
+        })
+        .collect()
+}
+
+fn write_hashes(path: &Path, hashes: &BTreeMap<String, u64>) {
+    let text: String = hashes.iter().map(|(name, hash)| format!("{} {:016x}\n", name, hash)).collect();
+    fs::write(path, text).unwrap();
+}
+
+// The actual frame dimmed, with the pixels that differ from the expected one in red
+fn diff(expected: &Image, actual: &Image) -> Image {
+    let mut diff = Image::new(actual.width, actual.height);
+    let pixels = actual.data.chunks_exact(3).zip(expected.data.chunks_exact(3));
+    for (out, (a, e)) in diff.data.chunks_exact_mut(3).zip(pixels) {
+        if a == e {
+            let gray = ((a[0] as u32 + a[1] as u32 + a[2] as u32) / 9) as u8;
+            out.copy_from_slice(&[gray, gray, gray]);
+        } else {
+            out.copy_from_slice(&[255, 0, 0]);
+        }
+    }
+    diff
+}
+
+#[test]
+fn test_screenshots() {
+    let dir = manifest_dir().join("tests/screenshots");
+    let hashes_path = dir.join("hashes.txt");
+    let mut hashes = read_hashes(&hashes_path);
+    let update = std::env::var_os("PABNES_UPDATE_SCREENSHOTS").is_some();
+    let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("screenshots");
+
+    let mut failures = Vec::new();
+    for case in CASES {
+        let image = run(case);
+        let actual = hash(&image.data);
+        let reference = dir.join(format!("{}.png", case.name));
+        if update {
+            hashes.insert(case.name.to_string(), actual);
+            image.save_png(&reference).unwrap();
+            continue;
+        }
+        match hashes.get(case.name) {
+            Some(&expected) if expected == actual => continue,
+            Some(&expected) => failures.push(format!("{}: hash {:016x}, expected {:016x}", case.name, actual, expected)),
+            None => failures.push(format!("{}: no stored hash", case.name)),
+        }
+
+        fs::create_dir_all(&out_dir).unwrap();
+        image.save_png(out_dir.join(format!("{}.png", case.name))).unwrap();
+        match Image::load_png(&reference) {
+            Ok(expected) if expected.width == image.width && expected.height == image.height => {
+                diff(&expected, &image).save_png(out_dir.join(format!("{}.diff.png", case.name))).unwrap();
+            }
+            _ => {}
+        }
+    }
+
+    if update {
+        write_hashes(&hashes_path, &hashes);
+    }
+    assert!(
+        failures.is_empty(),
+        "{}\nframes written to {}, run with PABNES_UPDATE_SCREENSHOTS=1 if the changes are expected",
+        failures.join("\n"),
+        out_dir.display()
+    );
+}
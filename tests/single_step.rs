@@ -0,0 +1,204 @@
+// Runs the SingleStepTests 6502 JSON vectors (github.com/SingleStepTests/65x02, and
+// ProcessorTests/nes6502 for the 2A03): one file per opcode, each test giving the
+// CPU and RAM state before and after one instruction and the bus access of every
+// cycle, which are compared with the accesses of the cycle accurate CPU. Both
+// modes have to take one cycle per access. tests/single_step/README.md describes
+// the vectors checked in; to run a full copy, point PABNES_SINGLE_STEP_TESTS at
+// the directory holding the opcode files, and PABNES_SINGLE_STEP_CPU at the
+// variant if it is not the NMOS 6502.
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use pabnes::cpu::{BusAccess, CpuFlags, CpuVariant};
+use pabnes::{Mem, CPU};
+
+// The unused and break bits are not real flags, the suite keeps them set
+const FLAGS_MASK: u8 = !(CpuFlags::BREAK.bits() | CpuFlags::BREAK2.bits());
+const MAX_REPORTED: usize = 20;
+
+#[derive(Deserialize)]
+struct Test {
+    name: String,
+    initial: State,
+    #[serde(rename = "final")]
+    expected: State,
+    cycles: Vec<(u16, u8, String)>,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+struct State {
+    pc: u16,
+    s: u8,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+impl Test {
+    // Both modes take the same number of cycles, but only the cycle accurate
+    // CPU makes every access of the instruction
+    fn run(&self, variant: CpuVariant, cycle_accurate: bool) -> Result<(), String> {
+        let mut cpu = CPU::new();
+        cpu.variant = variant;
+        cpu.cycle_accurate = cycle_accurate;
+        let state = &self.initial;
+        cpu.pc = state.pc;
+        cpu.sp = state.s;
+        cpu.reg_a = state.a;
+        cpu.reg_x = state.x;
+        cpu.reg_y = state.y;
+        cpu.status = CpuFlags::from_bits_truncate(state.p);
+        for &(addr, data) in &state.ram {
+            cpu.mem_write(addr, data);
+        }
+
+        cpu.access_log = Some(Vec::new());
+        cpu.step().unwrap();
+
+        let actual = State {
+            pc: cpu.pc,
+            s: cpu.sp,
+            a: cpu.reg_a,
+            x: cpu.reg_x,
+            y: cpu.reg_y,
+            p: cpu.status.bits(),
+            ram: self.expected.ram.iter().map(|&(addr, _)| (addr, cpu.mem_read(addr))).collect(),
+        };
+        let expected = State { p: self.expected.p & FLAGS_MASK, ..self.expected.clone() };
+        let actual = State { p: actual.p & FLAGS_MASK, ..actual };
+        if actual != expected {
+            return Err(format!("{}: expected {:?}, got {:?}", self.name, expected, actual));
+        }
+        if cpu.bus.cycles != self.cycles.len() {
+            return Err(format!("{}: expected {} cycles, got {}", self.name, self.cycles.len(), cpu.bus.cycles));
+        }
+        let accesses = cpu.access_log.unwrap();
+        if cycle_accurate && accesses != self.accesses() {
+            return Err(format!("{}: expected {:?}, got {:?}", self.name, self.accesses(), accesses));
+        }
+        Ok(())
+    }
+
+    fn accesses(&self) -> Vec<BusAccess> {
+        self.cycles
+            .iter()
+            .map(|(addr, data, kind)| match kind.as_str() {
+                "write" => BusAccess::Write(*addr, *data),
+                _ => BusAccess::Read(*addr, *data),
+            })
+            .collect()
+    }
+}
+
+// Runs every opcode file in dir, returns how many tests ran and the failures
+fn run_dir(dir: &Path, variant: CpuVariant) -> (usize, Vec<String>) {
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("{}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+
+    let mut ran = 0;
+    let mut failures = Vec::new();
+    for path in paths {
+        let opcode = path.file_stem().and_then(|stem| u8::from_str_radix(&stem.to_string_lossy(), 16).ok());
+        // illegal opcodes are not emulated
+        match opcode {
+            Some(opcode) if variant.opcodes().contains_key(&opcode) => {}
+            _ => continue,
+        }
+        let json = fs::read_to_string(&path).unwrap();
+        let tests: Vec<Test> = serde_json::from_str(&json).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        for test in &tests {
+            ran += 1;
+            for cycle_accurate in [false, true] {
+                if let Err(e) = test.run(variant, cycle_accurate) {
+                    failures.push(e);
+                }
+            }
+        }
+    }
+    (ran, failures)
+}
+
+fn check_dir(dir: &Path, variant: CpuVariant) {
+    let (ran, failures) = run_dir(dir, variant);
+    for failure in failures.iter().take(MAX_REPORTED) {
+        eprintln!("{}", failure);
+    }
+    assert!(ran > 0, "no tests in {}", dir.display());
+    assert!(failures.is_empty(), "{} of {} tests failed", failures.len(), ran);
+}
+
+// Runs each directory of a set, named after the CPU its vectors are for
+fn check_set(set: &str) {
+    let set = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/single_step").join(set);
+    assert!(set.is_dir(), "{} is missing, see tests/single_step/README.md", set.display());
+    for entry in fs::read_dir(set).unwrap() {
+        let dir = entry.unwrap().path();
+        if !dir.is_dir() {
+            continue;
+        }
+        let variant = CpuVariant::parse(&dir.file_name().unwrap().to_string_lossy()).unwrap();
+        check_dir(&dir, variant);
+    }
+}
+
+#[test]
+fn test_handwritten() {
+    check_set("handwritten");
+}
+
+#[test]
+#[ignore = "tests/single_step/upstream has not been vendored yet, see vendor_upstream"]
+fn test_upstream() {
+    check_set("upstream");
+}
+
+// Copies the first tests of every opcode pabnes emulates from a checkout of
+// github.com/SingleStepTests/65x02 into tests/single_step/upstream, with the
+// suite's license, unchanged apart from one test per line:
+//
+//     PABNES_SINGLE_STEP_UPSTREAM=../65x02 cargo test --test single_step vendor_upstream -- --ignored
+//
+// PABNES_SINGLE_STEP_CPU picks the CPU directory, PABNES_SINGLE_STEP_COUNT
+// how many tests of each opcode are kept (20 by default).
+#[test]
+#[ignore]
+fn vendor_upstream() {
+    let checkout = std::env::var_os("PABNES_SINGLE_STEP_UPSTREAM").expect("PABNES_SINGLE_STEP_UPSTREAM is not set");
+    let checkout = Path::new(&checkout);
+    let cpu = std::env::var("PABNES_SINGLE_STEP_CPU").unwrap_or_else(|_| "6502".to_string());
+    let count = std::env::var("PABNES_SINGLE_STEP_COUNT").map_or(20, |count| count.parse().unwrap());
+    let variant = CpuVariant::parse(&cpu).unwrap();
+
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/single_step/upstream");
+    let out = root.join(&cpu);
+    fs::create_dir_all(&out).unwrap();
+    fs::copy(checkout.join("LICENSE"), root.join("LICENSE")).unwrap();
+
+    let mut opcodes: Vec<u8> = variant.opcodes().keys().copied().collect();
+    opcodes.sort_unstable();
+    for opcode in opcodes {
+        let path = checkout.join(&cpu).join("v1").join(format!("{:02x}.json", opcode));
+        let json = fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        let tests: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+        let lines: Vec<String> = tests.iter().take(count).map(|test| test.to_string()).collect();
+        fs::write(out.join(format!("{:02x}.json", opcode)), format!("[\n{}\n]\n", lines.join(",\n"))).unwrap();
+    }
+}
+
+#[test]
+fn test_full_suite() {
+    let dir = match std::env::var_os("PABNES_SINGLE_STEP_TESTS") {
+        Some(dir) => dir,
+        None => return,
+    };
+    let variant = std::env::var("PABNES_SINGLE_STEP_CPU").map_or(Ok(CpuVariant::Nmos6502), |name| CpuVariant::parse(&name));
+    check_dir(Path::new(&dir), variant.unwrap());
+}
@@ -0,0 +1,73 @@
+// Runs test ROMs that report through the $6000 status protocol (see
+// pabnes::testrom) and prints a pass/fail report. The ROMs in
+// tests/roms/status only check the harness; point PABNES_TEST_ROMS at a
+// directory of real ones, like blargg's, to track accuracy.
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use pabnes::testrom;
+use pabnes::Emulator;
+
+// blargg's longest ROMs take around 30 seconds
+const MAX_FRAMES: u64 = 60 * 60;
+
+fn roms_in(dir: &Path) -> Vec<PathBuf> {
+    let mut roms = Vec::new();
+    for entry in fs::read_dir(dir).unwrap_or_else(|e| panic!("{}: {}", dir.display(), e)) {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            roms.extend(roms_in(&path));
+        } else if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("nes")) {
+            roms.push(path);
+        }
+    }
+    roms.sort();
+    roms
+}
+
+fn run_rom(path: &Path) -> Result<testrom::TestResult, String> {
+    let raw = fs::read(path).map_err(|e| e.to_string())?;
+    // a ROM crashing the emulator fails instead of ending the run
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut emulator = Emulator::new();
+        emulator.load_rom(&raw).map_err(|e| e.to_string())?;
+        testrom::run(&mut emulator, MAX_FRAMES)
+    }))
+    .unwrap_or_else(|_| Err("Emulator panicked".to_string()))
+}
+
+fn check_dir(dir: &Path) {
+    let roms = roms_in(dir);
+    assert!(!roms.is_empty(), "no ROMs in {}", dir.display());
+
+    let mut failed = 0;
+    for path in &roms {
+        let name = path.strip_prefix(dir).unwrap_or(path).display();
+        match run_rom(path) {
+            Ok(result) if result.passed() => println!("PASS {}", name),
+            Ok(result) => {
+                failed += 1;
+                println!("FAIL {} (#{}): {}", name, result.code, result.text.trim());
+            }
+            Err(err) => {
+                failed += 1;
+                println!("FAIL {}: {}", name, err);
+            }
+        }
+    }
+    println!("{} of {} passed", roms.len() - failed, roms.len());
+    assert_eq!(failed, 0, "{} of {} test ROMs failed", failed, roms.len());
+}
+
+#[test]
+fn test_status_roms() {
+    check_dir(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms/status"));
+}
+
+#[test]
+fn test_rom_dir() {
+    if let Some(dir) = std::env::var_os("PABNES_TEST_ROMS") {
+        check_dir(Path::new(&dir));
+    }
+}
@@ -0,0 +1,120 @@
+// wasm-bindgen bindings for running pabnes in a browser. The page in www/
+// owns timing, drawing and audio output; this side only moves bytes.
+
+use pabnes::emulator::AUDIO_SAMPLE_RATE;
+use pabnes::joypad::JoypadButton;
+use pabnes::render::frame::Frame;
+use pabnes::render::palette::Palette;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+pub struct Nes {
+    emulator: pabnes::Emulator,
+    rgba: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl Nes {
+    #[wasm_bindgen(constructor)]
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Nes {
+        Nes {
+            emulator: pabnes::Emulator::new(),
+            rgba: vec![0xFF; Frame::WIDTH * Frame::HEIGHT * 4],
+        }
+    }
+
+    #[wasm_bindgen(js_name = loadRom)]
+    pub fn load_rom(&mut self, raw: &[u8]) -> Result<(), JsValue> {
+        self.emulator.load_rom(raw).map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    /// Uses the colors of a .pal file with 64 or 512 entries.
+    #[wasm_bindgen(js_name = loadPalette)]
+    pub fn load_palette(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        let palette = Palette::from_pal(data).map_err(|err| JsValue::from_str(&err))?;
+        self.emulator.set_palette(palette);
+        Ok(())
+    }
+
+    pub fn reset(&mut self) {
+        self.emulator.reset();
+    }
+
+    /// Runs one frame and refreshes the RGBA buffer. Returns false once the
+    /// CPU has halted, and throws if it hit an instruction it cannot execute.
+    #[wasm_bindgen(js_name = runFrame)]
+    pub fn run_frame(&mut self) -> Result<bool, JsValue> {
+        let running = self.emulator.step_frame();
+        for (rgba, rgb) in self.rgba.chunks_exact_mut(4).zip(self.emulator.frame_buffer().chunks_exact(3)) {
+            rgba[..3].copy_from_slice(rgb);
+        }
+        running.map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    /// 256x240 RGBA, ready for an ImageData.
+    #[wasm_bindgen(js_name = frameBuffer)]
+    pub fn frame_buffer(&self) -> Vec<u8> {
+        self.rgba.clone()
+    }
+
+    /// `buttons` uses the controller's bit order: A, B, Select, Start, Up, Down, Left, Right.
+    #[wasm_bindgen(js_name = setInput)]
+    pub fn set_input(&mut self, player: u8, buttons: u8) -> Result<(), JsValue> {
+        if player != 1 && player != 2 {
+            return Err(JsValue::from_str("player must be 1 or 2"));
+        }
+        self.emulator.set_input(player, JoypadButton::from_bits_truncate(buttons));
+        Ok(())
+    }
+
+    /// Mono samples produced since the last call.
+    #[wasm_bindgen(js_name = takeAudioSamples)]
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        self.emulator.take_audio_samples()
+    }
+
+    /// Frames per second of the loaded game's region, for pacing `runFrame`.
+    #[wasm_bindgen(getter, js_name = frameRate)]
+    pub fn frame_rate(&self) -> f64 {
+        self.emulator.region().frame_rate()
+    }
+
+    #[wasm_bindgen(getter, js_name = sampleRate)]
+    pub fn sample_rate(&self) -> u32 {
+        AUDIO_SAMPLE_RATE
+    }
+
+    #[wasm_bindgen(js_name = saveState)]
+    pub fn save_state(&self) -> Vec<u8> {
+        self.emulator.save_state()
+    }
+
+    #[wasm_bindgen(js_name = loadState)]
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        self.emulator.load_state(data).map_err(|err| JsValue::from_str(&err))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pabnes::cartridge::nrom;
+
+    // JMP $8000
+    fn test_rom() -> Vec<u8> {
+        nrom(&[0x4C, 0x00, 0x80], &[])
+    }
+
+    #[test]
+    fn test_run_frame_fills_rgba() {
+        let mut nes = Nes::new();
+        nes.load_rom(&test_rom()).unwrap();
+
+        assert_eq!(nes.run_frame(), Ok(true));
+        let frame = nes.frame_buffer();
+        assert_eq!(frame.len(), 256 * 240 * 4);
+        // nothing is drawn, so the whole frame is backdrop color $00
+        assert!(frame.chunks_exact(4).all(|pixel| pixel == [0x80, 0x80, 0x80, 0xFF]));
+    }
+}
@@ -0,0 +1,93 @@
+import init, { Nes } from "./pkg/pabnes_wasm.js";
+
+let frameMs = 1000 / 60.0988;
+
+// Same bit order as the controller's shift register
+const KEYS = {
+  KeyX: 0x01,       // A
+  KeyZ: 0x02,       // B
+  ShiftRight: 0x04, // Select
+  ShiftLeft: 0x04,
+  Enter: 0x08,      // Start
+  ArrowUp: 0x10,
+  ArrowDown: 0x20,
+  ArrowLeft: 0x40,
+  ArrowRight: 0x80,
+};
+
+await init();
+
+const nes = new Nes();
+const canvas = document.getElementById("screen");
+const ctx = canvas.getContext("2d");
+const image = ctx.createImageData(256, 240);
+const status = document.getElementById("status");
+
+let buttons = 0;
+let running = false;
+let audio = null;
+let audioTime = 0;
+
+function onKey(event, pressed) {
+  const bit = KEYS[event.code];
+  if (bit === undefined) return;
+  buttons = pressed ? buttons | bit : buttons & ~bit;
+  event.preventDefault();
+}
+window.addEventListener("keydown", (e) => onKey(e, true));
+window.addEventListener("keyup", (e) => onKey(e, false));
+
+document.getElementById("rom").addEventListener("change", async (event) => {
+  const file = event.target.files[0];
+  if (!file) return;
+  try {
+    nes.loadRom(new Uint8Array(await file.arrayBuffer()));
+  } catch (err) {
+    status.textContent = err;
+    return;
+  }
+  // browsers only allow audio to start from a user gesture like this one
+  audio = audio || new AudioContext({ sampleRate: nes.sampleRate });
+  audioTime = audio.currentTime;
+  frameMs = 1000 / nes.frameRate;
+  status.textContent = file.name;
+  running = true;
+});
+
+function queueAudio(samples) {
+  if (!audio || samples.length === 0) return;
+  const buffer = audio.createBuffer(1, samples.length, nes.sampleRate);
+  buffer.copyToChannel(samples, 0);
+  const source = audio.createBufferSource();
+  source.buffer = buffer;
+  source.connect(audio.destination);
+  // don't let the queue drift behind real time after a stall
+  audioTime = Math.max(audioTime, audio.currentTime);
+  source.start(audioTime);
+  audioTime += buffer.duration;
+}
+
+let last = performance.now();
+let lag = 0;
+function tick(now) {
+  lag = Math.min(lag + now - last, frameMs * 4);
+  last = now;
+  while (running && lag >= frameMs) {
+    nes.setInput(1, buttons);
+    try {
+      if (!nes.runFrame()) {
+        running = false;
+        status.textContent = "CPU halted";
+      }
+    } catch (err) {
+      running = false;
+      status.textContent = `CPU halted: ${err}`;
+    }
+    queueAudio(nes.takeAudioSamples());
+    lag -= frameMs;
+  }
+  image.data.set(nes.frameBuffer());
+  ctx.putImageData(image, 0, 0);
+  requestAnimationFrame(tick);
+}
+requestAnimationFrame(tick);